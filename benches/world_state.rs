@@ -0,0 +1,83 @@
+use bevy::math::Vec2;
+use bincode::error::DecodeError;
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use fixedtick::common::*;
+
+const ENTITY_COUNTS: [usize; 3] = [10, 100, 1000];
+
+// Big enough for 1000 entities, real packets are capped at ETHERNET_MTU
+const BENCH_BUF_LEN: usize = 64 * 1024;
+
+// Roughly the mix the server sends: mostly bricks, a paddle and ball per player, one score
+fn make_world(num_entities: usize) -> NetWorldStateData {
+    let mut world = NetWorldStateData::default();
+    world.frame = 1234;
+    for i in 0..num_entities {
+        let pos = Vec2::new(i as f32, -(i as f32));
+        let net_id = NetId(i as u16 + 1);
+        let player_index = NetPlayerIndex((i % NUM_COLORS) as u8);
+        let entity_type = match i % 10 {
+            0 => NetEntityType::Paddle(NetPaddleData { pos, player_index, color: NetColorIndex(player_index.0), lives: None }),
+            1 => NetEntityType::Ball(NetBallData {
+                pos,
+                velocity: Vec2::new(200.0, -200.0),
+                spin: 0.0,
+                launch_frame: None,
+                held_by: None,
+                player_index,
+                color: NetColorIndex(player_index.0)
+            }),
+            _ => NetEntityType::Brick(NetBrickData { pos, value: 1 }),
+        };
+        world.entities.push(NetEntity { entity_type, net_id });
+    }
+    world.entities.push(NetEntity {
+        entity_type: NetEntityType::Score(NetScoreData { score: 42 }),
+        net_id: NetId(0)
+    });
+    world
+}
+
+fn encode(world: NetWorldStateData, buf: &mut [u8]) -> usize {
+    let packet = ServerToClientPacket::WorldState(world);
+    bincode::serde::encode_into_slice(packet, buf, NET_CONFIG).unwrap()
+}
+
+// Build + encode is what broadcast_world_state pays every tick, encode alone is what
+// hand-serializing straight into the buffer would have to beat
+fn bench_server_encode(c: &mut Criterion) {
+    let mut group = c.benchmark_group("server_encode");
+    let mut buf = vec![0u8; BENCH_BUF_LEN];
+    for num_entities in ENTITY_COUNTS {
+        group.throughput(Throughput::Elements(num_entities as u64));
+        group.bench_with_input(BenchmarkId::new("build_and_encode", num_entities), &num_entities, |b, &n| {
+            b.iter(|| encode(make_world(black_box(n)), &mut buf))
+        });
+        group.bench_with_input(BenchmarkId::new("build_only", num_entities), &num_entities, |b, &n| {
+            b.iter(|| make_world(black_box(n)))
+        });
+    }
+    group.finish();
+}
+
+fn bench_client_decode(c: &mut Criterion) {
+    let mut group = c.benchmark_group("client_decode");
+    for num_entities in ENTITY_COUNTS {
+        let mut buf = vec![0u8; BENCH_BUF_LEN];
+        let num_bytes = encode(make_world(num_entities), &mut buf);
+        let encoded = &buf[..num_bytes];
+
+        group.throughput(Throughput::Bytes(num_bytes as u64));
+        group.bench_with_input(BenchmarkId::new("decode", num_entities), &encoded, |b, encoded| {
+            b.iter(|| {
+                type ServerToClientResult = Result<(ServerToClientPacket, usize), DecodeError>;
+                let decode_result: ServerToClientResult = bincode::serde::decode_from_slice(black_box(encoded), NET_CONFIG);
+                decode_result.unwrap()
+            })
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_server_encode, bench_client_decode);
+criterion_main!(benches);
@@ -0,0 +1,128 @@
+// The networking module on its own, no breakout: clients bump a counter the server keeps.
+//
+//   cargo run --example net_counter -- server
+//   cargo run --example net_counter -- client   (as many as you like)
+//
+// Every client sends a bump twice a second. The server adds them up and sends the new total to
+// everyone connected. Start from this for a game that only wants the UDP transport, sim latency,
+// heartbeats and connection events. Packets are whatever bytes you like, as long as the first one
+// isn't networking::HEARTBEAT_TAG.
+use std::net::SocketAddr;
+use std::mem::size_of;
+use std::time::Duration;
+use bevy::app::ScheduleRunnerPlugin;
+use bevy::log::LogPlugin;
+use bevy::prelude::*;
+use fixedtick::networking::{ClientPlugin, NetworkEvent, NetworkSystem, ResSocketAddr, ResUdpSocket, ServerPlugin, Transport};
+
+const SERVER_ADDRESS: &str = "127.0.0.1:7101";
+const BUMP_INTERVAL_S: f32 = 0.5;
+
+// Every packet starts with one of these. A total is followed by the count as a big endian u64.
+const BUMP: u8 = 0;
+const TOTAL: u8 = 1;
+
+#[derive(Resource, Default)]
+struct Counter {
+    total: u64,
+    clients: Vec<SocketAddr>
+}
+
+fn main() {
+    let mode = std::env::args().nth(1).unwrap_or_default();
+    let mut app = App::new();
+    app.add_plugins((
+        MinimalPlugins.set(ScheduleRunnerPlugin::run_loop(Duration::from_secs_f64(1.0 / 60.0))),
+        LogPlugin::default(),
+    ));
+
+    // With no_systems left false the plugins run receive and send in Update, so game systems only
+    // have to go between the two
+    match mode.as_str() {
+        "server" => {
+            app.insert_resource(ResUdpSocket::new_server(&[SERVER_ADDRESS.to_string()]))
+                .insert_resource(Counter::default())
+                .add_plugins(ServerPlugin::default())
+                .add_systems(Update, count_bumps.after(NetworkSystem::Receive).before(NetworkSystem::Send));
+        }
+        "client" => {
+            let server: SocketAddr = SERVER_ADDRESS.parse().unwrap();
+            app.insert_resource(ResUdpSocket::new_client(server))
+                .insert_resource(ResSocketAddr(server))
+                .add_plugins(ClientPlugin::default())
+                .add_systems(Update, (
+                    print_totals.after(NetworkSystem::Receive),
+                    send_bumps.before(NetworkSystem::Send)
+                ));
+        }
+        _ => {
+            eprintln!("usage: net_counter <server|client>");
+            return;
+        }
+    }
+    app.run();
+}
+
+fn count_bumps(
+    mut events: EventReader<NetworkEvent>,
+    mut transport: ResMut<Transport>,
+    mut counter: ResMut<Counter>,
+) {
+    let mut bumped = false;
+    for event in events.read() {
+        match event {
+            NetworkEvent::Connected(addr) => {
+                info!("{} connected", addr);
+                counter.clients.push(*addr);
+            }
+            NetworkEvent::Disconnected(addr) | NetworkEvent::TimedOut(addr, _) => {
+                info!("{} left", addr);
+                counter.clients.retain(|client| client != addr);
+            }
+            NetworkEvent::Message(addr, msg, _) => {
+                if msg.as_ref() == [BUMP] {
+                    counter.total += 1;
+                    bumped = true;
+                } else {
+                    warn!("{} sent {} bytes that aren't a bump", addr, msg.len());
+                }
+            }
+            _ => {}
+        }
+    }
+
+    if bumped {
+        let mut packet = [TOTAL; 1 + size_of::<u64>()];
+        packet[1..].copy_from_slice(&counter.total.to_be_bytes());
+        for &client in counter.clients.iter() {
+            transport.send(client, &packet);
+        }
+    }
+}
+
+fn send_bumps(
+    time: Res<Time>,
+    server: Res<ResSocketAddr>,
+    mut transport: ResMut<Transport>,
+    mut next_bump_s: Local<f32>,
+) {
+    if time.elapsed_seconds() >= *next_bump_s {
+        *next_bump_s = time.elapsed_seconds() + BUMP_INTERVAL_S;
+        transport.send(server.0, &[BUMP]);
+    }
+}
+
+fn print_totals(mut events: EventReader<NetworkEvent>) {
+    for event in events.read() {
+        match event {
+            NetworkEvent::Message(_, msg, _) => match msg.as_ref() {
+                [TOTAL, total @ ..] if total.len() == size_of::<u64>() => {
+                    info!("total is {}", u64::from_be_bytes(total.try_into().unwrap()));
+                }
+                other => warn!("Server sent {} bytes that aren't a total", other.len()),
+            },
+            NetworkEvent::SendError(addr, err, _) => error!("Sending to {} failed: {:?}", addr, err),
+            _ => {}
+        }
+    }
+}
@@ -1,20 +1,21 @@
 mod networking;
 mod common;
+#[cfg(feature = "fixed_point_sim")]
+mod fixed_point;
 mod client_types;
 
 mod client_util;
 
-use clap::Parser;
+use clap::{CommandFactory, Parser};
 use common::*;
 
+use std::collections::VecDeque;
 use std::time;
-use bincode::config;
-use bincode::error::DecodeError;
 use bevy::{prelude::*};
-use bevy::utils::HashMap;
-use networking::{ClientPlugin, NetworkEvent, ResSocketAddr, ResUdpSocket, Transport};
+use bevy::utils::tracing::field;
+use bevy::utils::{HashMap, HashSet};
+use networking::{ClientPlugin, NetStats, NetworkEvent, ResSocketAddr, ResUdpSocket, Transport};
 use bevy::diagnostic::FrameTimeDiagnosticsPlugin;
-use byteorder::ByteOrder;
 use iyes_perf_ui::prelude::*;
 use crate::networking::NetworkSystem;
 use crate::client_types::*;
@@ -22,46 +23,85 @@ use crate::client_util as util;
 
 fn main() {
     let args = Args::parse();
-    let remote_addr = format!("{}:{}", args.ip, args.port).parse().expect("could not parse addr");
+    if let Err(msg) = args.validate_buffer_bounds() {
+        Args::command().error(clap::error::ErrorKind::ArgumentConflict, msg).exit();
+    }
+    let remote_addr = util::resolve_server_addr(&args.ip, args.port);
     let socket = ResUdpSocket::new_client(remote_addr);
     //let addr = socket.0.local_addr().unwrap();
     //println!("local socket addr: {}", addr);
     let res_addr = ResSocketAddr(remote_addr);
-    let sim_settings = args.sim_latency.into();
+    let sim_settings = args.sim_latency.settings()
+        .unwrap_or_else(|msg| Args::command().error(clap::error::ErrorKind::InvalidValue, msg).exit());
+    let window_args = args.window;
+    let tick_args = args.tick;
+    let jitter_padding = JitterPadding::from(args.jitter);
+    report_timing_issues(util::check_client_timing(&jitter_padding, &args))
+        .unwrap_or_else(|msg| Args::command().error(clap::error::ErrorKind::ValueValidation, msg).exit());
+    let log_packets = args.log_packets;
+    let nonce_heartbeats = args.nonce_heartbeats;
+    let rtt_window = args.rtt_window;
+    let perf_ui = !args.no_perf_ui;
     let net_utils = NetIdUtils {
         net_id_to_entity_id: HashMap::new(),
+        missing_states: HashMap::new(),
+        spawned_ahead: HashSet::new(),
+        prediction_policy: PredictionPolicy::new(&args),
         args
     };
 
-    App::new()
-        .insert_resource(bevy::winit::WinitSettings {
-            focused_mode: bevy::winit::UpdateMode::Continuous,
-            unfocused_mode: bevy::winit::UpdateMode::Continuous,
-        })
+    let mut app = App::new();
+    app
+        .insert_resource(window_args.winit_settings(true))
         .insert_resource(res_addr)
         .insert_resource(socket)
         .insert_resource(net_utils)
         .insert_resource(Time::<Fixed>::from_hz(TICK_RATE_HZ))
+        .insert_resource(tick_args.virtual_time())
         .insert_resource(WorldStates::default())
+        .insert_resource(jitter_padding)
         .insert_resource(Score(0))
         .insert_resource(PingState{
             last_sent_time: 0.0,
             next_ping_id: 1,
             ping_id_to_instance: HashMap::default(),
-            pongs: Vec::default()
+            pongs: Vec::default(),
+            pongs_received: 0,
+            pings_lost: 0,
+            rtt: RttWindow::new(rtt_window)
         })
         .insert_resource(FixedTickWorldResource::default())
+        .insert_resource(TickTimeHistogram::default())
         .insert_resource(UnAckedPlayerInputs::default())
-        .add_plugins(FrameTimeDiagnosticsPlugin::default())
-        .add_plugins(PerfUiPlugin)
-        .add_plugins(DefaultPlugins)
-        .add_plugins(ClientPlugin{sim_settings, no_systems: true})
+        .insert_resource(PredictedBricks::default())
+        .insert_resource(PredictedCollisions::default())
+        .insert_resource(ScoreCorrection::default())
+        .insert_resource(PaddleSelection::default())
+        .insert_resource(InputStarvation::default())
+        .insert_resource(ResyncState::default())
+        .insert_resource(BallAssetCache::default())
+        .insert_resource(ArrivalIntervals::default())
+        .insert_resource(PredictionError::default())
+        .add_plugins(DefaultPlugins.set(window_args.window_plugin()))
+        .add_plugins(ClientPlugin{sim_settings, no_systems: true, log_packets, nonce_heartbeats, ..default()})
         .add_event::<networking::events::NetworkEvent>()
+        .add_event::<CollisionEvent>()
         .add_systems(Startup, setup)
+        .add_systems(Last, send_disconnect_on_exit.before(NetworkSystem::FlushOnExit))
         .add_systems(
             Update,
             (
                 interpolate_frame_for_render,
+                play_collision_feedback,
+                fade_collision_effects,
+                update_sync_indicator,
+                update_countdown,
+                update_lives,
+                update_prediction_error_overlay,
+                update_frame_skew_overlay,
+                toggle_local_ball_prediction,
+                cycle_paddle_selection,
+                toggle_input_starvation,
             )
         )
         .add_systems (
@@ -69,67 +109,103 @@ fn main() {
             (
                 common::start_tick,
                 networking::systems::client_recv_packet_system.in_set(NetworkSystem::Receive),
-                send_input,
+                send_input.run_if(server_running),
                 connection_handler,
+                spawn_local_paddles,
                 reconcile_and_update_predictions,
+                show_predicted_bricks,
                 ping_server,
+                request_resync,
                 tick_simulation,
+                emit_server_collisions,
+                smooth_score_correction.run_if(predicting_bricks),
                 update_scoreboard,
                 networking::systems::auto_heartbeat_system.in_set(networking::ClientSystem::Heartbeat),
                 networking::systems::send_packet_system.in_set(NetworkSystem::Send),
                 common::end_tick
             ).chain()
-        )
-        .run();
+        );
+    if perf_ui {
+        app
+            .add_plugins(FrameTimeDiagnosticsPlugin::default())
+            .add_plugins(PerfUiPlugin)
+            .add_systems(Startup, spawn_perf_ui);
+    }
+    app.run();
 }
 
 fn connection_handler(
     mut events: EventReader<NetworkEvent>,
     mut world_states: ResMut<WorldStates>,
     mut ping_state: ResMut<PingState>,
+    mut net_stats: ResMut<NetStats>,
+    mut resync: ResMut<ResyncState>,
     //mut unacked_inputs: ResMut<UnAckedPlayerInputs>,
+    fixed_state: Res<FixedTickWorldResource>,
     time: Res<Time<Real>>,
 ) {
+    let recv_span = info_span!(
+        parent: &fixed_state.tick_span(),
+        "recv",
+        frame = fixed_state.frame_counter,
+        world_states = field::Empty
+    ).entered();
+
     //let mut recv_count = 0;
     for event in events.read() {
         match event {
-            NetworkEvent::Message(handle, msg, _) => {
-                let config = config::standard();
-                if msg.len() < HEADER_LEN + 1 {
-                    warn!("Packet too small, ignoring");
-                    continue;
-                }
-
-                let msg_slice = msg.as_ref();
-
-                let header_tag = byteorder::NetworkEndian::read_u32(msg_slice);
-                if header_tag != WORLD_PACKET_HEADER_TAG {
-                    warn!("Invalid tag, ignoring");
-                    continue;
-                }
-
+            NetworkEvent::Message(handle, msg, recv_time) => {
                 // This is gross but I wanted to stay simple, there is no framing, every message has all needed data
                 // This allows the server to serialize the world state once
-                let last_applied_input = byteorder::NetworkEndian::read_u32(&msg_slice[size_of::<u32>()..]);
-                let local_client_index = msg_slice[size_of::<u32>() * 2];
-
-                let msg_slice = &msg.as_ref()[HEADER_LEN..];
-                type ServerToClientResult = Result<(ServerToClientPacket, usize), DecodeError>;
-                let decode_result: ServerToClientResult = bincode::serde::decode_from_slice(msg_slice, config);
-                match decode_result {
-                    Ok((packet, _)) => {
+                match ServerToClientPacket::decode(msg.as_ref()) {
+                    Ok((header, packet)) => {
+                        resync.record_decoded();
+                        let last_applied_input = header.last_applied_input;
+                        let local_client_index = header.player_index;
+                        net_stats.record_ack(last_applied_input, time::Instant::now());
+                        if let Some(input_state) = header.input_state {
+                            world_states.server_input_state = input_state;
+                            world_states.server_buffer_remaining_ms = header.buffer_remaining_ms;
+                        }
+                        world_states.server_input_delay_ms = header.input_delay_ms;
+                        world_states.server_send_interval_ticks = header.send_interval_ticks;
                         match packet {
                             ServerToClientPacket::WorldState(ws) => {
+                                let Some(ws) = util::assemble_world_state(&mut world_states.partial, ws) else {
+                                    continue;
+                                };
+                                // Paddles can't be spawned until we know how big they are, the
+                                // server resends ServerInfo until we have it
+                                if world_states.server_info.is_none() {
+                                    continue;
+                                }
+                                // A resync would carry the same serve, balls take the server's
+                                // velocity either way so a mismatch only costs serve prediction
+                                util::check_serves(&ws);
+                                let frame = ws.frame;
                                 world_states.states.push_back(ClientWorldState::new(ws, last_applied_input, local_client_index));
-                                world_states.received_per_sec.push_back(time.elapsed_seconds())
+                                world_states.received_per_sec.push_back(time.elapsed_seconds());
+                                world_states.arrival_times.push_back(*recv_time);
+                                let send_interval_s = TICK_S * world_states.send_interval_ticks() as f64;
+                                world_states.jitter.record_arrival(*recv_time, send_interval_s);
+                                world_states.latest_server_frame = Some(world_states.latest_server_frame.map_or(frame, |f| f.max(frame)));
                             },
                             ServerToClientPacket::Pong(pd) => {
                                 ping_state.pongs.push(pd);
                             }
+                            ServerToClientPacket::ServerInfo(info) => {
+                                util::apply_server_info(&mut world_states, info);
+                            }
                         }
                     }
+                    Err(PacketDecodeError::Header(err)) => {
+                        net_stats.decode_errors.record(*handle, msg.len());
+                        warn!("Bad header from {} ({} bytes, header is {}), ignoring: {:?}", handle, msg.len(), HEADER_LEN, err);
+                    }
                     Err(err) => {
-                        warn!("Error parsing message from {}: {:?} {:?}", handle, msg_slice, err);
+                        net_stats.decode_errors.record(*handle, msg.len());
+                        resync.record_decode_failure();
+                        warn!("Error parsing message from {}: {:?} {:?}", handle, &msg.as_ref()[HEADER_LEN..], err);
                     }
                 }
             }
@@ -146,6 +222,7 @@ fn connection_handler(
             _ => {}
         }
     }
+    recv_span.record("world_states", world_states.states.len());
     /*if recv_count > 0 {
         if world_states.received_per_sec.len() > 1 {
             let recent = world_states.received_per_sec.back().unwrap();
@@ -157,25 +234,101 @@ fn connection_handler(
     }*/
 }
 
+// Local paddles are predicted, so they don't have to wait for interpolation to reach a state with
+// them. They're spawned as soon as the newest state has them, right where reconcile is about to
+// predict them, so they show up in the right spot without a correction.
+fn spawn_local_paddles(
+    mut commands: Commands,
+    mut net_id_utils: ResMut<NetIdUtils>,
+    world_states: Res<WorldStates>,
+    unacked_inputs: Res<UnAckedPlayerInputs>,
+) {
+    let Some(newest) = world_states.states.back() else {
+        return;
+    };
+    // Ones the server removed again before interpolation caught up go back to being despawned normally
+    net_id_utils.spawned_ahead.retain(|net_id| newest.get_by_net_id(net_id).is_some());
+
+    if net_id_utils.prediction_policy.bundle_type(NetEntityKind::Paddle, NetOwnership::Local) != NetBundleType::Predicted {
+        return;
+    }
+    let tuning = world_states.paddle_tuning();
+    for (net_id, d, pos) in util::local_paddle_spawns(newest, unacked_inputs.iter(), &tuning) {
+        if net_id_utils.net_id_to_entity_id.contains_key(&net_id) {
+            continue;
+        }
+        let bundle = PaddleBundle::new(pos, net_id, d.player_index, d.color, tuning);
+        let entity = util::spawn_net_bundle(&mut commands, bundle, NetBundleType::Predicted);
+        net_id_utils.net_id_to_entity_id.insert(net_id, entity);
+        net_id_utils.spawned_ahead.insert(net_id);
+    }
+}
+
 fn reconcile_and_update_predictions(
     mut ball_query: Query<BallQuery, BallFilter>,
     mut local_paddle_query: Query<PaddleQuery, PaddleFilter>,
     remaining_colliders: Query<RemainingCollidersQuery, RemainingCollidersFilter>,
+    brick_query: Query<(Entity, &NetId), With<Brick>>,
     mut unacked_inputs: ResMut<UnAckedPlayerInputs>,
+    mut predicted_bricks: ResMut<PredictedBricks>,
     mut score: ResMut<Score>,
+    net_id_utils: Res<NetIdUtils>,
     world_states: Res<WorldStates>,
+    fixed_state: Res<FixedTickWorldResource>,
+    time: Res<Time<Real>>,
+    mut collision_events: EventWriter<CollisionEvent>,
+    mut played_collisions: Local<VecDeque<(u32, NetId)>>,
+    mut predicted_collisions: ResMut<PredictedCollisions>,
+    mut prediction_error: ResMut<PredictionError>,
+    mut last_reconciled_frame: Local<Option<u32>>,
 ) {
+    prediction_error.roll_window_if_due(time.elapsed_seconds());
     if world_states.states.is_empty() {
         return;
     }
 
+    let reconcile_span = info_span!(
+        parent: &fixed_state.tick_span(),
+        "reconcile",
+        frame = fixed_state.frame_counter,
+        unacked_inputs = field::Empty
+    ).entered();
+
     // Clear previous inputs
     let most_recent_state = world_states.states.back().unwrap();
     let most_recent_input = most_recent_state.last_applied_input;
-    unacked_inputs.inputs.retain(|input| input.sequence > most_recent_input);
+    unacked_inputs.ack_through(most_recent_input, time.elapsed_seconds());
+
+    // Predicted breaks are recomputed from scratch every tick, so a break the server disagrees with
+    // just doesn't show up again and the brick reappears
+    let predict_bricks = net_id_utils.args.predict_bricks();
+    predicted_bricks.destroyed.clear();
+    if predict_bricks {
+        // Bricks the server already broke stay around until interpolation catches up to that state.
+        // Treat them as gone so predicted balls don't bounce off of them.
+        for (entity, net_id) in brick_query.iter() {
+            if most_recent_state.get_by_net_id(net_id).is_none() {
+                predicted_bricks.destroyed.push(entity);
+            }
+        }
 
-    let inputs = &unacked_inputs.inputs;
-    if inputs.is_empty() {
+        // Predicted hits below are counted on top of the newest authoritative score
+        if let Some(s) = most_recent_state.score() {
+            score.0 = s;
+        }
+    }
+
+    if most_recent_state.world.paused {
+        // The server throws away anything we sent while paused, so snap predicted entities back
+        // to authoritative state and forget those inputs instead of replaying them on resume
+        unacked_inputs.clear();
+        util::rollback_all(local_paddle_query.iter_mut(), &most_recent_state);
+        util::rollback_all(ball_query.iter_mut(), &most_recent_state);
+        return;
+    }
+
+    reconcile_span.record("unacked_inputs", unacked_inputs.len());
+    if unacked_inputs.is_empty() {
         info!("NO UNACKED, RETURNING");
         return;
     }
@@ -184,48 +337,290 @@ fn reconcile_and_update_predictions(
     let original_paddle_transforms = util::rollback_all(local_paddle_query.iter_mut(), &most_recent_state);
     let original_ball_transforms = util::rollback_all(ball_query.iter_mut(), &most_recent_state);
 
-    let mut entities_to_ignore = Vec::new();
-    let last_idx = inputs.len() - 1;
+    let mut entities_to_ignore = predicted_bricks.destroyed.clone();
+    let mut collisions = Vec::new();
+    let last_idx = unacked_inputs.len() - 1;
+    // Resimulating without a new state reproduces the same prediction, only sample when one arrives
+    let new_state = *last_reconciled_frame != Some(most_recent_state.world.frame);
+    *last_reconciled_frame = Some(most_recent_state.world.frame);
+    let served_frame = util::served_frame(most_recent_state, unacked_inputs.iter());
 
-    for (i, input) in unacked_inputs.inputs.iter().enumerate() {
+    for (i, input) in unacked_inputs.iter().enumerate() {
         if i == last_idx {
             // Print mispredicts. The last input in the list hasn't been predicted yet and is
             // for this frame. So to detect mispredicts we need to compare to the state BEFORE
             // that last input has been applied
-            util::detect_mispredicts(
+            util::compare_predictions(
                 &ball_query,
                 &local_paddle_query,
                 &original_paddle_transforms,
-                &original_ball_transforms
+                &original_ball_transforms,
+                |kind, original, now| {
+                    if now != original {
+                        info!("{:?} MISPREDICT (orginally {:?} now {:?}", kind, original.translation, now.translation);
+                    }
+                    if new_state {
+                        prediction_error.record(kind, now.translation.xy().distance(original.translation.xy()));
+                    }
+                }
             );
         }
 
+        // The server applies the first unacked input on the tick after the newest state
+        let server_frame = most_recent_state.world.frame + i as u32 + 1;
+
         // Forward predict paddles and balls
-        util::resimulate_all(local_paddle_query.iter_mut(), input);
-        util::resimulate_all(ball_query.iter_mut(), input);
+        util::resimulate_paddles(local_paddle_query.iter_mut(), input);
+        util::carry_held_balls(ball_query.iter_mut(), &local_paddle_query, &most_recent_state, server_frame, served_frame);
+        util::resimulate_balls(ball_query.iter_mut(), world_states.gravity(), &most_recent_state, server_frame, served_frame);
 
-        // Perform collision detection on predicted objects
-        for mut b in ball_query.iter_mut() {
+        // Perform collision detection on predicted objects. Lowest NetId first, same as the server.
+        let mut balls: Vec<_> = ball_query.iter_mut().collect();
+        balls.sort_unstable_by_key(|b| *b.net_id);
+        for b in balls.iter_mut() {
+            if !util::ball_launched(&most_recent_state, b.net_id, server_frame, served_frame) {
+                continue;
+            }
             let colliders = local_paddle_query
                 .iter()
-                .map(|p| (p.entity, p.transform, None))
+                .map(|p| (p.entity, p.transform, None, true))
                 .chain(
                     remaining_colliders
                         .iter()
-                        .map(|r| (r.entity, r.transform, r.brick))
+                        .map(|r| (r.entity, r.transform, r.brick, r.paddle))
                 );
-            check_single_ball_collision(&mut score, colliders, &b.transform, &mut b.velocity, &mut entities_to_ignore);
+            check_single_ball_collision(&mut score, colliders, &b.transform, &mut b.velocity, &mut b.spin, &mut entities_to_ignore, &mut collisions);
+
+            // Every unacked input is resimulated every tick, so the same hit shows up here
+            // again and again. Only the first time it's predicted gets an event.
+            for (pos, kind) in collisions.drain(..) {
+                let key = (input.sequence, *b.net_id);
+                if !played_collisions.contains(&key) {
+                    played_collisions.push_back(key);
+                    let collision = CollisionEvent { frame: server_frame, ball: *b.net_id, pos, kind };
+                    predicted_collisions.record(collision);
+                    collision_events.send(collision);
+                }
+            }
         }
     }
+
+    while played_collisions.len() > MAX_PLAYED_COLLISIONS {
+        played_collisions.pop_front();
+    }
+
+    if predict_bricks {
+        predicted_bricks.destroyed = entities_to_ignore;
+    }
+}
+
+fn play_collision_feedback(
+    mut commands: Commands,
+    mut collision_events: EventReader<CollisionEvent>,
+    sound: Option<Res<CollisionSound>>,
+) {
+    for collision in collision_events.read() {
+        if let Some(sound) = &sound {
+            commands.spawn(AudioBundle {
+                source: sound.0.clone(),
+                settings: PlaybackSettings::DESPAWN,
+            });
+        }
+
+        let color = match collision.kind {
+            CollisionKind::Brick => BRICK_COLOR,
+            CollisionKind::Bounce => WALL_COLOR,
+        };
+        commands.spawn((
+            SpriteBundle {
+                sprite: Sprite { color, ..default() },
+                transform: Transform::from_translation(collision.pos.extend(2.0))
+                    .with_scale(Vec2::splat(BALL_DIAMETER).extend(1.0)),
+                ..default()
+            },
+            CollisionEffect(Timer::from_seconds(COLLISION_EFFECT_S, TimerMode::Once)),
+        ));
+    }
+}
+
+fn fade_collision_effects(
+    mut commands: Commands,
+    mut query: Query<(Entity, &mut CollisionEffect, &mut Sprite, &mut Transform)>,
+    time: Res<Time>,
+) {
+    for (entity, mut effect, mut sprite, mut transform) in &mut query {
+        effect.0.tick(time.delta());
+        if effect.0.finished() {
+            commands.entity(entity).despawn();
+            continue;
+        }
+
+        let remaining = 1.0 - effect.0.fraction();
+        sprite.color.set_alpha(remaining);
+        transform.scale = (Vec2::splat(BALL_DIAMETER) * (2.0 - remaining)).extend(1.0);
+    }
+}
+
+fn predicting_bricks(net_id_utils: Res<NetIdUtils>) -> bool {
+    net_id_utils.args.predict_bricks()
+}
+
+// The predicted score goes up the moment a predicted ball breaks a brick. If the server disagrees,
+// or a hit briefly drops out of resimulation, the predicted score dips. Only follow it down once it
+// has stayed lower for a while, so the scoreboard never flickers.
+fn smooth_score_correction(
+    mut score: ResMut<Score>,
+    mut correction: ResMut<ScoreCorrection>,
+    time: Res<Time<Real>>,
+) {
+    let now = time.elapsed_seconds();
+    if score.0 >= correction.displayed {
+        correction.displayed = score.0;
+        correction.lower_since = None;
+    } else {
+        let lower_since = *correction.lower_since.get_or_insert(now);
+        if now - lower_since >= SCORE_CORRECTION_DELAY_S {
+            info!("Score mispredict, correcting {} -> {}", correction.displayed, score.0);
+            correction.displayed = score.0;
+            correction.lower_since = None;
+        }
+    }
+
+    score.0 = correction.displayed;
+}
+
+fn show_predicted_bricks(
+    predicted_bricks: Res<PredictedBricks>,
+    mut brick_query: Query<(Entity, &mut Visibility), With<Brick>>,
+) {
+    for (entity, mut visibility) in brick_query.iter_mut() {
+        let target = if predicted_bricks.destroyed.contains(&entity) {
+            Visibility::Hidden
+        } else {
+            Visibility::Inherited
+        };
+        visibility.set_if_neq(target);
+    }
 }
 
 
 fn setup(
     mut commands: Commands,
+    asset_server: Res<AssetServer>,
+    net_id_utils: Res<NetIdUtils>,
 ) {
     // Camera
     commands.spawn(Camera2dBundle::default());
 
+    // Lets the player know why their paddle isn't moving yet
+    commands.spawn((
+        TextBundle::from_section(
+            "",
+            TextStyle {
+                font_size: SCOREBOARD_FONT_SIZE,
+                color: TEXT_COLOR,
+                ..default()
+            },
+        )
+        .with_style(Style {
+            position_type: PositionType::Absolute,
+            top: SCOREBOARD_TEXT_PADDING,
+            right: SCOREBOARD_TEXT_PADDING,
+            ..default()
+        }),
+        SyncIndicatorUi,
+    ));
+
+    // Survival mode lives, under the score
+    commands.spawn((
+        TextBundle::from_section(
+            "",
+            TextStyle {
+                font_size: SCOREBOARD_FONT_SIZE,
+                color: SCORE_COLOR,
+                ..default()
+            },
+        )
+        .with_style(Style {
+            position_type: PositionType::Absolute,
+            top: Val::Px(SCOREBOARD_FONT_SIZE + 10.0),
+            left: SCOREBOARD_TEXT_PADDING,
+            ..default()
+        }),
+        LivesUi,
+    ));
+
+    if net_id_utils.args.prediction_error_overlay {
+        commands.spawn((
+            TextBundle::from_section(
+                "",
+                TextStyle {
+                    font_size: SCOREBOARD_FONT_SIZE / 2.0,
+                    color: TEXT_COLOR,
+                    ..default()
+                },
+            )
+            .with_style(Style {
+                position_type: PositionType::Absolute,
+                bottom: SCOREBOARD_TEXT_PADDING,
+                left: SCOREBOARD_TEXT_PADDING,
+                ..default()
+            }),
+            PredictionErrorUi,
+        ));
+    }
+
+    if net_id_utils.args.frame_skew_overlay {
+        commands.spawn((
+            TextBundle::from_section(
+                "",
+                TextStyle {
+                    font_size: SCOREBOARD_FONT_SIZE / 2.0,
+                    color: TEXT_COLOR,
+                    ..default()
+                },
+            )
+            .with_style(Style {
+                position_type: PositionType::Absolute,
+                bottom: SCOREBOARD_TEXT_PADDING,
+                right: SCOREBOARD_TEXT_PADDING,
+                ..default()
+            }),
+            FrameSkewUi,
+        ));
+    }
+
+    // Pre-match countdown, centered over the arena
+    commands.spawn(
+        NodeBundle {
+            style: Style {
+                width: Val::Percent(100.0),
+                height: Val::Percent(100.0),
+                align_items: AlignItems::Center,
+                justify_content: JustifyContent::Center,
+                ..default()
+            },
+            ..default()
+        }
+    ).with_children(|parent| {
+        parent.spawn((
+            TextBundle::from_section(
+                "",
+                TextStyle {
+                    font_size: SCOREBOARD_FONT_SIZE * 2.0,
+                    color: TEXT_COLOR,
+                    ..default()
+                },
+            ),
+            CountdownUi,
+        ));
+    });
+
+    // Sound
+    if let Some(path) = &net_id_utils.args.collision_sound {
+        commands.insert_resource(CollisionSound(asset_server.load(path.clone())));
+    }
+
     // Scoreboard
     commands.spawn(ScoreboardUiBundle::new());
 
@@ -234,7 +629,9 @@ fn setup(
     commands.spawn(WallBundle::new(WallLocation::Right));
     commands.spawn(WallBundle::new(WallLocation::Bottom));
     commands.spawn(WallBundle::new(WallLocation::Top));
+}
 
+fn spawn_perf_ui(mut commands: Commands) {
     commands.spawn((
         PerfUiRoot {
             display_labels: false,
@@ -249,10 +646,280 @@ fn setup(
 fn interpolate_frame_for_render(
     mut query: Query<(&mut Transform, &InterpolatedTransform)>,
     time: Res<Time<Fixed>>,
+    world_states: Res<WorldStates>,
 ) {
+    let blend = world_states.extrapolation_blend;
+    let alpha = world_states.interp_alpha(time.overstep_fraction());
     for (mut transform, interp) in &mut query {
-        let alpha= time.overstep_fraction();
-        transform.translation = interp.from.translation.lerp(interp.to.translation, alpha);
+        let (from, to) = (interp.from.translation, interp.to.translation);
+        let prev = interp.prev.unwrap_or(2.0 * from - to);
+        let next = interp.next.unwrap_or(2.0 * to - from);
+        let interpolated = util::catmull_rom(prev, from, to, next, alpha);
+        // Leaning a tick ahead as the buffer drains means starved ticks, which extrapolate from 'to',
+        // pick up right where the last frame left off
+        transform.translation = if blend > 0.0 {
+            interpolated.lerp(to + (to - from) * alpha, blend)
+        } else {
+            interpolated
+        };
+    }
+}
+
+// Tab steps through our paddles one at a time and then back to moving all of them together
+fn cycle_paddle_selection(
+    keyboard_input: Res<ButtonInput<KeyCode>>,
+    world_states: Res<WorldStates>,
+    paddle_query: Query<&NetPlayerIndex, With<Paddle>>,
+    mut selection: ResMut<PaddleSelection>,
+) {
+    if !keyboard_input.just_pressed(KeyCode::Tab) {
+        return;
+    }
+
+    let Some(local_client_index) = world_states.states.back().map(|s| s.local_client_index) else {
+        return;
+    };
+    let num_paddles = paddle_query.iter().filter(|p| p.0 == local_client_index).count();
+    if num_paddles < 2 {
+        selection.0 = None;
+        return;
+    }
+
+    selection.0 = match selection.0 {
+        None => Some(0),
+        Some(p) if (p as usize) + 1 < num_paddles => Some(p + 1),
+        Some(_) => None,
+    };
+    info!("Paddle selection is now {:?}", selection.0);
+}
+
+fn toggle_input_starvation(
+    keyboard_input: Res<ButtonInput<KeyCode>>,
+    mut starvation: ResMut<InputStarvation>,
+) {
+    if keyboard_input.just_pressed(KeyCode::F8) {
+        starvation.active = !starvation.active;
+        if starvation.active {
+            warn!("Input starvation on, no inputs are sent until F8 is pressed again");
+        } else {
+            warn!("Input starvation off, sending inputs again");
+        }
+    }
+}
+
+// B switches our own ball between predicted and interpolated, to compare how each feels mid-match
+fn toggle_local_ball_prediction(
+    mut commands: Commands,
+    keyboard_input: Res<ButtonInput<KeyCode>>,
+    mut net_id_utils: ResMut<NetIdUtils>,
+    world_states: Res<WorldStates>,
+    ball_query: Query<(Entity, &NetPlayerIndex, &Transform, Has<LocallyPredicted>), With<Ball>>,
+) {
+    if !keyboard_input.just_pressed(KeyCode::KeyB) {
+        return;
+    }
+    // Everything stays interpolated with --disable-client-prediction, B can't switch it back on
+    if net_id_utils.args.disable_client_prediction {
+        info!("Client prediction is disabled, ignoring the local ball prediction toggle");
+        return;
+    }
+
+    let policy = &mut net_id_utils.prediction_policy;
+    let bundle_type = match policy.bundle_type(NetEntityKind::Ball, NetOwnership::Local) {
+        NetBundleType::Predicted => NetBundleType::Interpolated,
+        NetBundleType::Interpolated => NetBundleType::Predicted,
+    };
+    policy.set(NetEntityKind::Ball, NetOwnership::Local, bundle_type);
+    info!("Local ball is now {:?}", bundle_type);
+
+    let Some(local_client_index) = world_states.states.back().map(|s| s.local_client_index) else {
+        return;
+    };
+    for (entity, player, transform, predicted) in ball_query.iter() {
+        if player.0 != local_client_index {
+            continue;
+        }
+
+        match bundle_type {
+            NetBundleType::Interpolated if predicted => {
+                // Start from where the prediction left it, the next applied state takes over from there
+                commands.entity(entity)
+                    .remove::<LocallyPredicted>()
+                    .insert(InterpolatedTransform { from: *transform, to: *transform, ..default() });
+            }
+            NetBundleType::Predicted if !predicted => {
+                // Next reconcile rolls it back to the newest state and replays every unacked input,
+                // so there's no in-flight prediction state to carry over
+                commands.entity(entity)
+                    .remove::<InterpolatedTransform>()
+                    .insert(LocallyPredicted);
+            }
+            _ => {}
+        }
+    }
+}
+
+fn server_running(world_states: Res<WorldStates>) -> bool {
+    world_states.states.back().map_or(true, |s| !s.world.paused)
+}
+
+// Fires each server collision once, when interpolation reaches the state that carried it.
+// Predicted balls already fired events from reconcile for the hits they saw coming, so skip those.
+fn emit_server_collisions(
+    world_states: Res<WorldStates>,
+    net_id_map: Res<NetIdUtils>,
+    predicted_query: Query<(), With<LocallyPredicted>>,
+    predicted_collisions: Res<PredictedCollisions>,
+    mut seen: Local<VecDeque<(u32, NetId)>>,
+    mut collision_events: EventWriter<CollisionEvent>,
+) {
+    let Some(to_frame) = world_states.interpolating_to else {
+        return;
+    };
+    let Some(to_state) = world_states.states.iter().find(|s| s.world.frame == to_frame) else {
+        return;
+    };
+
+    for collision in to_state.world.collisions.iter() {
+        let predicted = net_id_map.net_id_to_entity_id
+            .get(&collision.ball)
+            .is_some_and(|&e| predicted_query.contains(e));
+        if predicted && predicted_collisions.contains(collision) {
+            continue;
+        }
+
+        let key = (collision.frame, collision.ball);
+        if !seen.contains(&key) {
+            seen.push_back(key);
+            collision_events.send(*collision);
+        }
+    }
+
+    while seen.len() > MAX_COLLISIONS_PER_PACKET * COLLISION_RESEND_FRAMES as usize {
+        seen.pop_front();
+    }
+}
+
+fn update_prediction_error_overlay(
+    prediction_error: Res<PredictionError>,
+    mut query: Query<&mut Text, With<PredictionErrorUi>>,
+) {
+    let Ok(mut text) = query.get_single_mut() else {
+        return;
+    };
+
+    let (paddle, ball) = (&prediction_error.paddle, &prediction_error.ball);
+    let value = format!(
+        "prediction error (last {}s)\npaddle mean {:.2} max {:.2} px ({} samples)\nball mean {:.2} max {:.2} px ({} samples)",
+        PREDICTION_ERROR_WINDOW_S,
+        paddle.mean, paddle.window_max, paddle.window_samples,
+        ball.mean, ball.window_max, ball.window_samples
+    );
+
+    if text.sections[0].value != value {
+        text.sections[0].value = value;
+    }
+}
+
+fn update_frame_skew_overlay(
+    world_states: Res<WorldStates>,
+    ping_state: Res<PingState>,
+    arrival_intervals: Res<ArrivalIntervals>,
+    mut query: Query<&mut Text, With<FrameSkewUi>>,
+) {
+    let Ok(mut text) = query.get_single_mut() else {
+        return;
+    };
+
+    let mut value = match world_states.render_skew_frames() {
+        Some(skew) => format!(
+            "rendering {} frames ({:.0} ms) behind server\n{} states buffered",
+            skew,
+            skew as f64 * TICK_S * 1000.0,
+            world_states.states.len()
+        ),
+        None => String::new(),
+    };
+    let rtt = &ping_state.rtt;
+    if let (Some(mean), Some(min), Some(p95)) = (rtt.mean(), rtt.min(), rtt.p95()) {
+        value.push_str(&format!("\nrtt mean {:.1} min {:.1} p95 {:.1} ms (last {})", mean, min, p95, rtt.len()));
+    }
+    let arrivals = *arrival_intervals;
+    if arrivals.count > 0 {
+        value.push_str(&format!(
+            "\nstate intervals min {:.1} mean {:.1} max {:.1} jitter {:.1} ms",
+            arrivals.min_ms, arrivals.mean_ms, arrivals.max_ms, arrivals.jitter_ms
+        ));
+    }
+
+    if text.sections[0].value != value {
+        text.sections[0].value = value;
+    }
+}
+
+fn update_sync_indicator(
+    world_states: Res<WorldStates>,
+    mut query: Query<&mut Text, With<SyncIndicatorUi>>,
+) {
+    let mut text = query.single_mut();
+    let value = if !world_states.interp_started {
+        "Connecting...".to_string()
+    } else if world_states.states.back().is_some_and(|s| s.world.paused) {
+        "Paused".to_string()
+    } else if world_states.server_input_state == NetInputState::Buffering {
+        format!("Syncing... {} ms", world_states.server_buffer_remaining_ms)
+    } else {
+        String::new()
+    };
+
+    if text.sections[0].value != value {
+        text.sections[0].value = value;
+    }
+}
+
+fn update_lives(
+    world_states: Res<WorldStates>,
+    mut query: Query<&mut Text, With<LivesUi>>,
+) {
+    let mut text = query.single_mut();
+    let survival = world_states.server_info.is_some_and(|info| info.lives > 0);
+    let value = match world_states.states.back() {
+        Some(state) if survival => match util::local_lives(state) {
+            Some(lives) => format!("Lives: {}", lives),
+            None => "Out".to_string()
+        },
+        _ => String::new()
+    };
+
+    if text.sections[0].value != value {
+        text.sections[0].value = value;
+    }
+}
+
+fn update_countdown(
+    world_states: Res<WorldStates>,
+    time: Res<Time>,
+    mut query: Query<&mut Text, With<CountdownUi>>,
+    mut last_phase: Local<MatchPhase>,
+    mut go_remaining_s: Local<f32>,
+) {
+    let mut text = query.single_mut();
+    let phase = world_states.states.back().map_or(MatchPhase::Playing, |s| s.world.phase);
+    if phase == MatchPhase::Playing && *last_phase != MatchPhase::Playing {
+        *go_remaining_s = COUNTDOWN_GO_S;
+    }
+    *last_phase = phase;
+    *go_remaining_s = (*go_remaining_s - time.delta_seconds()).max(0.0);
+
+    let value = match phase {
+        MatchPhase::WaitingForPlayers => "Waiting for players".to_string(),
+        MatchPhase::Countdown(ticks) => format!("{}", (ticks as f64 / TICK_RATE_HZ).ceil() as u32),
+        MatchPhase::Playing if *go_remaining_s > 0.0 => "GO!".to_string(),
+        MatchPhase::Playing => String::new(),
+    };
+
+    if text.sections[0].value != value {
+        text.sections[0].value = value;
     }
 }
 
@@ -262,7 +929,11 @@ fn send_input (
     mut transport: ResMut<Transport>,
     world_states: ResMut<WorldStates>,
     fixed_state: ResMut<FixedTickWorldResource>,
-    mut unacked_inputs: ResMut<UnAckedPlayerInputs>
+    mut unacked_inputs: ResMut<UnAckedPlayerInputs>,
+    mut net_stats: ResMut<NetStats>,
+    selection: Res<PaddleSelection>,
+    starvation: Res<InputStarvation>,
+    time: Res<Time<Real>>,
 ) {
     if world_states.interpolating_from.is_none() {
         return;
@@ -271,6 +942,7 @@ fn send_input (
     let mut input = PlayerInputData::default();
     input.sequence = fixed_state.frame_counter;
     input.simulating_frame = world_states.interpolating_from.unwrap();
+    input.paddle = selection.0;
 
     if keyboard_input.pressed(KeyCode::ArrowLeft) {
         input.key_mask |= 1 << (NetKey::Left as u8);
@@ -280,12 +952,36 @@ fn send_input (
         input.key_mask |= 1 << (NetKey::Right as u8);
     }
 
-    unacked_inputs.inputs.push_back(input.clone());
+    if keyboard_input.pressed(KeyCode::Space) {
+        input.key_mask |= 1 << (NetKey::Serve as u8);
+    }
+
+    unacked_inputs.push(input.clone(), time.elapsed_seconds());
+    if starvation.active {
+        return;
+    }
+    let sequence = input.sequence;
 
     let packet = ClientToServerPacket::Input(input);
     let mut buf = [0; networking::ETHERNET_MTU];
-    let num_bytes = bincode::serde::encode_into_slice(packet, &mut buf, config::standard()).unwrap();
-    transport.send(remote_addr.0, &buf[..num_bytes]);
+    let num_bytes = bincode::serde::encode_into_slice(packet, &mut buf, NET_CONFIG).unwrap();
+    let send_time = transport.send(remote_addr.0, &buf[..num_bytes]);
+    net_stats.record_send(sequence, send_time);
+}
+
+fn send_disconnect_on_exit(
+    mut exit_events: EventReader<AppExit>,
+    remote_addr: Res<ResSocketAddr>,
+    mut transport: ResMut<Transport>,
+) {
+    if exit_events.read().next().is_none() {
+        return;
+    }
+
+    let mut buf = [0; networking::ETHERNET_MTU];
+    let num_bytes = bincode::serde::encode_into_slice(ClientToServerPacket::Disconnect, &mut buf, NET_CONFIG).unwrap();
+    // Sim loss would otherwise drop it, and then the server waits out the idle timeout
+    transport.send_immediate(remote_addr.0, &buf[..num_bytes]);
 }
 
 fn ping_server(
@@ -309,25 +1005,84 @@ fn ping_server(
     state.next_ping_id += 1;
 
     let mut buf = [0; networking::ETHERNET_MTU];
-    let num_bytes = bincode::serde::encode_into_slice(packet, &mut buf, config::standard()).unwrap();
+    let num_bytes = bincode::serde::encode_into_slice(packet, &mut buf, NET_CONFIG).unwrap();
     transport.send(remote_addr.0, &buf[..num_bytes]);
 
     debug!("({})  {} at {:?}", fixed_state.frame_counter, ping_id, time::Instant::now());
 }
 
+fn request_resync(
+    remote_addr: Res<ResSocketAddr>,
+    mut resync: ResMut<ResyncState>,
+    mut transport: ResMut<Transport>,
+    time: Res<Time<Real>>,
+) {
+    let now = time.elapsed_seconds();
+    if !resync.requested || resync.last_sent_s.is_some_and(|last| now - last < RESYNC_MIN_INTERVAL_S) {
+        return;
+    }
+
+    resync.requested = false;
+    resync.decode_failure_streak = 0;
+    resync.last_sent_s = Some(now);
+    resync.sent += 1;
+    info!("Out of sync, asking the server for a resync ({} so far)", resync.sent);
+
+    let mut buf = [0; networking::ETHERNET_MTU];
+    let num_bytes = bincode::serde::encode_into_slice(ClientToServerPacket::ResyncRequest, &mut buf, NET_CONFIG).unwrap();
+    transport.send(remote_addr.0, &buf[..num_bytes]);
+}
+
+fn extrapolate_while_starved(
+    world_states: &mut WorldStates,
+    query: &mut Query<&mut InterpolatedTransform>,
+    blend_states: usize,
+) {
+    if !world_states.interp_started || blend_states == 0 {
+        return;
+    }
+
+    if world_states.extrapolated_ticks >= MAX_EXTRAPOLATION_TICKS {
+        // Guessed long enough, wait for the server where we are
+        world_states.extrapolation_blend = 0.0;
+        util::hold_interpolated(query);
+        return;
+    }
+
+    // The first guess continues a from -> to that covers a whole send interval, later ones a tick
+    let ticks_per_step = if world_states.extrapolated_ticks == 0 { world_states.send_interval_ticks() } else { 1 };
+    world_states.extrapolated_ticks += 1;
+    world_states.extrapolation_blend = 1.0;
+    util::extrapolate_interpolated(query, ticks_per_step);
+}
+
 fn tick_simulation(
     mut commands: Commands,
     mut world_states: ResMut<WorldStates>,
     mut query: Query<&mut InterpolatedTransform>,
     net_id_query: Query<(Entity, &NetId)>,
     mut net_id_map: ResMut<NetIdUtils>,
-    mut meshes: ResMut<Assets<Mesh>>,
-    mut materials: ResMut<Assets<ColorMaterial>>,
+    mut ball_assets: BallAssets,
     mut score: ResMut<Score>,
     mut ping_state: ResMut<PingState>,
-    //fixed_state: Res<FixedTickWorldResource>,
+    fixed_state: Res<FixedTickWorldResource>,
     time: Res<Time<Real>>,
+    jitter_padding: Res<JitterPadding>,
+    mut arrival_intervals: ResMut<ArrivalIntervals>,
 ) {
+    let jitter_padding_s = jitter_padding.effective_s(&world_states.jitter);
+    // States only arrive every send interval, so we have to be that much further behind
+    let send_interval_s = TICK_S * world_states.send_interval_ticks() as f64;
+    let interp_delay_s = interp_delay_s(jitter_padding_s) + send_interval_s - TICK_S;
+    let _span = info_span!(
+        parent: &fixed_state.tick_span(),
+        "tick_simulation",
+        frame = fixed_state.frame_counter,
+        buffered = world_states.states.len(),
+        jitter_padding_ms = jitter_padding_s * 1000.0,
+        server_input_delay_ms = world_states.server_input_delay_ms
+    ).entered();
+
     // Clear old entries from our stats
     let now = time.elapsed_seconds();
     while !world_states.received_per_sec.is_empty() {
@@ -338,22 +1093,46 @@ fn tick_simulation(
             break;
         }
     }
+    let arrival_window = time::Duration::from_secs(1);
+    while world_states.arrival_times.front().is_some_and(|t| t.elapsed() > arrival_window) {
+        world_states.arrival_times.pop_front();
+    }
 
-    /*for pong in ping_state.pongs.clone().iter() {
-        let instant = ping_state.ping_id_to_instance.remove(&pong.ping_id).unwrap();
-        info!("({}) {} ms raw pong for ping {}", fixed_state.frame_counter, instant.elapsed().as_millis(), pong.ping_id);
-    }*/
-    ping_state.pongs.clear();
+    for pong in std::mem::take(&mut ping_state.pongs) {
+        // Pongs for pings we already gave up on were counted as lost, ignore them
+        if let Some(instant) = ping_state.ping_id_to_instance.remove(&pong.ping_id) {
+            ping_state.pongs_received += 1;
+            ping_state.rtt.record(instant.elapsed().as_secs_f32() * 1000.0);
+            debug!("{} ms raw pong for ping {}", instant.elapsed().as_millis(), pong.ping_id);
+        }
+    }
 
-    //if !world_states.received_per_sec.is_empty() {
-        //let mut avg_interval: f32 = world_states.received_per_sec.iter().tuple_windows().map(|(&p,&c)| c - p).sum();
-        //avg_interval /= world_states.received_per_sec.len() as f32;
-        //let intervals: Vec<f32> = world_states.received_per_sec.iter().tuple_windows().map(|(&p,&c)| c - p).collect();
-        //warn!("{} PPS, INTERVALS {:?}", world_states.received_per_sec.len(), intervals);
-    //}
+    // Without this the map grows forever when pongs get lost
+    let num_pending = ping_state.ping_id_to_instance.len();
+    let timeout = time::Duration::from_secs_f64(PING_TIMEOUT_S);
+    ping_state.ping_id_to_instance.retain(|_, sent| sent.elapsed() < timeout);
+    let num_expired = num_pending - ping_state.ping_id_to_instance.len();
+    if num_expired > 0 {
+        ping_state.pings_lost += num_expired as u32;
+        debug!("{} pings expired, loss estimate {}", num_expired, ping_state.loss_estimate());
+    }
 
+    *arrival_intervals = ArrivalIntervals::from_arrivals(&world_states.arrival_times);
+
+    // With a lower --send-hz on the server each pair of states covers several ticks, keep
+    // interpolating between the current pair until they're used up
+    if world_states.interp_started && world_states.extrapolated_ticks == 0 {
+        world_states.ticks_into_interval += 1;
+        if world_states.ticks_into_interval < world_states.send_interval_ticks() {
+            return;
+        }
+    }
+    world_states.ticks_into_interval = 0;
+
+    let blend_states = net_id_map.args.extrapolation_blend_states;
     if world_states.states.len() < 2 {
         debug!("STARVED {}!", world_states.states.len());
+        extrapolate_while_starved(&mut world_states, &mut query, blend_states);
         return;
     }
 
@@ -367,11 +1146,13 @@ fn tick_simulation(
         bootstrap_first_state = true;
     }
 
-    let expected_buffer = 2 + f64::round(INTERP_DELAY_S / TICK_S) as usize;
+    // One more than from/to so the state after to is usually buffered for smoothing, unless capped by --max-buffer
+    let expected_buffer = net_id_map.args.buffer_target(3 + f64::round(interp_delay_s / send_interval_s) as usize);
 
     if world_states.received_per_sec.len() > 0 &&
-        now - world_states.received_per_sec.front().unwrap() < INTERP_DELAY_S as f32 {
-        warn!("STARVED INTERP {} vs {}!", now - world_states.received_per_sec.back().unwrap(), INTERP_DELAY_S);
+        now - world_states.received_per_sec.front().unwrap() < interp_delay_s as f32 {
+        warn!("STARVED INTERP {} vs {}!", now - world_states.received_per_sec.back().unwrap(), interp_delay_s);
+        extrapolate_while_starved(&mut world_states, &mut query, blend_states);
         return;
     } else if world_states.states.len() > expected_buffer && world_states.interp_started {
         let drain_len = world_states.states.len() - expected_buffer;
@@ -383,9 +1164,11 @@ fn tick_simulation(
 
     if (bootstrap_first_state && world_states.states.len() < 2) ||
         world_states.states.is_empty() {
+        extrapolate_while_starved(&mut world_states, &mut query, blend_states);
         return;
     }
 
+    let paddle_tuning = world_states.paddle_tuning();
     if bootstrap_first_state {
         let from_state = &world_states.states[0];
         util::update_map_and_apply_world_state(
@@ -393,9 +1176,9 @@ fn tick_simulation(
             &mut query,
             &net_id_query,
             &mut net_id_map,
-            &mut meshes,
-            &mut materials,
+            &mut ball_assets,
             &mut score,
+            paddle_tuning,
             from_state);
         world_states.interpolating_from = Some(from_state.world.frame);
 
@@ -405,11 +1188,14 @@ fn tick_simulation(
             &mut query,
             &net_id_query,
             &mut net_id_map,
-            &mut meshes,
-            &mut materials,
+            &mut ball_assets,
             &mut score,
+            paddle_tuning,
             to_state);
         world_states.interpolating_to = Some(to_state.world.frame);
+        if let Some(next_state) = world_states.states.get(2) {
+            util::apply_next_world_state(&mut query, &net_id_map, next_state);
+        }
     } else {
         let to_state = &world_states.states[0];
         util::update_map_and_apply_world_state(
@@ -417,13 +1203,360 @@ fn tick_simulation(
             &mut query,
             &net_id_query,
             &mut net_id_map,
-            &mut meshes,
-            &mut materials,
+            &mut ball_assets,
             &mut score,
+            paddle_tuning,
             to_state);
         world_states.interpolating_to = Some(to_state.world.frame);
+        if let Some(next_state) = world_states.states.get(1) {
+            util::apply_next_world_state(&mut query, &net_id_map, next_state);
+        }
     }
 
+    // states[0] is now 'to', everything after it is buffer we can still interpolate into
+    world_states.extrapolated_ticks = 0;
+    let buffered_ahead = world_states.states.len() - 1;
+    world_states.extrapolation_blend = util::extrapolation_blend(buffered_ahead, blend_states);
+
     //info!("{} us", (Instant::now() - now_inst).as_micros());
 }
 
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bevy::ecs::system::{RunSystemOnce, SystemState};
+
+    const PADDLE_ID: NetId = NetId(1);
+    const BALL_ID: NetId = NetId(2);
+
+    fn input(sequence: u32, key: NetKey) -> PlayerInputData {
+        PlayerInputData { key_mask: 1 << key as u8, simulating_frame: sequence, sequence, paddle: None }
+    }
+
+    fn world_state(frame: u32, last_applied_input: u32, paddle_pos: Vec2, ball: NetBallData) -> ClientWorldState {
+        let mut world = NetWorldStateData { frame, ..default() };
+        world.entities.push(NetEntity {
+            entity_type: NetEntityType::Paddle(NetPaddleData { pos: paddle_pos, player_index: NetPlayerIndex(0), color: NetColorIndex(0), lives: None }),
+            net_id: PADDLE_ID
+        });
+        world.entities.push(NetEntity { entity_type: NetEntityType::Ball(ball), net_id: BALL_ID });
+        ClientWorldState::new(world, last_applied_input, 0)
+    }
+
+    // Inputs 1 to 5 are sent, the server state for frame 10 has applied 1 and 2, so reconciling
+    // replays 3 to 5 as server frames 11 to 13. Returns the world with the paddle and ball spawned
+    // somewhere else on purpose, reconciling shouldn't care where they start.
+    fn reconcile_world(server_paddle: Vec2, server_ball: NetBallData) -> (World, Entity, Entity) {
+        let args = Args::parse_from(["client"]);
+        let mut world = World::new();
+        world.insert_resource(NetIdUtils {
+            net_id_to_entity_id: HashMap::new(),
+            missing_states: HashMap::new(),
+            spawned_ahead: HashSet::new(),
+            prediction_policy: PredictionPolicy::new(&args),
+            args
+        });
+        world.insert_resource(Time::<Real>::default());
+        world.insert_resource(FixedTickWorldResource::default());
+        world.insert_resource(PredictedBricks::default());
+        world.insert_resource(PredictionError::default());
+        world.insert_resource(Score(0));
+        world.init_resource::<Events<CollisionEvent>>();
+        world.init_resource::<PredictedCollisions>();
+
+        let mut unacked = UnAckedPlayerInputs::default();
+        for (sequence, key) in [(1, NetKey::Left), (2, NetKey::Left), (3, NetKey::Right), (4, NetKey::Right), (5, NetKey::Left)] {
+            unacked.push(input(sequence, key), 0.0);
+        }
+        world.insert_resource(unacked);
+
+        let mut world_states = WorldStates::default();
+        world_states.states.push_back(world_state(10, 2, server_paddle, server_ball));
+        world.insert_resource(world_states);
+
+        let paddle = world.spawn((Transform::from_xyz(50.0, -250.0, 0.0), PADDLE_ID, Paddle, PaddleTuning::default(), Collider, LocallyPredicted)).id();
+        let ball = world.spawn((
+            Transform::from_xyz(30.0, 30.0, 1.0), Velocity(Vec2::ZERO), Spin(0.0), BALL_ID, Ball, LocallyPredicted
+        )).id();
+        (world, paddle, ball)
+    }
+
+    fn replayed_inputs() -> [PlayerInputData; 3] {
+        [input(3, NetKey::Right), input(4, NetKey::Right), input(5, NetKey::Left)]
+    }
+
+    // Our own launched ball with no spin, override the rest with struct update syntax
+    fn net_ball(pos: Vec2, velocity: Vec2) -> NetBallData {
+        NetBallData {
+            pos,
+            velocity,
+            spin: 0.0,
+            launch_frame: Some(0),
+            held_by: None,
+            player_index: NetPlayerIndex(0),
+            color: NetColorIndex(0)
+        }
+    }
+
+    // Where the paddle and a ball held by it end up after replaying replayed_inputs as server frames
+    // 11 to 13. The ball rides the paddle through launch_frame, then moves from there.
+    fn expected_held_launch(server_paddle: Vec2, velocity: Vec2, launch_frame: u32) -> (Transform, Transform) {
+        let tuning = PaddleTuning::default();
+        let mut expected_paddle = Transform::from_translation(server_paddle.extend(0.0));
+        let mut expected_ball = Transform::from_translation(held_ball_position(server_paddle, &tuning).extend(1.0));
+        let mut expected_velocity = Velocity(velocity);
+        let mut expected_spin = Spin(0.0);
+        for (frame, replayed) in (11..).zip(replayed_inputs()) {
+            move_paddle(&mut expected_paddle, &replayed, &tuning);
+            if frame <= launch_frame {
+                expected_ball.translation = held_ball_position(expected_paddle.translation.xy(), &tuning).extend(1.0);
+            }
+            if frame >= launch_frame {
+                apply_spin(&mut expected_velocity, &mut expected_spin);
+                apply_velocity(&mut expected_ball, &expected_velocity);
+            }
+        }
+        (expected_paddle, expected_ball)
+    }
+
+    #[test]
+    fn test_reconcile_replays_unacked_inputs() {
+        let server_paddle = Vec2::new(-100.0, -250.0);
+        let server_ball = NetBallData { spin: 0.5, ..net_ball(Vec2::new(0.0, 100.0), Vec2::new(200.0, 150.0)) };
+        let (ball_pos, ball_velocity, ball_spin) = (server_ball.pos, server_ball.velocity, server_ball.spin);
+        let (mut world, paddle, ball) = reconcile_world(server_paddle, server_ball);
+
+        world.run_system_once(reconcile_and_update_predictions);
+
+        let mut expected_paddle = Transform::from_translation(server_paddle.extend(0.0));
+        let mut expected_ball = Transform::from_translation(ball_pos.extend(1.0));
+        let mut expected_velocity = Velocity(ball_velocity);
+        let mut expected_spin = Spin(ball_spin);
+        for replayed in replayed_inputs() {
+            move_paddle(&mut expected_paddle, &replayed, &PaddleTuning::default());
+            apply_spin(&mut expected_velocity, &mut expected_spin);
+            apply_velocity(&mut expected_ball, &expected_velocity);
+        }
+
+        assert_eq!(world.get::<Transform>(paddle).unwrap().translation, expected_paddle.translation);
+        assert_eq!(world.get::<Transform>(ball).unwrap().translation, expected_ball.translation);
+        assert_eq!(world.get::<Velocity>(ball).unwrap().0, expected_velocity.0);
+        assert_eq!(world.resource::<UnAckedPlayerInputs>().len(), 3);
+    }
+
+    #[test]
+    fn test_reconcile_applies_server_gravity() {
+        let gravity = 980.0;
+        let server_ball = net_ball(Vec2::new(0.0, 100.0), Vec2::new(200.0, 150.0));
+        let (ball_pos, ball_velocity) = (server_ball.pos, server_ball.velocity);
+        let (mut world, _, ball) = reconcile_world(Vec2::new(-100.0, -250.0), server_ball);
+        world.resource_mut::<WorldStates>().server_info = Some(NetServerInfo {
+            tick_hz: TICK_RATE_HZ as u16,
+            send_interval_ticks: 1,
+            paddles_per_player: 1,
+            teams: 0,
+            arena_size: arena_size(),
+            paddle: PaddleTuning::default(),
+            lives: 0,
+            gravity
+        });
+
+        world.run_system_once(reconcile_and_update_predictions);
+
+        let mut expected_ball = Transform::from_translation(ball_pos.extend(1.0));
+        let mut expected_velocity = Velocity(ball_velocity);
+        for _ in replayed_inputs() {
+            apply_gravity(&mut expected_velocity, gravity);
+            apply_velocity(&mut expected_ball, &expected_velocity);
+        }
+
+        assert!(expected_velocity.y < ball_velocity.y);
+        assert_eq!(world.get::<Velocity>(ball).unwrap().0, expected_velocity.0);
+        assert_eq!(world.get::<Transform>(ball).unwrap().translation, expected_ball.translation);
+    }
+
+    // The paddle isn't spawned yet when the state with it arrives. It should show up where the
+    // replayed inputs left it, so reconcile carries on from there instead of correcting it.
+    #[test]
+    fn test_local_paddle_spawns_where_reconcile_predicts() {
+        let server_paddle = Vec2::new(-100.0, -250.0);
+        let server_ball = net_ball(Vec2::new(0.0, 100.0), Vec2::ZERO);
+        let (mut world, paddle, _) = reconcile_world(server_paddle, server_ball);
+        world.despawn(paddle);
+
+        world.run_system_once(spawn_local_paddles);
+
+        let paddle = world.resource::<NetIdUtils>().net_id_to_entity_id[&PADDLE_ID];
+        let mut expected_paddle = Transform::from_translation(server_paddle.extend(0.0));
+        let [first, second, last] = replayed_inputs();
+        for replayed in [first, second] {
+            move_paddle(&mut expected_paddle, &replayed, &PaddleTuning::default());
+        }
+        assert_eq!(world.get::<Transform>(paddle).unwrap().translation, expected_paddle.translation);
+        assert!(world.get::<LocallyPredicted>(paddle).is_some());
+
+        world.run_system_once(reconcile_and_update_predictions);
+
+        move_paddle(&mut expected_paddle, &last, &PaddleTuning::default());
+        assert_eq!(world.get::<Transform>(paddle).unwrap().translation, expected_paddle.translation);
+    }
+
+    // The ball launches on frame 12. It has to ride the predicted paddle through frames 11 and 12,
+    // then leave from where the paddle was on 12, the same way the server's carry_held_balls does.
+    #[test]
+    fn test_held_ball_follows_paddle_until_launch() {
+        let server_paddle = Vec2::new(-100.0, -250.0);
+        let server_ball = NetBallData {
+            launch_frame: Some(12),
+            held_by: Some(PADDLE_ID),
+            ..net_ball(held_ball_position(server_paddle, &PaddleTuning::default()), Vec2::new(200.0, 150.0))
+        };
+        let ball_velocity = server_ball.velocity;
+        let (mut world, paddle, ball) = reconcile_world(server_paddle, server_ball);
+
+        world.run_system_once(reconcile_and_update_predictions);
+
+        let (expected_paddle, expected_ball) = expected_held_launch(server_paddle, ball_velocity, 12);
+        assert_eq!(world.get::<Transform>(paddle).unwrap().translation, expected_paddle.translation);
+        assert_eq!(world.get::<Transform>(ball).unwrap().translation, expected_ball.translation);
+    }
+
+    // During play our ball waits on the paddle with no launch frame until we serve. Input 4 serves,
+    // the server applies it on frame 12 and launches the ball that tick, so it has to be predicted
+    // the same as a launch frame of 12.
+    #[test]
+    fn test_serve_launches_ball_waiting_on_paddle() {
+        let server_paddle = Vec2::new(-100.0, -250.0);
+        let server_ball = NetBallData {
+            launch_frame: None,
+            held_by: Some(PADDLE_ID),
+            ..net_ball(held_ball_position(server_paddle, &PaddleTuning::default()), Vec2::new(200.0, 150.0))
+        };
+        let ball_velocity = server_ball.velocity;
+        let (mut world, paddle, ball) = reconcile_world(server_paddle, server_ball);
+        let mut unacked = UnAckedPlayerInputs::default();
+        for (i, mut replayed) in replayed_inputs().into_iter().enumerate() {
+            if i == 1 {
+                replayed.key_mask |= 1 << NetKey::Serve as u8;
+            }
+            unacked.push(replayed, 0.0);
+        }
+        world.insert_resource(unacked);
+
+        world.run_system_once(reconcile_and_update_predictions);
+
+        let (expected_paddle, expected_ball) = expected_held_launch(server_paddle, ball_velocity, 12);
+        assert_eq!(world.get::<Transform>(paddle).unwrap().translation, expected_paddle.translation);
+        assert_eq!(world.get::<Transform>(ball).unwrap().translation, expected_ball.translation);
+    }
+
+    // Predicted balls only skip the server's copy of hits reconcile already played, a hit
+    // prediction missed still plays when interpolation reaches it
+    #[test]
+    fn test_server_collisions_for_predicted_balls_play_unless_predicted() {
+        let server_ball = net_ball(Vec2::new(0.0, 100.0), Vec2::ZERO);
+        let (mut world, _, ball) = reconcile_world(Vec2::new(-100.0, -250.0), server_ball);
+        world.resource_mut::<NetIdUtils>().net_id_to_entity_id.insert(BALL_ID, ball);
+
+        let predicted_hit = CollisionEvent { frame: 9, ball: BALL_ID, pos: Vec2::new(10.0, 100.0), kind: CollisionKind::Bounce };
+        let missed_hit = CollisionEvent { frame: 10, ball: BALL_ID, pos: Vec2::new(-200.0, 300.0), kind: CollisionKind::Brick };
+        // Reconcile's guess at the frame is a tick off, still the same hit
+        world.resource_mut::<PredictedCollisions>().record(CollisionEvent { frame: 8, ..predicted_hit });
+        let mut world_states = world.resource_mut::<WorldStates>();
+        world_states.interpolating_to = Some(10);
+        world_states.states[0].world.collisions.extend([predicted_hit, missed_hit]);
+
+        world.run_system_once(emit_server_collisions);
+
+        let events = world.resource::<Events<CollisionEvent>>();
+        let fired: Vec<_> = events.get_reader().read(events).map(|c| (c.frame, c.kind)).collect();
+        assert_eq!(fired, vec![(missed_hit.frame, missed_hit.kind)]);
+    }
+
+    // With --send-hz 20 states are three ticks apart, a starved tick should only guess one tick on
+    #[test]
+    fn test_extrapolation_steps_one_tick_with_send_interval() {
+        let mut world = World::new();
+        let mut world_states = WorldStates { interp_started: true, server_send_interval_ticks: 3, ..default() };
+        assert_eq!(world_states.interp_alpha(0.5), 0.5 / 3.0);
+        let ball = world.spawn(InterpolatedTransform {
+            from: Transform::from_xyz(0.0, 0.0, 1.0),
+            to: Transform::from_xyz(30.0, 0.0, 1.0),
+            prev: Some(Vec3::new(-30.0, 0.0, 1.0)),
+            next: None,
+            states_applied: 2,
+        }).id();
+
+        let mut state = SystemState::<Query<&mut InterpolatedTransform>>::new(&mut world);
+        for expected_x in [40.0, 50.0] {
+            extrapolate_while_starved(&mut world_states, &mut state.get_mut(&mut world), 1);
+            let interp = world.get::<InterpolatedTransform>(ball).unwrap();
+            assert_eq!(interp.to.translation.x - interp.from.translation.x, 10.0);
+            assert_eq!(interp.to.translation.x, expected_x);
+            assert_eq!(interp.prev, Some(Vec3::new(expected_x - 20.0, 0.0, 1.0)));
+        }
+        assert_eq!(world_states.interp_alpha(0.5), 0.5);
+    }
+
+    #[test]
+    fn test_arrival_intervals_use_receive_times() {
+        assert_eq!(ArrivalIntervals::from_arrivals(&VecDeque::new()), ArrivalIntervals::default());
+
+        // Two of these landed in the same frame, they're still 10 ms apart on the socket
+        let start = time::Instant::now();
+        let arrivals: VecDeque<_> = [0, 10, 30, 40].into_iter().map(|ms| start + time::Duration::from_millis(ms)).collect();
+        let intervals = ArrivalIntervals::from_arrivals(&arrivals);
+        assert_eq!(intervals.count, 3);
+        assert!((intervals.min_ms - 10.0).abs() < 0.01);
+        assert!((intervals.mean_ms - 40.0 / 3.0).abs() < 0.01);
+        assert!((intervals.max_ms - 20.0).abs() < 0.01);
+        assert!((intervals.jitter_ms - 4.714).abs() < 0.01);
+    }
+
+    #[test]
+    fn test_ball_prediction_toggle_respects_disabled_prediction() {
+        let args = Args::parse_from(["client", "--disable-client-prediction"]);
+        let mut world = World::new();
+        world.insert_resource(NetIdUtils {
+            net_id_to_entity_id: HashMap::new(),
+            missing_states: HashMap::new(),
+            spawned_ahead: HashSet::new(),
+            prediction_policy: PredictionPolicy::new(&args),
+            args
+        });
+        world.insert_resource(WorldStates::default());
+        let mut keys = ButtonInput::<KeyCode>::default();
+        keys.press(KeyCode::KeyB);
+        world.insert_resource(keys);
+
+        world.run_system_once(toggle_local_ball_prediction);
+        let policy = &world.resource::<NetIdUtils>().prediction_policy;
+        assert_eq!(policy.bundle_type(NetEntityKind::Ball, NetOwnership::Local), NetBundleType::Interpolated);
+    }
+
+    #[test]
+    fn test_logical_transforms_prefer_interpolation_target() {
+        let args = Args::parse_from(["client"]);
+        let mut world = World::new();
+        let rendered = Transform::from_xyz(1.0, 2.0, 0.0);
+        let target = Transform::from_xyz(5.0, 6.0, 0.0);
+        let interpolated = world.spawn((rendered, InterpolatedTransform { from: rendered, to: target, ..default() })).id();
+        let plain = world.spawn(Transform::from_xyz(-3.0, 4.0, 0.0)).id();
+        world.insert_resource(NetIdUtils {
+            net_id_to_entity_id: HashMap::from([(BALL_ID, interpolated), (PADDLE_ID, plain)]),
+            missing_states: HashMap::new(),
+            spawned_ahead: HashSet::new(),
+            prediction_policy: PredictionPolicy::new(&args),
+            args
+        });
+
+        let mut state = SystemState::<LogicalTransforms>::new(&mut world);
+        let transforms = state.get(&world);
+        assert_eq!(transforms.get(interpolated), Some(target));
+        assert_eq!(transforms.get(plain), Some(Transform::from_xyz(-3.0, 4.0, 0.0)));
+        assert_eq!(transforms.get_by_net_id(&BALL_ID), Some(target));
+        assert_eq!(transforms.position(plain), Some(Vec2::new(-3.0, 4.0)));
+        assert_eq!(transforms.get_by_net_id(&NetId(99)), None);
+    }
+}
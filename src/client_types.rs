@@ -1,12 +1,29 @@
 use std::collections::VecDeque;
+use std::str::FromStr;
 use std::time;
 use bevy::{prelude::*};
-use bevy::utils::HashMap;
+use bevy::utils::{HashMap, HashSet};
 use bevy::ecs::query::{QueryData, QueryFilter};
+use bevy::ecs::system::SystemParam;
+use clap::builder::TypedValueParser;
 use clap::Parser;
 use crate::common::*;
 
-pub const INTERP_DELAY_S: f64 = TICK_S + MIN_JITTER_S;
+pub fn interp_delay_s(jitter_padding_s: f64) -> f64 {
+    TICK_S + jitter_padding_s
+}
+// Interpolated entities keep moving along their last motion for at most this many starved ticks
+pub const MAX_EXTRAPOLATION_TICKS: u32 = 6;
+pub const DEFAULT_EXTRAPOLATION_BLEND_STATES: usize = 2;
+// 'to' plus one more, tick_simulation treats anything less as starved
+pub const MIN_BUFFERED_STATES: usize = 2;
+// How long a predicted score has to stay below what's displayed before we believe it
+pub const SCORE_CORRECTION_DELAY_S: f32 = 0.5;
+pub const COLLISION_EFFECT_S: f32 = 0.2;
+// Predicted hits already played, at least a few ticks worth of unacked inputs
+pub const MAX_PLAYED_COLLISIONS: usize = 64;
+// A ping without a pong after this long is counted as lost
+pub const PING_TIMEOUT_S: f64 = 2.0;
 
 pub struct ClientWorldState {
     pub world: NetWorldStateData,
@@ -20,6 +37,7 @@ pub struct ClientWorldState {
 pub struct BallQuery {
     pub transform: &'static mut Transform,
     pub velocity: &'static mut Velocity,
+    pub spin: &'static mut Spin,
     pub net_id: &'static NetId,
 }
 
@@ -37,6 +55,7 @@ pub struct PaddleQuery {
     pub entity: Entity,
     pub transform: &'static mut Transform,
     pub net_id: &'static NetId,
+    pub tuning: &'static PaddleTuning,
 }
 
 #[derive(QueryFilter)]
@@ -54,6 +73,7 @@ pub struct RemainingCollidersQuery {
     pub entity: Entity,
     pub transform: &'static Transform,
     pub brick: Option<&'static Brick>,
+    pub paddle: Has<Paddle>,
 }
 
 #[derive(QueryFilter)]
@@ -63,11 +83,47 @@ pub struct RemainingCollidersFilter {
     pub w2: Without<LocallyPredicted>,
 }
 
+// Where an entity logically is, as opposed to where it's drawn. Interpolated entities render
+// part way between two server states, gameplay code wants the newer one ('to'). Everything else
+// is already drawn where it logically is.
+#[derive(QueryData)]
+pub struct LogicalTransformQuery {
+    pub transform: &'static Transform,
+    pub interpolated: Option<&'static InterpolatedTransform>,
+}
+
+impl LogicalTransformQueryItem<'_> {
+    pub fn logical(&self) -> Transform {
+        self.interpolated.map_or(*self.transform, |interp| interp.to)
+    }
+}
+
+// Reads Transform, so it can't share a system with a query that writes it
+#[allow(dead_code)]
+#[derive(SystemParam)]
+pub struct LogicalTransforms<'w, 's> {
+    query: Query<'w, 's, LogicalTransformQuery>,
+    net_id_utils: Res<'w, NetIdUtils>,
+}
+
+#[allow(dead_code)]
+impl LogicalTransforms<'_, '_> {
+    pub fn get(&self, entity: Entity) -> Option<Transform> {
+        self.query.get(entity).ok().map(|item| item.logical())
+    }
+
+    pub fn get_by_net_id(&self, net_id: &NetId) -> Option<Transform> {
+        self.get(*self.net_id_utils.net_id_to_entity_id.get(net_id)?)
+    }
+
+    pub fn position(&self, entity: Entity) -> Option<Vec2> {
+        self.get(entity).map(|t| t.translation.xy())
+    }
+}
+
 pub trait LocallyPredictedEntity {
     fn transform(&self) -> &Transform;
     fn rollback_to(&mut self, ws: &ClientWorldState) -> bool;
-
-    fn simulate_forward(&mut self, input: &PlayerInputData);
 }
 
 
@@ -77,8 +133,62 @@ pub struct WorldStates {
     pub states: VecDeque<ClientWorldState>,
     pub interp_started: bool,
     pub received_per_sec: VecDeque<f32>,
+    // Receive times of the same states, for ArrivalIntervals
+    pub arrival_times: VecDeque<time::Instant>,
     pub interpolating_from: Option<u32>,
-    pub interpolating_to: Option<u32>
+    pub interpolating_to: Option<u32>,
+    // From the header of the newest server packet
+    pub server_input_state: NetInputState,
+    pub server_buffer_remaining_ms: u16,
+    pub server_input_delay_ms: u16,
+    // 0 renders purely interpolated, 1 renders a tick ahead along the from -> to motion
+    pub extrapolation_blend: f32,
+    pub extrapolated_ticks: u32,
+    // World state arrivals, feeds JitterPadding when it's adaptive
+    pub jitter: JitterEstimator,
+    // A world state split across packets that isn't all here yet
+    pub partial: Option<PartialWorldState>,
+    // Newest frame the server has sent us, kept after its state is drained from the buffer
+    pub latest_server_frame: Option<u32>,
+    // From the header, the server's --send-hz as ticks between world states
+    pub server_send_interval_ticks: u8,
+    // Latest ServerInfo, None until the first one arrives
+    pub server_info: Option<NetServerInfo>,
+    // Ticks spent interpolating between the current from and to states
+    pub ticks_into_interval: u32
+}
+
+impl WorldStates {
+    pub fn send_interval_ticks(&self) -> u32 {
+        self.server_send_interval_ticks.max(1) as u32
+    }
+
+    // How far from 'from' to 'to' rendering is, states can be several ticks apart. Extrapolated
+    // ones are always a tick apart.
+    pub fn interp_alpha(&self, overstep_fraction: f32) -> f32 {
+        if self.extrapolated_ticks > 0 {
+            return overstep_fraction;
+        }
+        (self.ticks_into_interval as f32 + overstep_fraction) / self.send_interval_ticks() as f32
+    }
+
+    // How many frames behind the newest server state we're rendering, the frame part of perceived latency
+    pub fn render_skew_frames(&self) -> Option<u32> {
+        Some(self.latest_server_frame?.saturating_sub(self.interpolating_from?))
+    }
+
+    pub fn paddle_tuning(&self) -> PaddleTuning {
+        self.server_info.map_or_else(PaddleTuning::default, |info| info.paddle)
+    }
+
+    pub fn gravity(&self) -> f32 {
+        self.server_info.map_or(0.0, |info| info.gravity)
+    }
+}
+
+pub struct PartialWorldState {
+    pub state: NetWorldStateData,
+    pub received: Vec<bool>,
 }
 
 #[derive(Resource)]
@@ -86,13 +196,245 @@ pub struct PingState {
     pub last_sent_time: f32,
     pub next_ping_id: u32,
     pub ping_id_to_instance: HashMap<u32, time::Instant>,
-    pub pongs: Vec<PingData>
+    pub pongs: Vec<PingData>,
+    pub pongs_received: u32,
+    pub pings_lost: u32,
+    pub rtt: RttWindow
+}
+
+pub const DEFAULT_RTT_WINDOW: usize = 100;
+pub const MAX_RTT_WINDOW: usize = 1000;
+
+// The last few round trips in ms, oldest dropped first
+pub struct RttWindow {
+    samples: VecDeque<f32>,
+    capacity: usize
+}
+
+impl RttWindow {
+    pub fn new(capacity: usize) -> Self {
+        let capacity = capacity.clamp(1, MAX_RTT_WINDOW);
+        Self { samples: VecDeque::with_capacity(capacity), capacity }
+    }
+
+    pub fn record(&mut self, rtt_ms: f32) {
+        if self.samples.len() == self.capacity {
+            self.samples.pop_front();
+        }
+        self.samples.push_back(rtt_ms);
+    }
+
+    pub fn len(&self) -> usize {
+        self.samples.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.samples.is_empty()
+    }
+
+    pub fn mean(&self) -> Option<f32> {
+        if self.is_empty() {
+            return None;
+        }
+        Some(self.samples.iter().sum::<f32>() / self.samples.len() as f32)
+    }
+
+    pub fn min(&self) -> Option<f32> {
+        self.samples.iter().copied().reduce(f32::min)
+    }
+
+    // Nearest rank, so it's always a sample we actually saw
+    pub fn percentile(&self, p: f32) -> Option<f32> {
+        if self.is_empty() {
+            return None;
+        }
+        let mut sorted: Vec<f32> = self.samples.iter().copied().collect();
+        sorted.sort_unstable_by(f32::total_cmp);
+        let rank = ((p * sorted.len() as f32).ceil() as usize).clamp(1, sorted.len());
+        Some(sorted[rank - 1])
+    }
+
+    pub fn p95(&self) -> Option<f32> {
+        self.percentile(0.95)
+    }
+}
+
+impl PingState {
+    // Fraction of pings that never got a pong back within PING_TIMEOUT_S
+    pub fn loss_estimate(&self) -> f32 {
+        let total = self.pongs_received + self.pings_lost;
+        if total == 0 {
+            0.0
+        } else {
+            self.pings_lost as f32 / total as f32
+        }
+    }
+}
+
+// Bricks hidden on the client, either broken by a predicted ball or already gone in the newest world state
+#[derive(Resource, Default)]
+pub struct PredictedBricks {
+    pub destroyed: Vec<Entity>,
+}
+
+// Prediction error averages restart this often so they follow current network conditions
+pub const PREDICTION_ERROR_WINDOW_S: f32 = 5.0;
+
+// How far predicted entities jump when a new world state corrects them, in px
+#[derive(Default, Clone, Copy, Debug)]
+pub struct PredictionErrorStats {
+    sum: f32,
+    samples: u32,
+    max: f32,
+    // From the last full window
+    pub mean: f32,
+    pub window_max: f32,
+    pub window_samples: u32,
+}
+
+impl PredictionErrorStats {
+    pub fn record(&mut self, error: f32) {
+        self.sum += error;
+        self.samples += 1;
+        self.max = self.max.max(error);
+    }
+
+    fn roll(&mut self) {
+        *self = PredictionErrorStats {
+            mean: if self.samples > 0 { self.sum / self.samples as f32 } else { 0.0 },
+            window_max: self.max,
+            window_samples: self.samples,
+            ..default()
+        };
+    }
+}
+
+#[derive(Resource, Default)]
+pub struct PredictionError {
+    pub paddle: PredictionErrorStats,
+    pub ball: PredictionErrorStats,
+    window_start: f32,
+}
+
+impl PredictionError {
+    pub fn record(&mut self, kind: NetEntityKind, error: f32) {
+        match kind {
+            NetEntityKind::Paddle => self.paddle.record(error),
+            NetEntityKind::Ball => self.ball.record(error),
+            // Never predicted
+            NetEntityKind::Brick => {}
+        }
+    }
+
+    pub fn roll_window_if_due(&mut self, now: f32) {
+        if now - self.window_start >= PREDICTION_ERROR_WINDOW_S {
+            self.paddle.roll();
+            self.ball.roll();
+            self.window_start = now;
+        }
+    }
+}
+
+#[derive(Component)]
+pub struct PredictionErrorUi;
+
+#[derive(Component)]
+pub struct FrameSkewUi;
+
+// Time between world state arrivals over the last second, in ms. A steady server shows min, mean
+// and max all close to its send interval, uneven sends or a bursty link spread them out.
+#[derive(Resource, Default, Clone, Copy, Debug, PartialEq)]
+pub struct ArrivalIntervals {
+    pub count: usize,
+    pub min_ms: f32,
+    pub mean_ms: f32,
+    pub max_ms: f32,
+    pub jitter_ms: f32, // Standard deviation
+}
+
+impl ArrivalIntervals {
+    // Takes socket receive times, frame times would fold however many arrived in one frame into a
+    // single frame's worth of interval
+    pub fn from_arrivals(arrivals: &VecDeque<time::Instant>) -> Self {
+        let intervals = || arrivals.iter().zip(arrivals.iter().skip(1)).map(|(p, c)| c.saturating_duration_since(*p).as_secs_f32() * 1000.0);
+        let count = arrivals.len().saturating_sub(1);
+        if count == 0 {
+            return Self::default();
+        }
+        let mean_ms = intervals().sum::<f32>() / count as f32;
+        let variance = intervals().map(|i| (i - mean_ms).powi(2)).sum::<f32>() / count as f32;
+        ArrivalIntervals {
+            count,
+            min_ms: intervals().fold(f32::INFINITY, f32::min),
+            mean_ms,
+            max_ms: intervals().fold(0.0, f32::max),
+            jitter_ms: variance.sqrt(),
+        }
+    }
+}
+
+#[derive(Resource, Default)]
+pub struct ScoreCorrection {
+    pub displayed: u32,
+    pub lower_since: Option<f32>,
+}
+
+// Every unacked input is resimulated each tick, so a server that stops acking would make that cost
+// grow forever. Past this many (2 seconds) the oldest are dropped and prediction starts from there.
+pub const MAX_UNACKED_INPUTS: usize = 2 * TICK_RATE_HZ as usize;
+
+pub struct UnAckedInput {
+    pub input: PlayerInputData,
+    pub send_time: f32,
 }
 
-// Parallel vectors
 #[derive(Resource, Default)]
 pub struct UnAckedPlayerInputs {
-    pub inputs: VecDeque<PlayerInputData>,
+    pub inputs: VecDeque<UnAckedInput>,
+    pub dropped: u32, // Since the last ack, see MAX_UNACKED_INPUTS
+}
+
+impl UnAckedPlayerInputs {
+    pub fn push(&mut self, input: PlayerInputData, send_time: f32) {
+        self.inputs.push_back(UnAckedInput { input, send_time });
+
+        if self.inputs.len() > MAX_UNACKED_INPUTS {
+            self.inputs.pop_front();
+            if self.dropped == 0 {
+                warn!("Server hasn't acked the last {} inputs, dropping the oldest", MAX_UNACKED_INPUTS);
+            }
+            self.dropped += 1;
+        }
+    }
+
+    // Sequences only go up, so everything at or before `sequence` is at the front
+    pub fn ack_through(&mut self, sequence: u32, now: f32) {
+        while self.inputs.front().is_some_and(|unacked| unacked.input.sequence <= sequence) {
+            let unacked = self.inputs.pop_front().unwrap();
+            debug!("Input {} acked after {:.1} ms", unacked.input.sequence, (now - unacked.send_time) * 1000.0);
+            if self.dropped > 0 {
+                warn!("Server acking inputs again, dropped {} unacked inputs", self.dropped);
+                self.dropped = 0;
+            }
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.inputs.clear();
+    }
+
+    pub fn len(&self) -> usize {
+        self.inputs.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.inputs.is_empty()
+    }
+
+    // Oldest first
+    pub fn iter(&self) -> impl Iterator<Item = &PlayerInputData> {
+        self.inputs.iter().map(|unacked| &unacked.input)
+    }
 }
 
 #[derive(Parser, Debug)]
@@ -107,13 +449,117 @@ pub struct Args {
     #[command(flatten)]
     pub sim_latency: SimLatencyArgs,
 
+    #[command(flatten)]
+    pub window: WindowArgs,
+
+    #[command(flatten)]
+    pub jitter: JitterArgs,
+
+    #[command(flatten)]
+    pub tick: TickArgs,
+
     #[arg(long, default_value_t = false)]
     pub disable_client_prediction: bool,
+
+    /// Interpolate these entities, e.g. --interpolate ball:local
+    #[arg(long)]
+    pub interpolate: Vec<PolicyKey>,
+
+    /// Predict these entities, e.g. --predict ball:remote
+    #[arg(long)]
+    pub predict: Vec<PolicyKey>,
+
+    /// Hide bricks as soon as a predicted ball hits them instead of waiting for the server
+    #[arg(long, default_value_t = false)]
+    pub predict_brick_destruction: bool,
+
+    /// Start blending interpolated entities toward extrapolated motion once fewer than this many
+    /// states are buffered, so running dry doesn't hitch. 0 turns extrapolation off.
+    #[arg(long, default_value_t = DEFAULT_EXTRAPOLATION_BLEND_STATES)]
+    pub extrapolation_blend_states: usize,
+
+    /// Hex dump every packet sent and received, logged at trace level
+    #[arg(long, default_value_t = false)]
+    pub log_packets: bool,
+
+    /// Never keep more than this many world states buffered, overriding the adaptive target.
+    /// Less latency, more hitching.
+    #[arg(long)]
+    pub max_buffer: Option<usize>,
+
+    /// Always keep at least this many world states buffered
+    #[arg(long)]
+    pub min_buffer: Option<usize>,
+
+    /// Show how far prediction is off from the server, averaged per PREDICTION_ERROR_WINDOW_S
+    #[arg(long, default_value_t = false)]
+    pub prediction_error_overlay: bool,
+
+    /// Despawn an entity only after it's been missing from this many world states in a row, so
+    /// lost packets don't make it blink out
+    #[arg(long, default_value_t = DEFAULT_DESPAWN_AFTER_MISSING_STATES, value_parser = clap::value_parser!(u8).range(1..))]
+    pub despawn_after_missing_states: u8,
+
+    /// How many recent pings the RTT mean, min and p95 are taken over
+    #[arg(long, default_value_t = DEFAULT_RTT_WINDOW, value_parser = clap::value_parser!(u64).range(1..=MAX_RTT_WINDOW as u64).map(|v| v as usize))]
+    pub rtt_window: usize,
+
+    /// Show how many frames behind the newest server state the client is rendering, and ping RTT
+    #[arg(long, default_value_t = false)]
+    pub frame_skew_overlay: bool,
+
+    /// Heartbeats carry a nonce and send time instead of being empty, so the server can spot stale
+    /// ones and watch one way delay grow
+    #[arg(long, default_value_t = false)]
+    pub nonce_heartbeats: bool,
+
+    /// Leave out the FPS overlay and the frame time diagnostics behind it
+    #[arg(long, default_value_t = false)]
+    pub no_perf_ui: bool,
+
+    /// Play this sound on every collision, a path under the assets folder. Silent without it.
+    #[arg(long)]
+    pub collision_sound: Option<String>,
+}
+
+impl Args {
+    pub fn predict_bricks(&self) -> bool {
+        !self.disable_client_prediction && self.predict_brick_destruction
+    }
+
+    pub fn validate_buffer_bounds(&self) -> Result<(), String> {
+        for (name, bound) in [("--min-buffer", self.min_buffer), ("--max-buffer", self.max_buffer)] {
+            if bound.is_some_and(|b| b < MIN_BUFFERED_STATES) {
+                return Err(format!("{} must be at least {}, tick_simulation starves with fewer states buffered", name, MIN_BUFFERED_STATES));
+            }
+        }
+        if let (Some(min), Some(max)) = (self.min_buffer, self.max_buffer) {
+            if min > max {
+                return Err(format!("--min-buffer ({}) can't be more than --max-buffer ({})", min, max));
+            }
+        }
+        Ok(())
+    }
+
+    // The adaptive target with --min-buffer and --max-buffer applied
+    pub fn buffer_target(&self, adaptive: usize) -> usize {
+        let target = adaptive.max(self.min_buffer.unwrap_or(MIN_BUFFERED_STATES));
+        self.max_buffer.map_or(target, |max| target.min(max))
+    }
 }
 
+// An entity has to be missing from this many world states in a row before it's despawned, so a
+// single bad state doesn't make it blink out and get respawned. --despawn-after-missing-states overrides it.
+pub const DEFAULT_DESPAWN_AFTER_MISSING_STATES: u8 = 2;
+
 #[derive(Resource)]
 pub struct NetIdUtils {
     pub net_id_to_entity_id: HashMap<NetId, Entity>,
+    // How many world states in a row each still-spawned entity has been missing from
+    pub missing_states: HashMap<NetId, u8>,
+    // Local paddles spawned from the newest world state before interpolation reached one with them
+    pub spawned_ahead: HashSet<NetId>,
+    pub prediction_policy: PredictionPolicy,
     pub args: Args
 }
 
@@ -121,11 +567,98 @@ pub struct NetIdUtils {
 pub struct InterpolatedTransform {
     pub from: Transform,
     pub to: Transform,
+    // Translations on either side of from/to, used to smooth the curve through snapshot boundaries.
+    // None when there's no state for it yet, render extrapolates from from/to instead.
+    pub prev: Option<Vec3>,
+    pub next: Option<Vec3>,
+    pub states_applied: u8,
 }
 
 #[derive(Component)]
 pub struct LocallyPredicted;
 
+// Only there when --collision-sound is passed
+#[derive(Resource)]
+pub struct CollisionSound(pub Handle<AudioSource>);
+
+// Hits reconcile already played for predicted balls. The server's copy of a hit is only skipped
+// when it matches one of these, anything prediction missed still plays when it arrives.
+#[derive(Resource, Default)]
+pub struct PredictedCollisions(pub VecDeque<CollisionEvent>);
+
+// How far off a predicted hit can be from the server's and still be the same one
+pub const PREDICTED_COLLISION_FRAME_SLACK: u32 = 3;
+
+impl PredictedCollisions {
+    pub fn record(&mut self, collision: CollisionEvent) {
+        self.0.push_back(collision);
+        while self.0.len() > MAX_PLAYED_COLLISIONS {
+            self.0.pop_front();
+        }
+    }
+
+    pub fn contains(&self, server: &CollisionEvent) -> bool {
+        self.0.iter().any(|predicted| {
+            predicted.ball == server.ball
+                && predicted.kind == server.kind
+                && predicted.frame.abs_diff(server.frame) <= PREDICTED_COLLISION_FRAME_SLACK
+                && predicted.pos.distance(server.pos) <= BALL_DIAMETER
+        })
+    }
+}
+
+#[derive(Component)]
+pub struct CollisionEffect(pub Timer);
+
+#[derive(Component)]
+pub struct SyncIndicatorUi;
+
+#[derive(Component)]
+pub struct CountdownUi;
+
+#[derive(Component)]
+pub struct LivesUi;
+
+// Which of our paddles inputs go to when the server gives us more than one, None drives all of them
+#[derive(Resource, Default)]
+pub struct PaddleSelection(pub Option<u8>);
+
+// Chaos testing, F8 toggles it. Inputs are still made and predicted but never sent, like an uplink
+// that died mid-game, while world states keep coming in.
+#[derive(Resource, Default)]
+pub struct InputStarvation {
+    pub active: bool
+}
+
+// World states that failed to decode in a row before the client asks for a resync
+pub const RESYNC_DECODE_FAILURE_STREAK: u32 = 3;
+
+// The client can't repair a desync on its own, it asks the server for a fresh world state.
+// Requests are sent at most every RESYNC_MIN_INTERVAL_S, the server enforces the same limit.
+#[derive(Resource, Default)]
+pub struct ResyncState {
+    pub requested: bool,
+    pub decode_failure_streak: u32,
+    pub last_sent_s: Option<f32>,
+    pub sent: u32
+}
+
+impl ResyncState {
+    pub fn record_decode_failure(&mut self) {
+        self.decode_failure_streak += 1;
+        if self.decode_failure_streak >= RESYNC_DECODE_FAILURE_STREAK {
+            self.requested = true;
+        }
+    }
+
+    pub fn record_decoded(&mut self) {
+        self.decode_failure_streak = 0;
+    }
+}
+
+// How long "GO!" stays up once the countdown finishes
+pub const COUNTDOWN_GO_S: f32 = 0.5;
+
 pub trait SpawNetBundleEx {
     // define a method that we will be able to call on `commands`
     fn spawn_interpolated_transform_bundle<B: Bundle>(
@@ -156,7 +689,104 @@ impl<'w, 's> SpawNetBundleEx for Commands<'w, 's> {
     }
 }
 
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
 pub enum NetBundleType {
     Predicted,
     Interpolated
 }
+
+#[derive(Clone, Copy, Hash, PartialEq, Eq, Debug)]
+pub enum NetEntityKind {
+    Paddle,
+    Brick,
+    Ball
+}
+
+// Bricks don't belong to anyone and count as remote
+#[derive(Clone, Copy, Hash, PartialEq, Eq, Debug)]
+pub enum NetOwnership {
+    Local,
+    Remote
+}
+
+// Parsed from "<paddle|brick|ball>:<local|remote>" on the command line
+#[derive(Clone, Copy, Hash, PartialEq, Eq, Debug)]
+pub struct PolicyKey {
+    pub kind: NetEntityKind,
+    pub ownership: NetOwnership
+}
+
+impl FromStr for PolicyKey {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (kind, ownership) = s.split_once(':').ok_or_else(|| format!("expected <kind>:<owner>, got {}", s))?;
+        let kind = match kind {
+            "paddle" => NetEntityKind::Paddle,
+            "brick" => NetEntityKind::Brick,
+            "ball" => NetEntityKind::Ball,
+            _ => return Err(format!("unknown entity kind {}", kind))
+        };
+        let ownership = match ownership {
+            "local" => NetOwnership::Local,
+            "remote" => NetOwnership::Remote,
+            _ => return Err(format!("unknown owner {}", ownership))
+        };
+        Ok(PolicyKey { kind, ownership })
+    }
+}
+
+// Decides which entities get predicted and which get interpolated when they're spawned
+pub struct PredictionPolicy {
+    policy: HashMap<PolicyKey, NetBundleType>
+}
+
+impl PredictionPolicy {
+    pub fn new(args: &Args) -> Self {
+        let mut policy = HashMap::new();
+        let mut set = |kind, ownership, bundle_type| {
+            policy.insert(PolicyKey { kind, ownership }, bundle_type);
+        };
+
+        // Defaults: our own paddle and every ball are predicted, everything else is interpolated
+        set(NetEntityKind::Paddle, NetOwnership::Local, NetBundleType::Predicted);
+        set(NetEntityKind::Paddle, NetOwnership::Remote, NetBundleType::Interpolated);
+        set(NetEntityKind::Brick, NetOwnership::Local, NetBundleType::Interpolated);
+        set(NetEntityKind::Brick, NetOwnership::Remote, NetBundleType::Interpolated);
+        set(NetEntityKind::Ball, NetOwnership::Local, NetBundleType::Predicted);
+        set(NetEntityKind::Ball, NetOwnership::Remote, NetBundleType::Predicted);
+
+        for key in args.interpolate.iter() {
+            policy.insert(*key, NetBundleType::Interpolated);
+        }
+
+        for key in args.predict.iter() {
+            // We only have our own inputs, a predicted remote paddle would move with our keys
+            if key.kind == NetEntityKind::Paddle && key.ownership == NetOwnership::Remote {
+                warn!("Remote paddles can't be predicted, ignoring --predict paddle:remote");
+                continue;
+            }
+            policy.insert(*key, NetBundleType::Predicted);
+        }
+
+        if args.disable_client_prediction {
+            for bundle_type in policy.values_mut() {
+                *bundle_type = NetBundleType::Interpolated;
+            }
+        }
+
+        PredictionPolicy { policy }
+    }
+
+    // Only affects entities spawned after this, the caller has to switch existing ones over
+    pub fn set(&mut self, kind: NetEntityKind, ownership: NetOwnership, bundle_type: NetBundleType) {
+        self.policy.insert(PolicyKey { kind, ownership }, bundle_type);
+    }
+
+    pub fn bundle_type(&self, kind: NetEntityKind, ownership: NetOwnership) -> NetBundleType {
+        self.policy
+            .get(&PolicyKey { kind, ownership })
+            .copied()
+            .unwrap_or(NetBundleType::Interpolated)
+    }
+}
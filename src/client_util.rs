@@ -1,11 +1,41 @@
+use std::net::{SocketAddr, ToSocketAddrs};
 use bevy::{prelude::*};
 use bevy::utils::HashMap;
 use crate::common::*;
 use crate::client_types::*;
 
-pub fn apply_velocity(delta_secs: f32, transform: &mut Transform, velocity: &Velocity) {
-    transform.translation.x += velocity.x * delta_secs;
-    transform.translation.y += velocity.y * delta_secs;
+// Takes IPv6 literals with or without brackets (::1 or [::1]) as well as IPv4 and hostnames
+pub fn resolve_server_addr(ip: &str, port: u16) -> SocketAddr {
+    let host = ip.trim_start_matches('[').trim_end_matches(']');
+    (host, port)
+        .to_socket_addrs()
+        .expect("could not resolve server address")
+        .next()
+        .expect("server address resolved to nothing")
+}
+
+// Client only timing invariants, on top of common::check_shared_timing
+pub fn check_client_timing(padding: &JitterPadding, args: &Args) -> Vec<TimingIssue> {
+    let mut issues = check_shared_timing(padding);
+    let interp_delay_ms = interp_delay_s(padding.configured_s) * 1000.0;
+    let unacked_window_ms = MAX_UNACKED_INPUTS as f64 * TICK_S * 1000.0;
+    if interp_delay_ms >= unacked_window_ms {
+        issues.push(TimingIssue::Error(format!(
+            "interp delay ({:.1} ms) must be well under the {:.0} ms of unacked inputs we keep, or inputs are dropped before the server can ack them",
+            interp_delay_ms, unacked_window_ms
+        )));
+    }
+
+    if let Some(max_buffer) = args.max_buffer {
+        let max_buffer_ms = max_buffer as f64 * TICK_S * 1000.0;
+        if max_buffer_ms < interp_delay_ms {
+            issues.push(TimingIssue::Warning(format!(
+                "--max-buffer {} ({:.1} ms of states) is under the interp delay ({:.1} ms), expect to run dry and extrapolate on any late packet",
+                max_buffer, max_buffer_ms, interp_delay_ms
+            )));
+        }
+    }
+    issues
 }
 
 pub fn rollback_all<T: LocallyPredictedEntity>(entities: impl Iterator<Item = T>, ws: &ClientWorldState) -> Vec<Transform> {
@@ -17,12 +47,220 @@ pub fn rollback_all<T: LocallyPredictedEntity>(entities: impl Iterator<Item = T>
     original_transforms
 }
 
-pub fn resimulate_all<T: LocallyPredictedEntity>(entities: impl Iterator<Item = T>, input: &PlayerInputData) {
-    for mut e in entities {
-        e.simulate_forward(input);
+// Sorted by NetId so index i lines up with the server's paddle i for this player
+pub fn resimulate_paddles<'w>(paddles: impl Iterator<Item = PaddleQueryItem<'w>>, input: &PlayerInputData) {
+    let mut paddles: Vec<_> = paddles.collect();
+    paddles.sort_unstable_by_key(|p| *p.net_id);
+    for (i, p) in paddles.iter_mut().enumerate() {
+        if input.controls_paddle(i) {
+            p.simulate_forward(input);
+        }
+    }
+}
+
+// Local paddles in ws, placed where reconcile will have predicted them up to this tick: their
+// authoritative spot with every unacked input replayed except the newest, which is this tick's
+pub fn local_paddle_spawns<'a, 'i>(
+    ws: &'a ClientWorldState,
+    inputs: impl Iterator<Item = &'i PlayerInputData>,
+    tuning: &PaddleTuning
+) -> Vec<(NetId, &'a NetPaddleData, Vec2)> {
+    let mut paddles: Vec<(NetId, &NetPaddleData, Transform)> = ws.world.entities.iter().filter_map(|e| match &e.entity_type {
+        NetEntityType::Paddle(d) if d.player_index.0 == ws.local_client_index => {
+            Some((e.net_id, d, Transform::from_translation(d.pos.extend(0.0))))
+        }
+        _ => None
+    }).collect();
+    // Same order resimulate_paddles picks controlled paddles in
+    paddles.sort_unstable_by_key(|(net_id, _, _)| *net_id);
+
+    let unacked: Vec<_> = inputs.filter(|input| input.sequence > ws.last_applied_input).collect();
+    for input in unacked.iter().take(unacked.len().saturating_sub(1)) {
+        for (i, (_, _, transform)) in paddles.iter_mut().enumerate() {
+            if input.controls_paddle(i) {
+                move_paddle(transform, input, tuning);
+            }
+        }
+    }
+
+    paddles.into_iter().map(|(net_id, d, transform)| (net_id, d, transform.translation.xy())).collect()
+}
+
+// The server frame our first unacked input with Serve pressed is applied on, if there is one
+pub fn served_frame<'i>(ws: &ClientWorldState, inputs: impl Iterator<Item = &'i PlayerInputData>) -> Option<u32> {
+    inputs.enumerate().find(|(_, input)| input.pressed(NetKey::Serve)).map(|(i, _)| ws.world.frame + i as u32 + 1)
+}
+
+// The launch frame the server stamped on a ball, or sooner if one of our inputs serves first. The
+// server launches on the tick it applies a Serve, cutting the countdown short for everyone or
+// letting go of our own ball waiting on its paddle during play.
+fn launch_frame(ws: &ClientWorldState, d: &NetBallData, served_frame: Option<u32>) -> Option<u32> {
+    let Some(served) = served_frame else {
+        return d.launch_frame;
+    };
+    match ws.world.phase {
+        MatchPhase::Countdown(_) => Some(d.launch_frame.map_or(served, |f| f.min(served))),
+        MatchPhase::Playing if d.launch_frame.is_none() && d.player_index.0 == ws.local_client_index => Some(served),
+        _ => d.launch_frame
+    }
+}
+
+// Held balls sit still until their launch frame
+pub fn ball_launched(ws: &ClientWorldState, net_id: &NetId, server_frame: u32, served_frame: Option<u32>) -> bool {
+    match ws.get_by_net_id(net_id) {
+        Some(NetEntity { entity_type: NetEntityType::Ball(d), .. }) => {
+            launch_frame(ws, d, served_frame).is_some_and(|f| server_frame >= f)
+        }
+        _ => true
+    }
+}
+
+// The paddle a ball rides on during server_frame, see NetBallData::held_by
+pub fn ball_held_by(ws: &ClientWorldState, net_id: &NetId, server_frame: u32, served_frame: Option<u32>) -> Option<NetId> {
+    match ws.get_by_net_id(net_id) {
+        Some(NetEntity { entity_type: NetEntityType::Ball(d), .. }) => {
+            d.held_by.filter(|_| !launch_frame(ws, d, served_frame).is_some_and(|f| server_frame > f))
+        }
+        _ => None
+    }
+}
+
+// Mirrors the server's carry_held_balls, call after the paddles are resimulated for server_frame.
+// Only our own paddles move during resimulation, a ball held by anyone else's stays where the
+// world state put it.
+pub fn carry_held_balls<'w>(
+    balls: impl Iterator<Item = BallQueryItem<'w>>,
+    paddles: &Query<PaddleQuery, PaddleFilter>,
+    ws: &ClientWorldState,
+    server_frame: u32,
+    served_frame: Option<u32>
+) {
+    for mut b in balls {
+        let Some(paddle_id) = ball_held_by(ws, b.net_id, server_frame, served_frame) else {
+            continue;
+        };
+        if let Some(p) = paddles.iter().find(|p| *p.net_id == paddle_id) {
+            let pos = held_ball_position(p.transform.translation.xy(), p.tuning);
+            b.transform.translation = Vec3::from((pos, b.transform.translation.z));
+        }
+    }
+}
+
+pub fn resimulate_balls<'w>(balls: impl Iterator<Item = BallQueryItem<'w>>, gravity: f32, ws: &ClientWorldState, server_frame: u32, served_frame: Option<u32>) {
+    for mut b in balls {
+        if ball_launched(ws, b.net_id, server_frame, served_frame) {
+            b.simulate_forward(gravity);
+        }
+    }
+}
+
+// Collects the parts of a split world state, returns it once every part is in. Parts of a newer
+// frame replace an unfinished older one, which is then never going to be applied anyway.
+pub fn assemble_world_state(partial: &mut Option<PartialWorldState>, ws: NetWorldStateData) -> Option<NetWorldStateData> {
+    if ws.part_count <= 1 {
+        return Some(ws);
+    }
+    if ws.part >= ws.part_count {
+        warn!("World state part {} of {} for frame {}, ignoring", ws.part, ws.part_count, ws.frame);
+        return None;
+    }
+
+    match partial {
+        Some(p) if p.state.frame == ws.frame => {}
+        Some(p) if p.state.frame > ws.frame => return None,
+        _ => {
+            if let Some(p) = partial.as_ref() {
+                debug!("Dropping incomplete world state for frame {}", p.state.frame);
+            }
+            *partial = Some(PartialWorldState {
+                state: NetWorldStateData { frame: ws.frame, paused: ws.paused, phase: ws.phase, ..default() },
+                received: vec![false; ws.part_count as usize],
+            });
+        }
+    }
+
+    let p = partial.as_mut().unwrap();
+    let Some(received) = p.received.get_mut(ws.part as usize) else {
+        warn!("World state part count changed within frame {}, ignoring", ws.frame);
+        return None;
+    };
+    if *received {
+        return None;
+    }
+    *received = true;
+    p.state.entities.extend(ws.entities);
+    p.state.collisions.extend(ws.collisions);
+    p.state.serve = p.state.serve.or(ws.serve);
+    if ws.part == 0 {
+        p.state.custom = ws.custom;
+    }
+
+    if p.received.iter().all(|&r| r) {
+        partial.take().map(|p| p.state)
+    } else {
+        None
     }
 }
 
+// Balls waiting to launch should hold exactly the serve the shared serve_velocity works out from the
+// seed. If they don't, client and server disagree about the sim and serve prediction can't be trusted,
+// so every mismatch is logged.
+pub fn check_serves(ws: &NetWorldStateData) {
+    let Some(serve) = &ws.serve else {
+        return;
+    };
+    for net_ent in ws.entities.iter() {
+        if let NetEntityType::Ball(ball) = &net_ent.entity_type {
+            let expected = serve_velocity(serve, net_ent.net_id);
+            if ball.velocity != expected {
+                warn!(
+                    "Frame {}: ball {:?} serve {:?} doesn't match {:?} from seed {} round {}",
+                    ws.frame, net_ent.net_id.0, ball.velocity, expected, serve.seed, serve.round
+                );
+            }
+        }
+    }
+}
+
+// The header already carries the send interval, this just gets it right before the first world state.
+// Paddle tuning comes from here. Tick rate and arena are compiled in on both sides, all we can do about a mismatch is say so.
+pub fn apply_server_info(world_states: &mut WorldStates, info: NetServerInfo) {
+    if world_states.server_info == Some(info) {
+        return;
+    }
+    if let Err(e) = info.paddle.validate().and(validate_gravity(info.gravity)) {
+        error!("Ignoring ServerInfo: {}", e);
+        return;
+    }
+    if info.lives > 0 {
+        info!("Survival mode, {} lives each", info.lives);
+    }
+    if info.gravity != 0.0 {
+        info!("Balls fall at {} px/s^2", info.gravity);
+    }
+    info!(
+        "Server ticks at {} Hz, sends every {} ticks, {} paddles per player ({} px wide, {} px/s), {}",
+        info.tick_hz, info.send_interval_ticks, info.paddles_per_player, info.paddle.size.x, info.paddle.speed,
+        if info.teams == 0 { "free-for-all".to_string() } else { format!("{} teams", info.teams) }
+    );
+    if info.tick_hz != TICK_RATE_HZ as u16 {
+        error!("Server ticks at {} Hz but this client at {} Hz, prediction and interpolation will be off", info.tick_hz, TICK_RATE_HZ);
+    }
+    if info.arena_size != arena_size() {
+        error!("Server arena is {} but this client's is {}, collisions will mispredict", info.arena_size, arena_size());
+    }
+    world_states.server_send_interval_ticks = info.send_interval_ticks;
+    world_states.server_info = Some(info);
+}
+
+// None once the local player has been eliminated and their paddles are gone
+pub fn local_lives(ws: &ClientWorldState) -> Option<u8> {
+    ws.world.entities.iter().find_map(|e| match &e.entity_type {
+        NetEntityType::Paddle(d) if d.player_index.0 == ws.local_client_index => d.lives,
+        _ => None
+    })
+}
+
 pub fn spawn_net_bundle<B: Bundle>(commands: &mut Commands, bundle: B, net_type: NetBundleType) -> Entity {
     match net_type {
         NetBundleType::Predicted => {
@@ -34,22 +272,22 @@ pub fn spawn_net_bundle<B: Bundle>(commands: &mut Commands, bundle: B, net_type:
     }
 }
 
-pub fn detect_mispredicts(
+// Calls `f` with each predicted entity's transform from before reconcile and the one it's just
+// been resimulated to from the newest world state. Call once every unacked input but the newest
+// has been replayed, any difference is then how far that prediction was off.
+pub fn compare_predictions(
     ball_query: &Query<BallQuery, BallFilter>,
     local_paddle_query: &Query<PaddleQuery, PaddleFilter>,
-    original_paddle_transforms: &Vec<Transform>,
-    original_ball_transforms: &Vec<Transform>
+    original_paddle_transforms: &[Transform],
+    original_ball_transforms: &[Transform],
+    mut f: impl FnMut(NetEntityKind, &Transform, &Transform)
 ) {
-    for (i, p) in local_paddle_query.iter().enumerate() {
-        if *p.transform != original_paddle_transforms[i] {
-            info!("PADDLE MISPREDICT (orginally {:?} now {:?}", original_paddle_transforms[i].translation, p.transform.translation);
-        }
+    for (p, original) in local_paddle_query.iter().zip(original_paddle_transforms) {
+        f(NetEntityKind::Paddle, original, &p.transform);
     }
 
-    for (i, b) in ball_query.iter().enumerate() {
-        if *b.transform != original_ball_transforms[i] {
-            info!("BALL MISPREDICT (orginally {:?} now {:?}", original_ball_transforms[i].translation, b.transform.translation);
-        }
+    for (b, original) in ball_query.iter().zip(original_ball_transforms) {
+        f(NetEntityKind::Ball, original, &b.transform);
     }
 }
 
@@ -58,25 +296,17 @@ pub fn sync_net_ids_and_update_score(
     ws: &ClientWorldState,
     net_id_query: &Query<(Entity, &NetId)>,
     net_id_util: &mut ResMut<NetIdUtils>,
-    meshes: &mut Assets<Mesh>,
+    ball_assets: &mut BallAssets,
     score: &mut Score,
-    materials: &mut Assets<ColorMaterial>
+    paddle_tuning: PaddleTuning
 ) {
     let mut ws_net_ids: Vec<NetId> = Vec::with_capacity(ws.world.entities.len());
 
-    let paddle_bt = |player_index: NetPlayerIndex, args: &Args| {
-        if args.disable_client_prediction == false && player_index.0 == ws.local_client_index {
-            NetBundleType::Predicted
+    let ownership = |player_index: NetPlayerIndex| {
+        if player_index.0 == ws.local_client_index {
+            NetOwnership::Local
         } else {
-            NetBundleType::Interpolated
-        }
-    };
-
-    let ball_bt = |args: &Args| {
-        if args.disable_client_prediction == false {
-            NetBundleType::Predicted
-        } else {
-            NetBundleType::Interpolated
+            NetOwnership::Remote
         }
     };
 
@@ -86,20 +316,26 @@ pub fn sync_net_ids_and_update_score(
         if !net_id_util.net_id_to_entity_id.contains_key(&net_ent.net_id) {
             let entity_id = match &net_ent.entity_type {
                 NetEntityType::Paddle(d) => {
-                    let bundle = PaddleBundle::new(d.pos, net_ent.net_id, d.player_index);
-                    Some(spawn_net_bundle(commands, bundle, paddle_bt(d.player_index, &net_id_util.args)))
+                    let bundle = PaddleBundle::new(d.pos, net_ent.net_id, d.player_index, d.color, paddle_tuning);
+                    let bundle_type = net_id_util.prediction_policy.bundle_type(NetEntityKind::Paddle, ownership(d.player_index));
+                    Some(spawn_net_bundle(commands, bundle, bundle_type))
                 }
                 NetEntityType::Brick(d) => {
-                    let bundle = BrickBundle::new(d.pos, net_ent.net_id);
-                    Some(spawn_net_bundle(commands, bundle, NetBundleType::Interpolated))
+                    let bundle = BrickBundle::new(d.pos, net_ent.net_id, d.value);
+                    let bundle_type = net_id_util.prediction_policy.bundle_type(NetEntityKind::Brick, NetOwnership::Remote);
+                    Some(spawn_net_bundle(commands, bundle, bundle_type))
                 }
                 NetEntityType::Ball(d) => {
-                    let bundle = BallBundle::new(meshes, materials, d.pos, net_ent.net_id, d.player_index);
-                    Some(spawn_net_bundle(commands, bundle, ball_bt(&net_id_util.args)))
+                    let bundle = BallBundle::new(ball_assets, d.pos, d.velocity, net_ent.net_id, d.player_index, d.color, d.spin);
+                    let bundle_type = net_id_util.prediction_policy.bundle_type(NetEntityKind::Ball, ownership(d.player_index));
+                    Some(spawn_net_bundle(commands, bundle, bundle_type))
                 }
                 NetEntityType::Score(d) => {
                     // Feels gross to do this here, TODO: find a better spot
-                    score.0 = d.score;
+                    // When predicting bricks, reconcile owns the score instead
+                    if !net_id_util.args.predict_bricks() {
+                        score.0 = d.score;
+                    }
                     None
                 }
             };
@@ -110,11 +346,27 @@ pub fn sync_net_ids_and_update_score(
         }
     }
 
-    // Second, remove entities that don't exist in this world state
+    // Second, remove entities that have been missing for long enough. Query order isn't stable,
+    // so go by NetId to make removals come out the same every time.
+    let mut missing: Vec<(NetId, Entity)> = Vec::new();
     for (entity, net_id) in net_id_query.iter() {
-        if !ws_net_ids.contains(net_id) {
+        if ws_net_ids.contains(net_id) {
+            net_id_util.missing_states.remove(net_id);
+            net_id_util.spawned_ahead.remove(net_id);
+        } else if !net_id_util.spawned_ahead.contains(net_id) {
+            missing.push((*net_id, entity));
+        }
+    }
+    missing.sort_unstable_by_key(|(net_id, _)| *net_id);
+
+    let despawn_after = net_id_util.args.despawn_after_missing_states;
+    for (net_id, entity) in missing {
+        let count = net_id_util.missing_states.entry(net_id).or_insert(0);
+        *count += 1;
+        if *count >= despawn_after {
             commands.entity(entity).despawn();
-            net_id_util.net_id_to_entity_id.remove(net_id);
+            net_id_util.net_id_to_entity_id.remove(&net_id);
+            net_id_util.missing_states.remove(&net_id);
         }
     }
 }
@@ -127,6 +379,10 @@ pub fn apply_world_state(
     for net_ent in to_state.world.entities.iter() {
         if let Some(entity) = net_id_map.net_id_to_entity_id.get(&net_ent.net_id) {
             if let Ok(mut interp_transform) = query.get_mut(*entity) {
+                // from only holds a real snapshot once two have been applied
+                interp_transform.prev = (interp_transform.states_applied >= 2).then_some(interp_transform.from.translation);
+                interp_transform.states_applied = interp_transform.states_applied.saturating_add(1);
+                interp_transform.next = None;
                 interp_transform.from = interp_transform.to;
                 if let Some(pos) = net_ent.pos() {
                     interp_transform.to.translation = pos;
@@ -136,17 +392,85 @@ pub fn apply_world_state(
     }
 }
 
+// Call after apply_world_state with the state after to_state, if it's already buffered
+pub fn apply_next_world_state(
+    query: &mut Query<&mut InterpolatedTransform>,
+    net_id_map: &ResMut<NetIdUtils>,
+    next_state: &ClientWorldState
+) {
+    for net_ent in next_state.world.entities.iter() {
+        if let Some(entity) = net_id_map.net_id_to_entity_id.get(&net_ent.net_id) {
+            if let Ok(mut interp_transform) = query.get_mut(*entity) {
+                interp_transform.next = net_ent.pos();
+            }
+        }
+    }
+}
+
+// Carries interpolated entities one more tick along their last motion when there's no state to move to.
+// Keeps turning by however much the last two steps turned, so spinning balls keep curving.
+// ticks_per_step is how many ticks from -> to covers, every step it adds is one tick.
+pub fn extrapolate_interpolated(query: &mut Query<&mut InterpolatedTransform>, ticks_per_step: u32) {
+    let ticks_per_step = ticks_per_step.max(1) as f32;
+    for mut interp_transform in query.iter_mut() {
+        let last_step = (interp_transform.to.translation - interp_transform.from.translation) / ticks_per_step;
+        let mut step = last_step;
+        if let Some(prev) = interp_transform.prev {
+            let prev_step = (interp_transform.from.translation - prev).xy();
+            if prev_step != Vec2::ZERO && step.xy() != Vec2::ZERO {
+                let turn = Vec2::from_angle(prev_step.angle_between(step.xy()) / ticks_per_step);
+                step = turn.rotate(step.xy()).extend(step.z);
+            }
+        }
+        // Keep prev a tick behind from like the new step, or the curve through from overshoots
+        interp_transform.prev = Some(interp_transform.to.translation - last_step);
+        interp_transform.from = interp_transform.to;
+        interp_transform.to.translation += step;
+        interp_transform.next = None;
+    }
+}
+
+// Stops interpolated entities where the fully extrapolated render last left them
+pub fn hold_interpolated(query: &mut Query<&mut InterpolatedTransform>) {
+    for mut interp_transform in query.iter_mut() {
+        let step = interp_transform.to.translation - interp_transform.from.translation;
+        interp_transform.to.translation += step;
+        interp_transform.from = interp_transform.to;
+        interp_transform.prev = None;
+        interp_transform.next = None;
+    }
+}
+
+// How far to lean toward extrapolated motion given how many states are buffered past 'to'
+pub fn extrapolation_blend(buffered_ahead: usize, blend_states: usize) -> f32 {
+    if blend_states == 0 {
+        return 0.0;
+    }
+    (1.0 - buffered_ahead as f32 / blend_states as f32).clamp(0.0, 1.0)
+}
+
+// Uniform Catmull-Rom between p1 and p2. With p0 and p3 extrapolated in line with p1 and p2 this is a
+// plain lerp, so missing neighbours fall back to the old behaviour.
+pub fn catmull_rom(p0: Vec3, p1: Vec3, p2: Vec3, p3: Vec3, t: f32) -> Vec3 {
+    let t2 = t * t;
+    let t3 = t2 * t;
+    0.5 * ((2.0 * p1) +
+        (p2 - p0) * t +
+        (2.0 * p0 - 5.0 * p1 + 4.0 * p2 - p3) * t2 +
+        (3.0 * p1 - p0 - 3.0 * p2 + p3) * t3)
+}
+
 pub fn update_map_and_apply_world_state(
     commands: &mut Commands,
     query: &mut Query<&mut InterpolatedTransform>,
     net_id_query: &Query<(Entity, &NetId)>,
     net_id_map: &mut ResMut<NetIdUtils>,
-    meshes: &mut ResMut<Assets<Mesh>>,
-    materials: &mut ResMut<Assets<ColorMaterial>>,
+    ball_assets: &mut BallAssets,
     score: &mut ResMut<Score>,
+    paddle_tuning: PaddleTuning,
     to_state: &ClientWorldState
 ) {
-    sync_net_ids_and_update_score(commands, to_state, net_id_query, net_id_map, meshes, score, materials);
+    sync_net_ids_and_update_score(commands, to_state, net_id_query, net_id_map, ball_assets, score, paddle_tuning);
     apply_world_state(query, net_id_map, to_state);
 }
 
@@ -161,6 +485,7 @@ impl<'w> LocallyPredictedEntity for BallQueryItem<'w> {
                 NetEntityType::Ball(d) => {
                     self.transform.translation = Vec3::from((d.pos, 1.0));
                     *self.velocity = Velocity(d.velocity);
+                    *self.spin = Spin(d.spin);
                     true
                 },
                 _ => panic!("Unexpected entity type")
@@ -170,12 +495,15 @@ impl<'w> LocallyPredictedEntity for BallQueryItem<'w> {
         }
     }
 
-    fn simulate_forward(&mut self, _input: &PlayerInputData) {
-        apply_velocity(
-            TICK_S as f32,
-            &mut self.transform,
-            &self.velocity
-        );
+}
+
+impl<'w> BallQueryItem<'w> {
+    // Balls don't take input, but do need the server's gravity
+    pub fn simulate_forward(&mut self, gravity: f32) {
+        // Same order as the server's apply_spin_system, apply_gravity_system then apply_velocity_system
+        apply_spin(&mut self.velocity, &mut self.spin);
+        apply_gravity(&mut self.velocity, gravity);
+        apply_velocity(&mut self.transform, &self.velocity);
     }
 }
 
@@ -196,9 +524,11 @@ impl<'w> LocallyPredictedEntity for PaddleQueryItem<'w> {
             false
         }
     }
+}
 
-    fn simulate_forward(&mut self, input: &PlayerInputData) {
-        move_paddle(TICK_S as f32, &mut self.transform, input);
+impl<'w> PaddleQueryItem<'w> {
+    pub fn simulate_forward(&mut self, input: &PlayerInputData) {
+        move_paddle(&mut self.transform, input, self.tuning);
     }
 }
 
@@ -224,4 +554,11 @@ impl ClientWorldState {
             None
         }
     }
+
+    pub fn score(&self) -> Option<u32> {
+        match self.get_by_net_id(&NetId(0)) {
+            Some(NetEntity { entity_type: NetEntityType::Score(d), .. }) => Some(d.score),
+            _ => None
+        }
+    }
 }
\ No newline at end of file
@@ -1,19 +1,58 @@
+use std::collections::VecDeque;
 use std::time;
+use std::path::PathBuf;
+use bevy::utils::tracing::Span;
 use bevy::{
+    ecs::system::SystemParam,
     math::bounding::{Aabb2d, BoundingCircle, BoundingVolume, IntersectsVolume},
     prelude::*,
     sprite::MaterialMesh2dBundle,
+    utils::HashMap,
 };
 use serde::Serialize;
 use serde::Deserialize;
+use serde::de::DeserializeOwned;
+use clap::builder::TypedValueParser;
 use clap::Args;
+use smallvec::SmallVec;
+use rand::{Rng, SeedableRng};
+use rand_chacha::ChaCha8Rng;
+use bincode::config;
+use bincode::error::DecodeError;
+use byteorder::ByteOrder;
 use crate::networking;
+#[cfg(feature = "fixed_point_sim")]
+use crate::fixed_point;
+
+// Every encode and decode goes through this so client and server always agree. Build with the
+// fixint_encoding feature to compare fixed size ints against the default varints.
+#[cfg(not(feature = "fixint_encoding"))]
+pub const NET_CONFIG: config::Configuration<config::LittleEndian, config::Varint> = config::standard();
+#[cfg(feature = "fixint_encoding")]
+pub const NET_CONFIG: config::Configuration<config::LittleEndian, config::Fixint> = config::standard().with_fixed_int_encoding();
 
 pub const WORLD_PACKET_HEADER_TAG: u32 = 0xba11ba11;
-pub const HEADER_LEN: usize = size_of::<u32>() * 2 + size_of::<u8>();
+// Per-client header in front of every server packet:
+// tag u32, last applied input u32, player index u8, input state u8, input buffering remaining ms u16,
+// smoothed delay between the server receiving an input and applying it in ms u16, ticks between
+// world states u8
+pub const HEADER_LAST_APPLIED_INPUT_OFFSET: usize = size_of::<u32>();
+pub const HEADER_PLAYER_INDEX_OFFSET: usize = size_of::<u32>() * 2;
+pub const HEADER_INPUT_STATE_OFFSET: usize = HEADER_PLAYER_INDEX_OFFSET + size_of::<u8>();
+pub const HEADER_BUFFER_REMAINING_OFFSET: usize = HEADER_INPUT_STATE_OFFSET + size_of::<u8>();
+pub const HEADER_INPUT_DELAY_OFFSET: usize = HEADER_BUFFER_REMAINING_OFFSET + size_of::<u16>();
+pub const HEADER_SEND_INTERVAL_OFFSET: usize = HEADER_INPUT_DELAY_OFFSET + size_of::<u16>();
+pub const HEADER_LEN: usize = HEADER_SEND_INTERVAL_OFFSET + size_of::<u8>();
 pub const TICK_RATE_HZ: f64 = 60.0;
 pub const TICK_S: f64 = 1.0 / TICK_RATE_HZ;
+// Default padding on top of the tick interval for both the client interp buffer and the server input buffer
 pub const MIN_JITTER_S: f64 = (1.0 / 1000.0) * 6.0;
+// Adaptive padding covers this many times the measured jitter, up to MAX_JITTER_PADDING_S
+pub const ADAPTIVE_JITTER_MULTIPLIER: f64 = 3.0;
+pub const MAX_JITTER_PADDING_S: f64 = 0.1;
+// Nothing we receive fits in more than one datagram, so no decode should ever need more than this.
+// Strings or other variable length fields claiming more are rejected instead of bloating the server.
+pub const MAX_DECODE_BYTES: usize = networking::ETHERNET_MTU;
 
 // These constants are defined in `Transform` units.
 // Using the default 2D camera they correspond 1:1 with screen pixels.
@@ -21,7 +60,11 @@ pub const PADDLE_SIZE: Vec2 = Vec2::new(120.0, 20.0);
 
 pub const BALL_DIAMETER: f32 = 30.;
 pub const BALL_SPEED: f32 = 400.0;
-pub const INITIAL_BALL_DIRECTION: Vec2 = Vec2::new(0.5, -0.5);
+pub const SPIN_DECAY_PER_TICK: f32 = 0.98;
+// Radians per second, anything slower snaps to no spin
+pub const MIN_SPIN: f32 = 0.01;
+// Radians per second a ball picks up off the very edge of a paddle, less the closer to the middle it hits
+pub const PADDLE_EDGE_SPIN: f32 = 0.6;
 
 pub const WALL_THICKNESS: f32 = 10.0;
 // x coordinates
@@ -49,7 +92,15 @@ pub const NUM_COLORS: usize = 7;
 pub const COLORS: [Color; NUM_COLORS] = [RED, GREEN, BLUE, PURPLE, YELLOW, CYAN, VIOLET];
 
 pub const BRICK_COLOR: Color = Color::srgb(0.5, 0.5, 1.0);
+// Indexed by brick value - 1, anything past the end uses the last one
+pub const BRICK_VALUE_COLORS: [Color; 4] = [
+    BRICK_COLOR,
+    Color::srgb(0.4, 0.8, 0.9),
+    Color::srgb(0.9, 0.8, 0.3),
+    Color::srgb(1.0, 0.4, 0.3),
+];
 pub const WALL_COLOR: Color = Color::srgb(0.8, 0.8, 0.8);
+pub const NEUTRAL_BALL_COLOR: Color = Color::srgb(0.95, 0.95, 0.95);
 pub const TEXT_COLOR: Color = Color::srgb(0.5, 0.5, 1.0);
 pub const SCORE_COLOR: Color = Color::srgb(1.0, 0.5, 0.5);
 
@@ -62,14 +113,41 @@ pub struct Ball;
 #[derive(Component, Deref, DerefMut)]
 pub struct Velocity(pub Vec2);
 
+// Radians per second the ball's velocity turns, curving its path. Decays every tick.
+#[derive(Component, Deref, DerefMut, Default, Clone, Copy)]
+pub struct Spin(pub f32);
+
 #[derive(Component)]
 pub struct Collider;
 
-#[derive(Event, Default)]
-pub struct CollisionEvent;
+#[derive(Deserialize, Serialize, Clone, Copy, PartialEq, Eq, Debug)]
+pub enum CollisionKind {
+    Brick,
+    Bounce // Walls and paddles
+}
+
+// Server collisions ride along in world states so clients can place effects exactly
+#[derive(Event, Deserialize, Serialize, Clone, Copy)]
+pub struct CollisionEvent {
+    pub frame: u32,
+    pub ball: NetId,
+    pub pos: Vec2,
+    pub kind: CollisionKind
+}
+
+pub const MAX_COLLISIONS_PER_PACKET: usize = 8;
+// Each collision is repeated in world states for this many frames so one lost packet doesn't lose it
+pub const COLLISION_RESEND_FRAMES: u32 = 6;
 
+// value is what breaking it adds to the score
 #[derive(Component, Clone, Copy)]
-pub struct Brick;
+pub struct Brick {
+    pub value: u8
+}
+
+pub fn brick_color(value: u8) -> Color {
+    BRICK_VALUE_COLORS[(value.max(1) as usize - 1).min(BRICK_VALUE_COLORS.len() - 1)]
+}
 
 // This bundle is a collection of the components that define a "wall" in our game
 #[derive(Bundle)]
@@ -156,13 +234,49 @@ pub struct ScoreboardUi;
 pub enum NetKey {
     Left,
     Right,
+    // Starts the countdown without waiting for more players, cuts it short once it's running and
+    // during play launches a ball waiting on the player's paddle. Clients predict the launch of
+    // their own ball in reconcile, everything else they pick up from world states.
+    Serve,
+}
+
+// Where the server is with a client's inputs. Sent in the header so the client can tell the
+// player their inputs are being held while the server fills its buffer.
+#[repr(u8)]
+#[derive(Clone, Copy, Default, PartialEq, Eq, Debug)]
+pub enum NetInputState {
+    #[default]
+    Buffering,
+    Playing
+}
+
+impl NetInputState {
+    pub fn from_u8(value: u8) -> Option<Self> {
+        match value {
+            0 => Some(NetInputState::Buffering),
+            1 => Some(NetInputState::Playing),
+            _ => None
+        }
+    }
 }
 
 #[derive(Deserialize, Serialize, Default, Clone)]
 pub struct PlayerInputData {
     pub key_mask: u8,
     pub simulating_frame: u32,
-    pub sequence: u32
+    pub sequence: u32,
+    pub paddle: Option<u8> // Which of the player's paddles this moves, None moves all of them
+}
+
+impl PlayerInputData {
+    // Paddles are indexed in the order the server spawned them, which is also NetId order
+    pub fn controls_paddle(&self, paddle_index: usize) -> bool {
+        self.paddle.map_or(true, |p| p as usize == paddle_index)
+    }
+
+    pub fn pressed(&self, key: NetKey) -> bool {
+        self.key_mask & (1 << key as u8) != 0
+    }
 }
 
 #[derive(Deserialize, Serialize, Default, Clone)]
@@ -173,33 +287,56 @@ pub struct PingData {
 #[derive(Deserialize, Serialize)]
 pub enum ClientToServerPacket {
     Input(PlayerInputData),
-    Ping(PingData)
+    Ping(PingData),
+    Disconnect, // Sent on exit so the server doesn't wait out the idle timeout
+    ResyncRequest // Client thinks it's out of sync, asks for a world state on the next tick
 }
 
-#[derive(Deserialize, Serialize)]
+#[allow(dead_code)]
+impl ClientToServerPacket {
+    // The limit caps how much any variable length field can make the decoder allocate
+    pub fn decode(msg: &[u8]) -> Result<Self, PacketDecodeError> {
+        decode_packet(msg, NET_CONFIG.with_limit::<MAX_DECODE_BYTES>())
+    }
+}
+
+// A client gets at most one resync per this long, so a broken one can't turn the server into a
+// world state firehose
+pub const RESYNC_MIN_INTERVAL_S: f32 = 1.0;
+
+#[derive(Deserialize, Serialize, Clone)]
 pub struct NetPaddleData {
     pub pos: Vec2,
-    pub player_index: NetPlayerIndex
+    pub player_index: NetPlayerIndex,
+    pub color: NetColorIndex,
+    pub lives: Option<u8> // None outside survival mode
 }
 
-#[derive(Deserialize, Serialize)]
+#[derive(Deserialize, Serialize, Clone)]
 pub struct NetBrickData {
-    pub pos: Vec2
+    pub pos: Vec2,
+    pub value: u8
 }
 
-#[derive(Deserialize, Serialize)]
+#[derive(Deserialize, Serialize, Clone)]
 pub struct NetBallData {
     pub pos: Vec2,
     pub velocity: Vec2, // experimental for not predicting collisions
-    pub player_index: NetPlayerIndex
+    pub spin: f32,
+    // First server frame the ball moves on, None while it's held with no launch scheduled yet
+    pub launch_frame: Option<u32>,
+    // Paddle the ball rides on up to and including launch_frame, None if it waits where it is
+    pub held_by: Option<NetId>,
+    pub player_index: NetPlayerIndex,
+    pub color: NetColorIndex
 }
 
-#[derive(Deserialize, Serialize)]
+#[derive(Deserialize, Serialize, Clone)]
 pub struct NetScoreData {
     pub score: u32
 }
 
-#[derive(Deserialize, Serialize)]
+#[derive(Deserialize, Serialize, Clone)]
 pub enum NetEntityType {
     Paddle(NetPaddleData),
     Brick(NetBrickData),
@@ -207,13 +344,29 @@ pub enum NetEntityType {
     Score(NetScoreData),
 }
 
-#[derive(Component, Deserialize, Serialize, Clone, Copy, Hash, PartialEq, Eq)]
+#[derive(Component, Deserialize, Serialize, Clone, Copy, Hash, PartialEq, Eq, PartialOrd, Ord)]
 pub struct NetId(pub u16);
 
 #[derive(Component, Deserialize, Serialize, Clone, Copy, Hash, PartialEq, Eq)]
 pub struct NetPlayerIndex(pub u8);
 
-#[derive(Deserialize, Serialize)]
+// Index into COLORS, picked by the server so every client draws a player the same way
+#[derive(Component, Deserialize, Serialize, Clone, Copy, Hash, PartialEq, Eq, Debug)]
+pub struct NetColorIndex(pub u8);
+
+impl NetColorIndex {
+    // Not a player color, for entities that shouldn't look like they belong to anyone
+    pub const NEUTRAL: NetColorIndex = NetColorIndex(u8::MAX);
+
+    pub fn color(&self) -> Color {
+        if *self == NetColorIndex::NEUTRAL {
+            return NEUTRAL_BALL_COLOR;
+        }
+        COLORS[self.0 as usize % COLORS.len()]
+    }
+}
+
+#[derive(Deserialize, Serialize, Clone)]
 pub struct NetEntity {
     pub entity_type: NetEntityType,
     pub net_id: NetId,
@@ -231,16 +384,158 @@ impl NetEntity{
     }
 }
 
-#[derive(Deserialize, Serialize, Default)]
+// The default arena (bricks + a couple of players) fits inline, so most ticks never touch the heap
+pub const INLINE_WORLD_ENTITIES: usize = 64;
+
+// Balls only move while Playing. Countdown carries the ticks left so every client shows the same number.
+#[derive(Deserialize, Serialize, Default, Clone, Copy, PartialEq, Eq, Debug)]
+pub enum MatchPhase {
+    #[default]
+    Playing,
+    WaitingForPlayers,
+    Countdown(u16)
+}
+
+impl MatchPhase {
+    // How many ticks from this state until balls move, None if that isn't known yet
+    pub fn ticks_until_play(&self) -> Option<u32> {
+        match self {
+            MatchPhase::Playing => Some(0),
+            MatchPhase::WaitingForPlayers => None,
+            MatchPhase::Countdown(ticks) => Some(*ticks as u32),
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, Default, Clone)]
 pub struct NetWorldStateData {
     pub frame: u32,
-    pub entities: Vec<NetEntity>,
+    pub paused: bool, // Server-authoritative, clients stop sending input and predicting while set
+    pub phase: MatchPhase,
+    pub entities: SmallVec<[NetEntity; INLINE_WORLD_ENTITIES]>,
+    pub collisions: SmallVec<[CollisionEvent; MAX_COLLISIONS_PER_PACKET]>,
+    // Big worlds are split across packets with the same frame, collisions and serve only ride in
+    // part 0. part_count 0 or 1 means this packet is the whole state.
+    pub part: u8,
+    pub part_count: u8,
+    // Only sent while a serve is coming up, i.e. whenever the phase isn't Playing
+    pub serve: Option<NetServeData>,
+    // Game specific per-client data from a server WorldStateHook, empty without one. Only part 0
+    // carries it when a state is split.
+    pub custom: Vec<u8>,
+}
+
+#[derive(Debug)]
+pub enum PacketDecodeError {
+    Decode(DecodeError),
+    // A valid packet followed by this many bytes nobody asked for, most likely corruption
+    TrailingBytes(usize),
+    Header(HeaderError),
+}
+
+#[derive(PartialEq, Debug)]
+pub enum HeaderError {
+    // Not even a header and a 1 byte packet
+    TooShort(usize),
+    BadTag(u32),
+}
+
+// The per-client header in front of every server packet, laid out by the HEADER_*_OFFSET consts.
+// Client to server packets have no header. The server writes these and the client parses them,
+// each binary only uses one half.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct ServerPacketHeader {
+    pub last_applied_input: u32,
+    pub player_index: u8,
+    // None if the server sent a state this client doesn't know
+    pub input_state: Option<NetInputState>,
+    pub buffer_remaining_ms: u16,
+    pub input_delay_ms: u16,
+    pub send_interval_ticks: u8,
+}
+
+#[allow(dead_code)]
+impl ServerPacketHeader {
+    // buf has to have room for the packet after the header
+    pub fn write(&self, buf: &mut [u8]) {
+        debug_assert!(buf.len() > HEADER_LEN, "{} byte packet has no room after the {} byte header", buf.len(), HEADER_LEN);
+        byteorder::NetworkEndian::write_u32(buf, WORLD_PACKET_HEADER_TAG);
+        byteorder::NetworkEndian::write_u32(&mut buf[HEADER_LAST_APPLIED_INPUT_OFFSET..], self.last_applied_input);
+        buf[HEADER_PLAYER_INDEX_OFFSET] = self.player_index;
+        buf[HEADER_INPUT_STATE_OFFSET] = self.input_state.map_or(u8::MAX, |s| s as u8);
+        byteorder::NetworkEndian::write_u16(&mut buf[HEADER_BUFFER_REMAINING_OFFSET..], self.buffer_remaining_ms);
+        byteorder::NetworkEndian::write_u16(&mut buf[HEADER_INPUT_DELAY_OFFSET..], self.input_delay_ms);
+        buf[HEADER_SEND_INTERVAL_OFFSET] = self.send_interval_ticks;
+        // The last field has to end at HEADER_LEN or the packet after it starts in the wrong place
+        debug_assert_eq!(HEADER_SEND_INTERVAL_OFFSET + size_of::<u8>(), HEADER_LEN);
+    }
+
+    // Returns the header and the packet after it
+    pub fn parse(msg: &[u8]) -> Result<(Self, &[u8]), HeaderError> {
+        if msg.len() < HEADER_LEN + 1 {
+            return Err(HeaderError::TooShort(msg.len()));
+        }
+        let tag = byteorder::NetworkEndian::read_u32(msg);
+        if tag != WORLD_PACKET_HEADER_TAG {
+            return Err(HeaderError::BadTag(tag));
+        }
+        let header = ServerPacketHeader {
+            last_applied_input: byteorder::NetworkEndian::read_u32(&msg[HEADER_LAST_APPLIED_INPUT_OFFSET..]),
+            player_index: msg[HEADER_PLAYER_INDEX_OFFSET],
+            input_state: NetInputState::from_u8(msg[HEADER_INPUT_STATE_OFFSET]),
+            buffer_remaining_ms: byteorder::NetworkEndian::read_u16(&msg[HEADER_BUFFER_REMAINING_OFFSET..]),
+            input_delay_ms: byteorder::NetworkEndian::read_u16(&msg[HEADER_INPUT_DELAY_OFFSET..]),
+            send_interval_ticks: msg[HEADER_SEND_INTERVAL_OFFSET],
+        };
+        Ok((header, &msg[HEADER_LEN..]))
+    }
+}
+
+// Every datagram carries exactly one packet, so it has to use up the whole slice
+pub fn decode_packet<T: DeserializeOwned, C: config::Config>(bytes: &[u8], config: C) -> Result<T, PacketDecodeError> {
+    let (packet, consumed) = bincode::serde::decode_from_slice(bytes, config).map_err(PacketDecodeError::Decode)?;
+    if consumed != bytes.len() {
+        return Err(PacketDecodeError::TrailingBytes(bytes.len() - consumed));
+    }
+    Ok(packet)
 }
 
 #[derive(Deserialize, Serialize)]
 pub enum ServerToClientPacket {
     WorldState(NetWorldStateData),
-    Pong(PingData)
+    Pong(PingData),
+    ServerInfo(NetServerInfo)
+}
+
+#[allow(dead_code)]
+impl ServerToClientPacket {
+    // A whole datagram from the server, header included
+    pub fn decode(msg: &[u8]) -> Result<(ServerPacketHeader, Self), PacketDecodeError> {
+        let (header, payload) = ServerPacketHeader::parse(msg).map_err(PacketDecodeError::Header)?;
+        Ok((header, decode_packet(payload, NET_CONFIG)?))
+    }
+}
+
+// How the server is set up, so clients don't need to be started with matching flags. Sent on
+// connect and every SERVER_INFO_RESEND_S after in case that one was lost.
+#[derive(Deserialize, Serialize, Clone, Copy, PartialEq, Debug)]
+pub struct NetServerInfo {
+    pub tick_hz: u16,
+    pub send_interval_ticks: u8,
+    pub paddles_per_player: u8,
+    pub teams: u8, // 0 is free-for-all
+    pub arena_size: Vec2, // Between the inside edges of the walls
+    pub paddle: PaddleTuning,
+    pub lives: u8, // Survival mode starting lives, 0 is off
+    pub gravity: f32 // px/s^2 pulling balls down, see apply_gravity
+}
+
+// Clients wait for ServerInfo before applying world states, so this is also how long a lost one
+// can hold up joining
+pub const SERVER_INFO_RESEND_S: f32 = 1.0;
+
+pub fn arena_size() -> Vec2 {
+    Vec2::new(RIGHT_WALL - LEFT_WALL, TOP_WALL - BOTTOM_WALL)
 }
 
 #[derive(Debug, PartialEq, Eq, Copy, Clone)]
@@ -254,12 +549,21 @@ enum Collision {
 // Returns `Some` if `ball` collides with `bounding_box`.
 // The returned `Collision` is the side of `bounding_box` that `ball` hit.
 fn ball_collision(ball: BoundingCircle, bounding_box: Aabb2d) -> Option<Collision> {
-    if !ball.intersects(&bounding_box) {
-        return None;
-    }
+    #[cfg(not(feature = "fixed_point_sim"))]
+    let offset = {
+        if !ball.intersects(&bounding_box) {
+            return None;
+        }
+        ball.center() - bounding_box.closest_point(ball.center())
+    };
+
+    // Grid offsets are small enough to be exact in f32, so the side checks below agree too
+    #[cfg(feature = "fixed_point_sim")]
+    let offset = {
+        let (x, y) = fixed_point::ball_box_offset(ball.center(), ball.radius(), bounding_box.min, bounding_box.max)?;
+        Vec2::new(x as f32, y as f32)
+    };
 
-    let closest = bounding_box.closest_point(ball.center());
-    let offset = ball.center() - closest;
     let side = if offset.x.abs() > offset.y.abs() {
         if offset.x < 0. {
             Collision::Left
@@ -275,20 +579,84 @@ fn ball_collision(ball: BoundingCircle, bounding_box: Aabb2d) -> Option<Collisio
     Some(side)
 }
 
+pub const RECENT_TICK_TIMES_LEN: usize = 60;
+
+pub const TICK_HISTOGRAM_BUCKET_US: u64 = 100;
+// Covers twice the tick budget, anything slower lands in the last bucket
+pub const TICK_HISTOGRAM_BUCKETS: usize = (2.0 * TICK_S * 1_000_000.0) as usize / TICK_HISTOGRAM_BUCKET_US as usize + 1;
+pub const TICK_HISTOGRAM_LOG_INTERVAL: u32 = 600;
+
+// Accumulates for the lifetime of the app, percentiles are only as precise as the bucket width
+#[derive(Resource)]
+pub struct TickTimeHistogram {
+    pub buckets: Vec<u32>,
+    pub count: u32,
+    pub over_budget: u32,
+}
+
+impl Default for TickTimeHistogram {
+    fn default() -> Self {
+        TickTimeHistogram {
+            buckets: vec![0; TICK_HISTOGRAM_BUCKETS],
+            count: 0,
+            over_budget: 0,
+        }
+    }
+}
+
+impl TickTimeHistogram {
+    pub fn record(&mut self, tick_time: time::Duration) {
+        let bucket = (tick_time.as_micros() as u64 / TICK_HISTOGRAM_BUCKET_US) as usize;
+        self.buckets[bucket.min(TICK_HISTOGRAM_BUCKETS - 1)] += 1;
+        self.count += 1;
+        if tick_time.as_secs_f64() > TICK_S {
+            self.over_budget += 1;
+        }
+    }
+
+    // Upper edge of the bucket the percentile falls in
+    pub fn percentile(&self, p: f64) -> time::Duration {
+        let target = (self.count as f64 * p).ceil() as u32;
+        let mut seen = 0;
+        for (i, &n) in self.buckets.iter().enumerate() {
+            seen += n;
+            if seen >= target && seen > 0 {
+                return time::Duration::from_micros((i as u64 + 1) * TICK_HISTOGRAM_BUCKET_US);
+            }
+        }
+        time::Duration::ZERO
+    }
+}
+
 #[derive(Resource, Default)]
 pub struct FixedTickWorldResource {
     pub frame_counter: u32,
-    pub tick_start: Option<time::Instant>
+    pub tick_start: Option<time::Instant>,
+    pub recent_tick_times: VecDeque<time::Duration>,
+    pub tick_span: Option<Span>
 }
 
+impl FixedTickWorldResource {
+    // Per system spans hang off of this so a tracing subscriber groups them by tick
+    pub fn tick_span(&self) -> Span {
+        self.tick_span.clone().unwrap_or(Span::none())
+    }
+}
+
+// A brick hit by two balls on the same tick only counts for the first ball checked; the other one
+// skips it, no bounce. Server and client prediction both check balls in ascending NetId order, so
+// the lowest NetId ball always gets the brick and the two agree on who scored.
+// Colliders are (entity, transform, brick, is a paddle)
 pub fn check_single_ball_collision<'a>(
     score: &mut ResMut<Score>,
-    colliders: impl Iterator<Item = (Entity, &'a Transform, Option<&'a Brick>)>,
+    colliders: impl Iterator<Item = (Entity, &'a Transform, Option<&'a Brick>, bool)>,
     ball_transform: &Transform,
     ball_velocity: &mut Velocity,
+    ball_spin: &mut Spin,
     entities_to_delete: &mut Vec<Entity>,
+    collisions: &mut Vec<(Vec2, CollisionKind)>,
 ) {
-    for (collider_entity, collider_transform, maybe_brick) in colliders {
+    for (collider_entity, collider_transform, maybe_brick, is_paddle) in colliders {
         if entities_to_delete.contains(&collider_entity) {
             continue;
         }
@@ -301,15 +669,16 @@ pub fn check_single_ball_collision<'a>(
         );
 
         if let Some(collision) = collision {
-            // Sends a collision event so that other systems can react to the collision
-            //collision_events.send_default();
-
             // Bricks should be despawned and increment the scoreboard on collision
-            if maybe_brick.is_some() {
+            if let Some(brick) = maybe_brick {
                 entities_to_delete.push(collider_entity);
-                score.0 += 1;
+                score.0 += brick.value as u32;
             }
 
+            // Let the caller turn these into events so other systems can react to the collision
+            let kind = if maybe_brick.is_some() { CollisionKind::Brick } else { CollisionKind::Bounce };
+            collisions.push((ball_transform.translation.truncate(), kind));
+
             // Reflect the ball's velocity when it collides
             let mut reflect_x = false;
             let mut reflect_y = false;
@@ -332,16 +701,152 @@ pub fn check_single_ball_collision<'a>(
             if reflect_y {
                 ball_velocity.y = -ball_velocity.y;
             }
+
+            if is_paddle && reflect_y {
+                ball_spin.0 = paddle_hit_spin(ball_transform.translation.x, collider_transform);
+            }
         }
     }
 }
 
+// Off center paddle hits curve the ball further out toward the side it hit, clockwise off the
+// right half and counterclockwise off the left
+pub fn paddle_hit_spin(ball_x: f32, paddle: &Transform) -> f32 {
+    let offset = (ball_x - paddle.translation.x) / (paddle.scale.x / 2.0);
+    -offset.clamp(-1.0, 1.0) * PADDLE_EDGE_SPIN
+}
+
 pub const PADDLE_SPEED: f32 = 500.0;
 pub const PADDLE_PADDING: f32 = 10.0;
-pub const PADDLE_LEFT_BOUND: f32 = LEFT_WALL + WALL_THICKNESS / 2.0 + PADDLE_SIZE.x / 2.0 + PADDLE_PADDING;
-pub const PADDLE_RIGHT_BOUND: f32 = RIGHT_WALL - WALL_THICKNESS / 2.0 - PADDLE_SIZE.x / 2.0 - PADDLE_PADDING;
 
-pub fn move_paddle(delta_seconds: f32, paddle_transform: &mut Transform, input: &PlayerInputData) {
+// How paddles move and how big they are. The server picks it and sends it in ServerInfo, and every
+// paddle carries a copy, so server and client prediction always move paddles the same way.
+#[derive(Component, Deserialize, Serialize, Clone, Copy, PartialEq, Debug)]
+pub struct PaddleTuning {
+    pub speed: f32,
+    pub size: Vec2
+}
+
+impl Default for PaddleTuning {
+    fn default() -> Self {
+        PaddleTuning { speed: PADDLE_SPEED, size: PADDLE_SIZE }
+    }
+}
+
+impl PaddleTuning {
+    pub fn left_bound(&self) -> f32 {
+        LEFT_WALL + WALL_THICKNESS / 2.0 + self.size.x / 2.0 + PADDLE_PADDING
+    }
+
+    pub fn right_bound(&self) -> f32 {
+        RIGHT_WALL - WALL_THICKNESS / 2.0 - self.size.x / 2.0 - PADDLE_PADDING
+    }
+
+    // Paddles have to be able to move and fit between the walls
+    pub fn validate(&self) -> Result<(), String> {
+        if self.speed.is_nan() || self.speed <= 0.0 {
+            return Err(format!("paddle speed {} has to be above 0", self.speed));
+        }
+        if self.size.is_nan() || self.size.min_element() <= 0.0 || self.left_bound() > self.right_bound() {
+            return Err(format!("paddle size {} doesn't fit in the arena", self.size));
+        }
+        Ok(())
+    }
+}
+
+// Rounds onto the fixed_point_sim grid so spawns and serves start out exact
+#[cfg(feature = "fixed_point_sim")]
+pub fn snap_to_sim_grid(v: Vec2) -> Vec2 {
+    fixed_point::snap(v)
+}
+
+#[cfg(not(feature = "fixed_point_sim"))]
+pub fn snap_to_sim_grid(v: Vec2) -> Vec2 {
+    v
+}
+
+// Between the top of a paddle and the bottom of a ball spawned on it
+pub const BALL_SPAWN_GAP: f32 = 5.0;
+
+// Where a ball sits on top of a paddle, both when it's served from one and while it's held by one
+pub fn held_ball_position(paddle: Vec2, tuning: &PaddleTuning) -> Vec2 {
+    snap_to_sim_grid(paddle + Vec2::new(0.0, tuning.size.y / 2.0 + BALL_SPAWN_GAP + BALL_DIAMETER / 2.0))
+}
+
+// Serves are a pure function of the match seed, the round and the ball rather than a draw from a
+// shared rng, so anyone who knows the seed gets the same serve no matter what order things happened in
+#[derive(Deserialize, Serialize, Clone, Copy, PartialEq, Debug)]
+pub struct NetServeData {
+    pub seed: u64,
+    // Goes up every match reset
+    pub round: u32,
+    // Serves go up toward the bricks inside a cone around +Y
+    pub half_angle_rad: f32,
+}
+
+pub fn serve_velocity(serve: &NetServeData, ball: NetId) -> Vec2 {
+    let mut rng = ChaCha8Rng::seed_from_u64(serve.seed);
+    rng.set_stream(((serve.round as u64) << 16) | ball.0 as u64);
+    let angle = if serve.half_angle_rad > 0.0 {
+        rng.gen_range(-serve.half_angle_rad..=serve.half_angle_rad)
+    } else {
+        0.0
+    };
+    snap_to_sim_grid(Vec2::from_angle(angle).rotate(Vec2::Y) * BALL_SPEED)
+}
+
+// Server and client prediction both step exactly one tick through these, so they agree on the math
+pub fn apply_velocity(transform: &mut Transform, velocity: &Velocity) {
+    #[cfg(not(feature = "fixed_point_sim"))]
+    {
+        transform.translation.x += velocity.x * TICK_S as f32;
+        transform.translation.y += velocity.y * TICK_S as f32;
+    }
+    #[cfg(feature = "fixed_point_sim")]
+    {
+        transform.translation.x = fixed_point::step(transform.translation.x, velocity.x);
+        transform.translation.y = fixed_point::step(transform.translation.y, velocity.y);
+    }
+}
+
+// Pulls velocity down by one tick of gravity in px/s^2, negative pushes up. Zero leaves velocity
+// untouched, bit for bit, so matches without gravity simulate exactly as before.
+pub fn apply_gravity(velocity: &mut Velocity, gravity: f32) {
+    if gravity == 0.0 {
+        return;
+    }
+    velocity.0 = snap_to_sim_grid(Vec2::new(velocity.x, velocity.y - gravity * TICK_S as f32));
+}
+
+pub fn validate_gravity(gravity: f32) -> Result<(), String> {
+    if !gravity.is_finite() {
+        return Err(format!("gravity {} has to be a finite number", gravity));
+    }
+    Ok(())
+}
+
+// Turns velocity by one tick of spin, then decays the spin. Only adds, multiplies and sqrt, which
+// IEEE 754 rounds the same everywhere, so server and client prediction curve identically. sin/cos
+// aren't guaranteed to.
+pub fn apply_spin(velocity: &mut Velocity, spin: &mut Spin) {
+    if spin.0 == 0.0 {
+        return;
+    }
+
+    // Second order small angle rotation, then back to the original speed
+    let angle = spin.0 * TICK_S as f32;
+    let (sin, cos) = (angle, 1.0 - angle * angle * 0.5);
+    let speed = velocity.length();
+    let rotated = Vec2::new(velocity.x * cos - velocity.y * sin, velocity.x * sin + velocity.y * cos);
+    velocity.0 = snap_to_sim_grid(rotated * (speed / rotated.length()));
+
+    spin.0 *= SPIN_DECAY_PER_TICK;
+    if spin.0.abs() < MIN_SPIN {
+        spin.0 = 0.0;
+    }
+}
+
+pub fn move_paddle(paddle_transform: &mut Transform, input: &PlayerInputData, tuning: &PaddleTuning) {
     let buttons = input.key_mask;
     let mut direction = 0.0;
     if (buttons & (1 << NetKey::Left as u8)) != 0 {
@@ -352,13 +857,25 @@ pub fn move_paddle(delta_seconds: f32, paddle_transform: &mut Transform, input:
         direction += 1.0;
     }
 
-    // Calculate the new horizontal paddle position based on player input
-    let new_paddle_position =
-        paddle_transform.translation.x + direction * PADDLE_SPEED * delta_seconds;
+    #[cfg(not(feature = "fixed_point_sim"))]
+    {
+        // Calculate the new horizontal paddle position based on player input
+        let new_paddle_position =
+            paddle_transform.translation.x + direction * tuning.speed * TICK_S as f32;
 
-    // Update the paddle position,
-    // making sure it doesn't cause the paddle to leave the arena
-    paddle_transform.translation.x = new_paddle_position.clamp(PADDLE_LEFT_BOUND, PADDLE_RIGHT_BOUND);
+        // Update the paddle position,
+        // making sure it doesn't cause the paddle to leave the arena
+        paddle_transform.translation.x = new_paddle_position.clamp(tuning.left_bound(), tuning.right_bound());
+    }
+    #[cfg(feature = "fixed_point_sim")]
+    {
+        paddle_transform.translation.x = fixed_point::step_clamped(
+            paddle_transform.translation.x,
+            direction * tuning.speed,
+            tuning.left_bound(),
+            tuning.right_bound()
+        );
+    }
 }
 
 pub fn update_scoreboard(score: Res<Score>, mut query: Query<&mut Text, With<ScoreboardUi>>) {
@@ -370,62 +887,98 @@ pub fn update_scoreboard(score: Res<Score>, mut query: Query<&mut Text, With<Sco
 pub struct PaddleBundle {
     sprite_bundle: SpriteBundle,
     paddle: Paddle,
+    tuning: PaddleTuning,
     collider: Collider,
     net_id: NetId,
-    player: NetPlayerIndex
+    player: NetPlayerIndex,
+    color: NetColorIndex
 }
 
 impl PaddleBundle {
-    pub fn new(translation: Vec2, net_id: NetId, player: NetPlayerIndex) -> Self {
+    pub fn new(translation: Vec2, net_id: NetId, player: NetPlayerIndex, color: NetColorIndex, tuning: PaddleTuning) -> Self {
         PaddleBundle {
             sprite_bundle: SpriteBundle {
                 transform: Transform {
-                    translation: Vec3::from((translation, 0.0)),
-                    scale: PADDLE_SIZE.extend(1.0),
+                    translation: Vec3::from((snap_to_sim_grid(translation), 0.0)),
+                    scale: tuning.size.extend(1.0),
                     ..default()
                 },
                 sprite: Sprite {
-                    color: COLORS[player.0 as usize % COLORS.len()],
+                    color: color.color(),
                     ..default()
                 },
                 ..default()
             },
             paddle: Paddle,
+            tuning,
             collider: Collider,
             net_id,
-            player
+            player,
+            color
         }
     }
 }
 
+// Every ball shares one circle mesh and one material per color. Adding fresh ones on each spawn
+// leaks a pair of assets every time a ball respawns.
+#[derive(Resource, Default)]
+pub struct BallAssetCache {
+    mesh: Option<Handle<Mesh>>,
+    materials: HashMap<NetColorIndex, Handle<ColorMaterial>>
+}
+
+#[derive(SystemParam)]
+pub struct BallAssets<'w> {
+    cache: ResMut<'w, BallAssetCache>,
+    meshes: ResMut<'w, Assets<Mesh>>,
+    materials: ResMut<'w, Assets<ColorMaterial>>
+}
+
+impl BallAssets<'_> {
+    pub fn handles(&mut self, color: NetColorIndex) -> (Handle<Mesh>, Handle<ColorMaterial>) {
+        let meshes = &mut self.meshes;
+        let mesh = self.cache.mesh.get_or_insert_with(|| meshes.add(Circle::default())).clone();
+        let materials = &mut self.materials;
+        let material = self.cache.materials.entry(color).or_insert_with(|| materials.add(color.color())).clone();
+        (mesh, material)
+    }
+}
+
 #[derive(Bundle)]
 pub struct BallBundle {
     mesh_bundle: MaterialMesh2dBundle<ColorMaterial>,
     ball: Ball,
     velocity: Velocity,
+    spin: Spin,
     net_id: NetId,
-    player: NetPlayerIndex
+    player: NetPlayerIndex,
+    color: NetColorIndex
 }
 
 impl BallBundle {
     pub fn new(
-        meshes: &mut Assets<Mesh>,
-        materials: &mut Assets<ColorMaterial>,
+        assets: &mut BallAssets,
         translation: Vec2,
+        velocity: Vec2,
         net_id: NetId,
-        player: NetPlayerIndex) -> Self {
+        player: NetPlayerIndex,
+        color: NetColorIndex,
+        spin: f32) -> Self {
+       let (mesh, material) = assets.handles(color);
        BallBundle {
            mesh_bundle: MaterialMesh2dBundle {
-               mesh: meshes.add(Circle::default()).into(),
-               material: materials.add(COLORS[player.0 as usize % COLORS.len()]),
-               transform: Transform::from_translation(Vec3::from((translation, 1.0)))
+               mesh: mesh.into(),
+               material,
+               transform: Transform::from_translation(Vec3::from((snap_to_sim_grid(translation), 1.0)))
                    .with_scale(Vec2::splat(BALL_DIAMETER).extend(1.)),
                ..default()
            },
            ball: Ball,
-           velocity: Velocity(INITIAL_BALL_DIRECTION.normalize() * BALL_SPEED),
+           velocity: Velocity(snap_to_sim_grid(velocity)),
+           spin: Spin(spin),
            net_id,
-           player
+           player,
+           color
        }
     }
 }
@@ -439,11 +992,11 @@ pub struct BrickBundle {
 }
 
 impl BrickBundle {
-    pub fn new(brick_position: Vec2, net_id: NetId) -> Self {
+    pub fn new(brick_position: Vec2, net_id: NetId, value: u8) -> Self {
         BrickBundle {
             sprite_bundle: SpriteBundle {
                 sprite: Sprite {
-                    color: BRICK_COLOR,
+                    color: brick_color(value),
                     ..default()
                 },
                 transform: Transform {
@@ -453,7 +1006,7 @@ impl BrickBundle {
                 },
                 ..default()
             },
-            brick: Brick,
+            brick: Brick { value },
             collider: Collider,
             net_id
         }
@@ -502,50 +1055,367 @@ pub fn start_tick(
 ) {
     world_resource.frame_counter += 1;
     world_resource.tick_start = Some(time::Instant::now());
+    world_resource.tick_span = Some(info_span!("tick", frame = world_resource.frame_counter));
 }
 
 pub fn end_tick(
-    world_resource: ResMut<FixedTickWorldResource>
+    mut world_resource: ResMut<FixedTickWorldResource>,
+    mut histogram: ResMut<TickTimeHistogram>,
 ) {
-    debug!("tick time: {:?}", world_resource.tick_start.unwrap().elapsed());
+    let tick_time = world_resource.tick_start.unwrap().elapsed();
+    let _span = info_span!(parent: &world_resource.tick_span(), "end_tick").entered();
+    debug!("tick time: {:?}", tick_time);
+
+    let budget = time::Duration::from_secs_f64(TICK_S);
+    histogram.record(tick_time);
+    if tick_time > budget {
+        warn!("Tick {} took {:?}, over the {:?} budget", world_resource.frame_counter, tick_time, budget);
+    }
+    if histogram.count % TICK_HISTOGRAM_LOG_INTERVAL == 0 {
+        info!(
+            "tick time p50 {:?} p99 {:?}, {} of {} ticks over budget",
+            histogram.percentile(0.5),
+            histogram.percentile(0.99),
+            histogram.over_budget,
+            histogram.count
+        );
+    }
+
+    world_resource.recent_tick_times.push_back(tick_time);
+    if world_resource.recent_tick_times.len() > RECENT_TICK_TIMES_LEN {
+        world_resource.recent_tick_times.pop_front();
+    }
+
+    // Closes the tick span once the spans above are done with it
+    world_resource.tick_span = None;
 }
 
+// How often an unfocused, throttled window still wakes up. Has to stay well under the idle timeout
+// since networking runs in FixedUpdate.
+pub const BG_THROTTLE_WAIT_MS: u64 = 100;
+
 #[derive(Args, Debug, Clone, Copy)]
+pub struct WindowArgs {
+    #[arg(long, action = clap::ArgAction::Set, default_value_t = true)]
+    pub vsync: bool,
+
+    /// Stop updating continuously while unfocused. Defaults to on for the client and off for the server
+    #[arg(long, action = clap::ArgAction::Set)]
+    pub bg_throttle: Option<bool>,
+}
+
+impl WindowArgs {
+    pub fn winit_settings(&self, default_bg_throttle: bool) -> bevy::winit::WinitSettings {
+        let unfocused_mode = if self.bg_throttle.unwrap_or(default_bg_throttle) {
+            bevy::winit::UpdateMode::reactive_low_power(time::Duration::from_millis(BG_THROTTLE_WAIT_MS))
+        } else {
+            bevy::winit::UpdateMode::Continuous
+        };
+
+        bevy::winit::WinitSettings {
+            focused_mode: bevy::winit::UpdateMode::Continuous,
+            unfocused_mode,
+        }
+    }
+
+    pub fn window_plugin(&self) -> WindowPlugin {
+        let present_mode = if self.vsync {
+            bevy::window::PresentMode::AutoVsync
+        } else {
+            bevy::window::PresentMode::AutoNoVsync
+        };
+
+        WindowPlugin {
+            primary_window: Some(Window {
+                present_mode,
+                ..default()
+            }),
+            ..default()
+        }
+    }
+}
+
+// Bevy's default lets a stall be made up with 15 ticks in one frame, a burst of inputs or world states
+pub const DEFAULT_MAX_CATCHUP_TICKS: u32 = 4;
+
+#[derive(Args, Debug, Clone, Copy)]
+pub struct TickArgs {
+    /// After a stall, run at most this many fixed ticks in one frame to catch up. Time past that is
+    /// dropped, the sim falls behind the wall clock instead of bursting.
+    #[arg(long, default_value_t = DEFAULT_MAX_CATCHUP_TICKS, value_parser = clap::value_parser!(u32).range(1..))]
+    pub max_catchup_ticks: u32,
+}
+
+impl TickArgs {
+    // Fixed ticks only run on virtual time, so capping how far it can jump in a frame caps catch-up
+    pub fn virtual_time(&self) -> Time<Virtual> {
+        Time::<Virtual>::from_max_delta(time::Duration::from_secs_f64(TICK_S * self.max_catchup_ticks as f64))
+    }
+}
+
+#[derive(Args, Debug, Clone, Copy)]
+pub struct JitterArgs {
+    /// Buffering added on top of the tick interval to absorb packet jitter
+    #[arg(long, default_value_t = MIN_JITTER_S * 1000.0)]
+    pub jitter_padding_ms: f64,
+
+    /// Size the padding from measured arrival jitter instead, --jitter-padding-ms is used until
+    /// there's a measurement
+    #[arg(long, default_value_t = false)]
+    pub adaptive_jitter: bool,
+}
+
+// Running estimate of how far packet arrivals stray from one per tick, smoothed like RFC 3550's
+// interarrival jitter
+#[derive(Default, Clone, Copy, Debug)]
+pub struct JitterEstimator {
+    last_arrival: Option<time::Instant>,
+    jitter_s: Option<f64>,
+}
+
+impl JitterEstimator {
+    // Takes when the datagram came off the socket, not when a system got around to it. Frame time
+    // would fold however many packets a frame drains into one arrival and hide the real spread.
+    // expected_interval_s is how far apart they're sent, TICK_S times the server's send interval
+    // for world states.
+    pub fn record_arrival(&mut self, arrival: time::Instant, expected_interval_s: f64) {
+        if let Some(last) = self.last_arrival {
+            let deviation = (arrival.saturating_duration_since(last).as_secs_f64() - expected_interval_s).abs();
+            let jitter = self.jitter_s.unwrap_or(deviation);
+            self.jitter_s = Some(jitter + (deviation - jitter) / 16.0);
+        }
+        self.last_arrival = Some(arrival);
+    }
+
+    pub fn jitter_s(&self) -> Option<f64> {
+        self.jitter_s
+    }
+}
+
+#[derive(Resource, Clone, Copy, Debug)]
+pub struct JitterPadding {
+    pub configured_s: f64,
+    pub adaptive: bool,
+}
+
+impl From<JitterArgs> for JitterPadding {
+    fn from(value: JitterArgs) -> Self {
+        JitterPadding {
+            configured_s: value.jitter_padding_ms / 1000.0,
+            adaptive: value.adaptive_jitter,
+        }
+    }
+}
+
+impl JitterPadding {
+    pub fn effective_s(&self, estimator: &JitterEstimator) -> f64 {
+        match estimator.jitter_s() {
+            Some(jitter) if self.adaptive => (jitter * ADAPTIVE_JITTER_MULTIPLIER).min(MAX_JITTER_PADDING_S),
+            _ => self.configured_s,
+        }
+    }
+}
+
+// Found by the startup timing checks. Errors are combinations that can't work, warnings ones that
+// probably won't do what was intended.
+#[derive(Debug)]
+pub enum TimingIssue {
+    Error(String),
+    Warning(String)
+}
+
+// Invariants between timing values both binaries share
+pub fn check_shared_timing(padding: &JitterPadding) -> Vec<TimingIssue> {
+    let mut issues = Vec::new();
+    let heartbeat_s = networking::DEFAULT_HEARTBEAT_TICK_RATE_SECS as f64;
+    let idle_timeout_s = networking::DEFAULT_IDLE_TIMEOUT_SECS as f64;
+    if heartbeat_s >= idle_timeout_s {
+        issues.push(TimingIssue::Error(format!(
+            "heartbeat interval ({} s) must be shorter than the idle timeout ({} s), or idle clients time out between heartbeats",
+            heartbeat_s, idle_timeout_s
+        )));
+    } else if heartbeat_s > idle_timeout_s / 2.0 {
+        issues.push(TimingIssue::Warning(format!(
+            "heartbeat interval ({} s) should be at most half the idle timeout ({} s), or a single lost heartbeat times out an idle client",
+            heartbeat_s, idle_timeout_s
+        )));
+    }
+
+    if padding.configured_s < 0.0 {
+        issues.push(TimingIssue::Error(format!(
+            "jitter padding ({} ms) can't be negative, interpolation and input buffering need at least one tick ({:.1} ms) of delay",
+            padding.configured_s * 1000.0, TICK_S * 1000.0
+        )));
+    } else if !padding.adaptive && padding.configured_s > MAX_JITTER_PADDING_S {
+        issues.push(TimingIssue::Warning(format!(
+            "jitter padding ({} ms) is over the most adaptive padding ever picks ({} ms), every packet will be held longer than jitter calls for",
+            padding.configured_s * 1000.0, MAX_JITTER_PADDING_S * 1000.0
+        )));
+    }
+    issues
+}
+
+// Prints the warnings and hands back the errors, if any, for the binary to report as a usage error
+pub fn report_timing_issues(issues: Vec<TimingIssue>) -> Result<(), String> {
+    let mut errors = Vec::new();
+    for issue in issues {
+        match issue {
+            TimingIssue::Error(msg) => errors.push(format!("bad timing config: {}", msg)),
+            TimingIssue::Warning(msg) => eprintln!("warning: timing config: {}", msg),
+        }
+    }
+    if errors.is_empty() {
+        Ok(())
+    } else {
+        Err(errors.join("\n"))
+    }
+}
+
+#[derive(Args, Debug, Clone)]
 pub struct SimLatencyArgs {
-    #[arg(long, default_value_t = 0)]
-    pub send_sim_latency_ms: u32,
+    /// Start from a named latency profile: lan, wifi, 4g, satellite or one from --net-profiles-file.
+    /// Any of the flags below override that value from the profile.
+    #[arg(long)]
+    pub net_profile: Option<String>,
+
+    /// Extra profiles for --net-profile, see networking/profiles.rs for the format
+    #[arg(long)]
+    pub net_profiles_file: Option<PathBuf>,
 
-    #[arg(long, default_value_t = 0)]
-    pub send_jitter_stddev_ms: u32,
+    #[arg(long)]
+    pub send_sim_latency_ms: Option<u32>,
 
-    #[arg(long, default_value_t = 0)]
-    pub recv_sim_latency_ms: u32,
+    #[arg(long)]
+    pub send_jitter_stddev_ms: Option<u32>,
 
-    #[arg(long, default_value_t = 0)]
-    pub recv_jitter_stddev_ms: u32,
+    /// Shape of the send jitter, normal (default), log-normal or exponential
+    #[arg(long, value_enum)]
+    pub send_jitter_distribution: Option<networking::JitterDistribution>,
+
+    /// Chance from 0 to 1 of dropping each sent packet
+    #[arg(long)]
+    pub send_loss: Option<f32>,
+
+    #[arg(long)]
+    pub recv_sim_latency_ms: Option<u32>,
+
+    #[arg(long)]
+    pub recv_jitter_stddev_ms: Option<u32>,
+
+    /// Shape of the receive jitter, normal (default), log-normal or exponential
+    #[arg(long, value_enum)]
+    pub recv_jitter_distribution: Option<networking::JitterDistribution>,
+
+    /// Chance from 0 to 1 of dropping each received packet
+    #[arg(long)]
+    pub recv_loss: Option<f32>,
+
+    /// Most received packets held back for sim latency at once
+    #[arg(long, default_value_t = networking::DEFAULT_MAX_SIM_DELAYED, value_parser = clap::value_parser!(u64).range(1..).map(|v| v as usize))]
+    pub recv_sim_queue_max: usize,
+
+    /// What to drop once --recv-sim-queue-max is reached
+    #[arg(long, value_enum, default_value_t = networking::SimQueueOverflow::DropOldest)]
+    pub recv_sim_queue_overflow: networking::SimQueueOverflow,
 }
 
-impl From<SimLatencyArgs> for networking::SimLatencySettings {
-    fn from(value: SimLatencyArgs) -> Self {
-        networking::SimLatencySettings {
-            send: networking::SimLatencySetting {
-                latency: networking::SimLatency {
-                    base_ms: value.send_sim_latency_ms,
-                    jitter_stddev_ms: value.send_jitter_stddev_ms
-                },
-                loss: networking::SimLoss {
-                    loss_chance: 0.0
-                }
-            },
-            receive: networking::SimLatencySetting {
-                latency: networking::SimLatency {
-                    base_ms: value.recv_sim_latency_ms,
-                    jitter_stddev_ms: value.recv_jitter_stddev_ms
-                },
-                loss: networking::SimLoss {
-                    loss_chance: 0.0
-                }
+impl SimLatencyArgs {
+    pub fn settings(&self) -> Result<networking::SimLatencySettings, String> {
+        let mut settings = match &self.net_profile {
+            Some(name) => networking::profiles::find_profile(name, self.net_profiles_file.as_deref())?,
+            None => networking::SimLatencySettings::default(),
+        };
+
+        let send = &mut settings.send;
+        send.latency.base_ms = self.send_sim_latency_ms.unwrap_or(send.latency.base_ms);
+        send.latency.jitter_stddev_ms = self.send_jitter_stddev_ms.unwrap_or(send.latency.jitter_stddev_ms);
+        send.latency.distribution = self.send_jitter_distribution.unwrap_or(send.latency.distribution);
+        send.loss.loss_chance = self.send_loss.unwrap_or(send.loss.loss_chance);
+
+        let receive = &mut settings.receive;
+        receive.latency.base_ms = self.recv_sim_latency_ms.unwrap_or(receive.latency.base_ms);
+        receive.latency.jitter_stddev_ms = self.recv_jitter_stddev_ms.unwrap_or(receive.latency.jitter_stddev_ms);
+        receive.latency.distribution = self.recv_jitter_distribution.unwrap_or(receive.latency.distribution);
+        receive.loss.loss_chance = self.recv_loss.unwrap_or(receive.loss.loss_chance);
+
+        settings.recv_queue = networking::SimQueueLimit {
+            max_len: self.recv_sim_queue_max,
+            overflow: self.recv_sim_queue_overflow,
+        };
+
+        for loss in [settings.send.loss.loss_chance, settings.receive.loss.loss_chance] {
+            if !(0.0..=1.0).contains(&loss) {
+                return Err(format!("loss chance {} isn't between 0 and 1", loss));
             }
         }
+        settings.send.latency.validate().map_err(|e| format!("send: {}", e))?;
+        settings.receive.latency.validate().map_err(|e| format!("receive: {}", e))?;
+        Ok(settings)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_server_packet_header_framing() {
+        let header = ServerPacketHeader {
+            last_applied_input: 0x01020304,
+            player_index: 5,
+            input_state: Some(NetInputState::Playing),
+            buffer_remaining_ms: 600,
+            input_delay_ms: 700,
+            send_interval_ticks: 3,
+        };
+        let mut buf = [0; HEADER_LEN + 2];
+        buf[HEADER_LEN..].copy_from_slice(&[8, 9]);
+        header.write(&mut buf);
+        let (parsed, packet) = ServerPacketHeader::parse(&buf).unwrap();
+        assert_eq!(parsed, header);
+        assert_eq!(packet, &[8, 9]);
+
+        assert_eq!(ServerPacketHeader::parse(&buf[..HEADER_LEN]).unwrap_err(), HeaderError::TooShort(HEADER_LEN));
+
+        buf[0] ^= 0xff;
+        let tag = WORLD_PACKET_HEADER_TAG ^ 0xff000000;
+        assert_eq!(ServerPacketHeader::parse(&buf).unwrap_err(), HeaderError::BadTag(tag));
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_off_center_paddle_hit_curves_ball() {
+        let paddle = Transform::from_xyz(0.0, -250.0, 0.0).with_scale(PADDLE_SIZE.extend(1.0));
+        assert_eq!(paddle_hit_spin(0.0, &paddle), 0.0);
+        assert_eq!(paddle_hit_spin(PADDLE_SIZE.x, &paddle), -PADDLE_EDGE_SPIN);
+
+        let mut spin = Spin(paddle_hit_spin(PADDLE_SIZE.x / 4.0, &paddle));
+        assert_eq!(spin.0, -paddle_hit_spin(-PADDLE_SIZE.x / 4.0, &paddle));
+        let mut velocity = Velocity(Vec2::new(0.0, BALL_SPEED));
+        for _ in 0..30 {
+            apply_spin(&mut velocity, &mut spin);
+        }
+
+        // Off the right half, so it bends right and keeps its speed
+        assert!(velocity.x > 0.0 && velocity.y > 0.0);
+        assert!((velocity.length() - BALL_SPEED).abs() < 0.1);
+        assert!(spin.0 < 0.0 && spin.0 > paddle_hit_spin(PADDLE_SIZE.x / 4.0, &paddle));
+    }
+
+    #[test]
+    fn test_jitter_measured_against_send_interval() {
+        let interval_s = TICK_S * 3.0;
+        let start = time::Instant::now();
+        let mut steady = JitterEstimator::default();
+        for i in 0..10 {
+            steady.record_arrival(start + time::Duration::from_secs_f64(interval_s * i as f64), interval_s);
+        }
+        assert!(steady.jitter_s().unwrap() < 1e-6);
+
+        // The same arrivals look like two ticks of jitter each if they were meant to be a tick apart
+        let mut per_tick = JitterEstimator::default();
+        for i in 0..10 {
+            per_tick.record_arrival(start + time::Duration::from_secs_f64(interval_s * i as f64), TICK_S);
+        }
+        assert!((per_tick.jitter_s().unwrap() - TICK_S * 2.0).abs() < 1e-6);
+    }
+}
@@ -0,0 +1,77 @@
+// Integer versions of the shared sim steps, used with the fixed_point_sim feature.
+// Positions and velocities are kept on a 1/SCALE px grid. f32 holds those values exactly at arena
+// sizes, so Transform and Velocity stay f32 while every step is integer math that comes out the
+// same on every platform.
+use bevy::math::Vec2;
+use crate::common::TICK_RATE_HZ;
+
+pub const SCALE: i64 = 256;
+const TICKS_PER_S: i64 = TICK_RATE_HZ as i64;
+
+pub fn to_fixed(v: f32) -> i64 {
+    // f32 -> f64 and the power of two multiply are exact, only the round picks a grid point
+    (v as f64 * SCALE as f64).round() as i64
+}
+
+pub fn from_fixed(v: i64) -> f32 {
+    v as f32 / SCALE as f32
+}
+
+pub fn snap(v: Vec2) -> Vec2 {
+    Vec2::new(from_fixed(to_fixed(v.x)), from_fixed(to_fixed(v.y)))
+}
+
+// Moves pos by one tick at units_per_s
+pub fn step(pos: f32, units_per_s: f32) -> f32 {
+    from_fixed(to_fixed(pos) + to_fixed(units_per_s) / TICKS_PER_S)
+}
+
+pub fn step_clamped(pos: f32, units_per_s: f32, min: f32, max: f32) -> f32 {
+    let next = to_fixed(pos) + to_fixed(units_per_s) / TICKS_PER_S;
+    from_fixed(next.clamp(to_fixed(min), to_fixed(max)))
+}
+
+// Offset from the closest point of the box to the ball's center, if they overlap
+pub fn ball_box_offset(center: Vec2, radius: f32, box_min: Vec2, box_max: Vec2) -> Option<(i64, i64)> {
+    let (cx, cy) = (to_fixed(center.x), to_fixed(center.y));
+    let closest_x = cx.clamp(to_fixed(box_min.x), to_fixed(box_max.x));
+    let closest_y = cy.clamp(to_fixed(box_min.y), to_fixed(box_max.y));
+    let (ox, oy) = (cx - closest_x, cy - closest_y);
+    let r = to_fixed(radius);
+    if ox * ox + oy * oy > r * r {
+        return None;
+    }
+    Some((ox, oy))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_grid_values_round_trip() {
+        for v in [-450.0, -299.99, 0.0, 0.1, 123.456, 600.0] {
+            let snapped = from_fixed(to_fixed(v));
+            assert_eq!(to_fixed(snapped), to_fixed(v));
+            assert_eq!(from_fixed(to_fixed(snapped)), snapped);
+        }
+    }
+
+    #[test]
+    fn test_step_stays_on_grid() {
+        let mut pos = 0.0;
+        for _ in 0..1000 {
+            pos = step(pos, 400.0);
+            assert_eq!(from_fixed(to_fixed(pos)), pos);
+        }
+        assert_eq!(to_fixed(pos), 1000 * (400 * SCALE / TICKS_PER_S));
+    }
+
+    #[test]
+    fn test_ball_box_offset() {
+        let min = Vec2::new(-10.0, -10.0);
+        let max = Vec2::new(10.0, 10.0);
+        assert_eq!(ball_box_offset(Vec2::new(0.0, 24.0), 15.0, min, max), Some((0, 14 * SCALE)));
+        assert_eq!(ball_box_offset(Vec2::new(0.0, 26.0), 15.0, min, max), None);
+    }
+}
@@ -0,0 +1,5 @@
+// The binaries compile these modules themselves, this only exists so benches and examples can reach the shared types
+pub mod common;
+#[cfg(feature = "fixed_point_sim")]
+pub mod fixed_point;
+pub mod networking;
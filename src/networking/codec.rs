@@ -0,0 +1,100 @@
+//! Optional deflate pass over whole datagrams, built with the `compression` feature. Every datagram
+//! gets a leading byte saying whether the rest is deflated, and it's only deflated when that comes
+//! out smaller, so pings and heartbeats never grow by more than that byte. Both sides have to be
+//! built with the same features, without it datagrams go out untouched.
+
+use std::borrow::Cow;
+use bytes::Bytes;
+
+#[cfg(feature = "compression")]
+const RAW: u8 = 0;
+#[cfg(feature = "compression")]
+const DEFLATED: u8 = 1;
+// Speed over ratio, this runs once per datagram per client
+#[cfg(feature = "compression")]
+const DEFLATE_LEVEL: u8 = 1;
+// Nothing we send inflates past an MTU, this just stops a small datagram from inflating into a huge one
+#[cfg(feature = "compression")]
+const MAX_INFLATED_LEN: usize = super::ETHERNET_MTU * 4;
+
+// Most encode ever adds to a datagram, anything that has to fit in an MTU leaves room for it
+#[cfg(feature = "compression")]
+pub const MAX_OVERHEAD: usize = 1;
+#[cfg(not(feature = "compression"))]
+pub const MAX_OVERHEAD: usize = 0;
+
+#[derive(Debug)]
+pub enum CodecError {
+    Empty,
+    UnknownFlag(u8),
+    Inflate,
+}
+
+#[cfg(feature = "compression")]
+pub fn encode(payload: &[u8]) -> Cow<'_, [u8]> {
+    let deflated = miniz_oxide::deflate::compress_to_vec(payload, DEFLATE_LEVEL);
+    let (flag, body) = if deflated.len() < payload.len() {
+        (DEFLATED, &deflated[..])
+    } else {
+        (RAW, payload)
+    };
+    let mut out = Vec::with_capacity(1 + body.len());
+    out.push(flag);
+    out.extend_from_slice(body);
+    Cow::Owned(out)
+}
+
+// Nothing to add, the datagram is the payload
+#[cfg(not(feature = "compression"))]
+pub fn encode(payload: &[u8]) -> Cow<'_, [u8]> {
+    Cow::Borrowed(payload)
+}
+
+#[cfg(feature = "compression")]
+pub fn decode(mut datagram: Bytes) -> Result<Bytes, CodecError> {
+    if datagram.is_empty() {
+        return Err(CodecError::Empty);
+    }
+    let flag = datagram[0];
+    let body = datagram.split_off(1);
+    match flag {
+        RAW => Ok(body),
+        DEFLATED => miniz_oxide::inflate::decompress_to_vec_with_limit(&body, MAX_INFLATED_LEN)
+            .map(Bytes::from)
+            .map_err(|_| CodecError::Inflate),
+        flag => Err(CodecError::UnknownFlag(flag)),
+    }
+}
+
+#[cfg(not(feature = "compression"))]
+pub fn decode(datagram: Bytes) -> Result<Bytes, CodecError> {
+    Ok(datagram)
+}
+
+#[cfg(all(test, feature = "compression"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_small_payload_not_deflated() {
+        let payload = [0xfe];
+        let encoded = encode(&payload);
+        assert_eq!(encoded.len(), payload.len() + 1);
+        assert_eq!(decode(Bytes::from(encoded.into_owned())).unwrap().as_ref(), &payload);
+    }
+
+    #[test]
+    fn test_repetitive_payload_deflated() {
+        let payload = [7u8; 1000];
+        let encoded = encode(&payload);
+        assert!(encoded.len() < payload.len());
+        assert_eq!(decode(Bytes::from(encoded.into_owned())).unwrap().as_ref(), &payload);
+    }
+
+    #[test]
+    fn test_decode_errors() {
+        assert!(matches!(decode(Bytes::new()), Err(CodecError::Empty)));
+        assert!(matches!(decode(Bytes::from_static(&[9, 1, 2])), Err(CodecError::UnknownFlag(9))));
+        assert!(matches!(decode(Bytes::from_static(&[DEFLATED, 0xff, 0xff])), Err(CodecError::Inflate)));
+    }
+}
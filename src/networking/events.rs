@@ -15,6 +15,9 @@ pub enum NetworkEvent {
     // A client has disconnected from us
     #[allow(dead_code)]
     Disconnected(SocketAddr),
+    // A client went quiet past the idle timeout, with how long it had been idle
+    #[allow(dead_code)]
+    TimedOut(SocketAddr, time::Duration),
     // An error occurred while receiving a message
     #[allow(dead_code)]
     RecvError(io::Error),
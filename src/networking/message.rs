@@ -6,6 +6,12 @@ pub struct Message {
     pub destination: SocketAddr,
     /// The serialized payload itself.
     pub payload: Bytes,
+    /// Critical messages skip the byte budget, set on everything from `send_immediate`.
+    pub critical: bool,
+    /// How many times sending has failed with a transient error and been put back.
+    pub retries: u8,
+    /// The drain pass it was queued for. Pacing keeps messages from the same pass together.
+    pub pass: u64,
 }
 
 impl Message {
@@ -14,6 +20,9 @@ impl Message {
         Self {
             destination,
             payload: Bytes::copy_from_slice(payload),
+            critical: false,
+            retries: 0,
+            pass: 0,
             //send_time
         }
     }
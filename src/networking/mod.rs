@@ -1,11 +1,15 @@
+pub mod codec;
 pub mod events;
 mod message;
+pub mod profiles;
 pub mod systems;
 pub mod transport;
 
 use std::collections::{HashMap, VecDeque};
 use std::ffi::c_void;
+use std::io;
 use std::net::{SocketAddr, UdpSocket};
+use std::sync::Arc;
 use std::time::Duration;
 
 pub use self::events::NetworkEvent;
@@ -14,19 +18,20 @@ pub use self::events::NetworkEvent;
 pub use self::transport::Transport;
 
 use bevy::prelude::*;
+use byteorder::ByteOrder;
 use windows::Win32::Foundation;
 use windows::Win32::Networking::WinSock;
 use std::os::windows::io::AsRawSocket;
 use std::time;
 use rand::Rng;
-use rand_distr::{Normal, Distribution};
+use rand_distr::{Distribution, Exp, LogNormal, Normal};
 
 /// Defines how many times a client automatically sends a heartbeat packet.
 /// This should be no more than half of idle_timeout.
 pub const DEFAULT_HEARTBEAT_TICK_RATE_SECS: f32 = 2.;
 /// Defines how long the server will wait until it sends
-/// NetworkEvent::Disconnected
-const DEFAULT_IDLE_TIMEOUT_SECS: f32 = 5.;
+/// NetworkEvent::TimedOut
+pub const DEFAULT_IDLE_TIMEOUT_SECS: f32 = 5.;
 
 pub const ETHERNET_MTU: usize = 1500;
 
@@ -34,29 +39,193 @@ pub const ETHERNET_MTU: usize = 1500;
 pub struct NetworkResource {
     // Hashmap of each live connection and their last known packet activity
     pub connections: HashMap<SocketAddr, Duration>,
-    pub idle_timeout: Duration,
+    // None never times anyone out, connections only end on a disconnect
+    pub idle_timeout: Option<Duration>,
+    // Only for peers sending nonce heartbeats
+    pub heartbeats: HashMap<SocketAddr, HeartbeatStats>,
+    pub decode_errors: DecodeErrorStats,
+}
+
+/// First byte of every heartbeat, so heartbeats don't need to be told apart by being empty and
+/// empty datagrams can be real messages. Client packets are bincode and start with a small enum
+/// index, server packets with WORLD_PACKET_HEADER_TAG, neither can start with this.
+pub const HEARTBEAT_TAG: u8 = 0xfe;
+/// A heartbeat with nothing else in it.
+pub const HEARTBEAT_PACKET: [u8; 1] = [HEARTBEAT_TAG];
+pub const NONCE_HEARTBEAT_LEN: usize = HEARTBEAT_PACKET.len() + size_of::<u32>() * 2;
+
+pub fn is_heartbeat(datagram: &[u8]) -> bool {
+    datagram.first() == Some(&HEARTBEAT_TAG)
+}
+
+/// A heartbeat that also carries a nonce and send time. Clients only send these with
+/// `ClientPlugin::nonce_heartbeats`, otherwise they send HEARTBEAT_PACKET.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Heartbeat {
+    /// Goes up by one every heartbeat
+    pub nonce: u32,
+    /// Sender's clock when it was sent, only meaningful compared to other heartbeats from it
+    pub sent_ms: u32,
+}
+
+impl Heartbeat {
+    pub fn encode(&self) -> [u8; NONCE_HEARTBEAT_LEN] {
+        let mut buf = [0; NONCE_HEARTBEAT_LEN];
+        buf[0] = HEARTBEAT_TAG;
+        byteorder::NetworkEndian::write_u32(&mut buf[1..], self.nonce);
+        byteorder::NetworkEndian::write_u32(&mut buf[1 + size_of::<u32>()..], self.sent_ms);
+        buf
+    }
+
+    /// None for plain heartbeats and anything that isn't a heartbeat.
+    pub fn decode(datagram: &[u8]) -> Option<Heartbeat> {
+        if datagram.len() != NONCE_HEARTBEAT_LEN || !is_heartbeat(datagram) {
+            return None;
+        }
+        Some(Heartbeat {
+            nonce: byteorder::NetworkEndian::read_u32(&datagram[1..]),
+            sent_ms: byteorder::NetworkEndian::read_u32(&datagram[1 + size_of::<u32>()..]),
+        })
+    }
+}
+
+#[derive(Default, Clone, Copy, Debug)]
+pub struct HeartbeatStats {
+    pub last_nonce: Option<u32>,
+    pub received: u32,
+    /// Duplicates and ones that arrived after a newer heartbeat
+    pub stale: u32,
+    /// How much longer than the fastest heartbeat so far the last one took to get here. Clocks
+    /// aren't synced so the one way time itself is unknown, but its growth is queueing.
+    pub one_way_excess: Option<Duration>,
+    min_offset_ms: Option<i64>,
+}
+
+impl HeartbeatStats {
+    /// Returns false if the heartbeat was stale.
+    pub fn record(&mut self, heartbeat: Heartbeat, arrival: Duration) -> bool {
+        if self.last_nonce.is_some_and(|last| heartbeat.nonce <= last) {
+            self.stale += 1;
+            return false;
+        }
+        self.last_nonce = Some(heartbeat.nonce);
+        self.received += 1;
+
+        let offset_ms = arrival.as_millis() as i64 - heartbeat.sent_ms as i64;
+        let min_offset_ms = self.min_offset_ms.map_or(offset_ms, |m| m.min(offset_ms));
+        self.min_offset_ms = Some(min_offset_ms);
+        self.one_way_excess = Some(Duration::from_millis((offset_ms - min_offset_ms) as u64));
+        true
+    }
+}
+
+/// When present, `auto_heartbeat_system` sends nonce heartbeats instead of empty ones.
+#[derive(Resource, Default)]
+pub struct NonceHeartbeats {
+    pub next_nonce: u32,
+}
+
+/// Most received events held back for sim latency at once. Only reached with delays of seconds
+/// under heavy traffic, normal settings hold a handful.
+pub const DEFAULT_MAX_SIM_DELAYED: usize = 4096;
+
+/// What to drop once the sim latency receive queue is full.
+#[derive(Default, Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum SimQueueOverflow {
+    /// Drop whatever has been waiting longest, later traffic keeps flowing
+    #[default]
+    DropOldest,
+    /// Turn away new arrivals until the queue drains, like a full router buffer
+    DropNewest,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct SimQueueLimit {
+    pub max_len: usize,
+    pub overflow: SimQueueOverflow,
+}
+
+impl Default for SimQueueLimit {
+    fn default() -> Self {
+        Self { max_len: DEFAULT_MAX_SIM_DELAYED, overflow: SimQueueOverflow::default() }
+    }
 }
 
 #[derive(Resource, Default)]
 pub struct SimLatencyReceiveQueue {
     pub sim_latency_delayed: VecDeque<NetworkEvent>,
     pub sim_latency_delivery_times: VecDeque<time::Instant>,
+    pub limit: SimQueueLimit,
+    pub dropped_on_overflow: u64,
+}
+
+/// Packets currently held back by sim latency in each direction, and how many each direction has
+/// dropped for being full. Send is capped by the transport's queue limit, receive by
+/// `SimQueueLimit`. Refreshed by `send_packet_system` at the end of every pass.
+#[derive(Resource, Default, Debug, Clone, Copy)]
+pub struct SimLatencyInFlight {
+    pub send: usize,
+    pub receive: usize,
+    pub send_dropped_on_overflow: u64,
+    pub receive_dropped_on_overflow: u64,
+}
+
+impl SimLatencyReceiveQueue {
+    pub fn new(limit: SimQueueLimit) -> Self {
+        Self { limit, ..default() }
+    }
+
+    pub fn push(&mut self, event: NetworkEvent, delivery_time: time::Instant) {
+        if self.sim_latency_delayed.len() >= self.limit.max_len {
+            if self.dropped_on_overflow == 0 {
+                warn!("Sim latency queue over {} events, {:?}", self.limit.max_len, self.limit.overflow);
+            }
+            self.dropped_on_overflow += 1;
+            match self.limit.overflow {
+                SimQueueOverflow::DropNewest => return,
+                SimQueueOverflow::DropOldest => {
+                    // Events and delivery times are matched up by index on delivery, so popping the
+                    // front of both keeps them paired
+                    self.sim_latency_delayed.pop_front();
+                    self.sim_latency_delivery_times.pop_front();
+                }
+            }
+        }
+
+        self.sim_latency_delayed.push_back(event);
+        let pos = self.sim_latency_delivery_times.binary_search(&delivery_time).unwrap_or_else(|p| p);
+        self.sim_latency_delivery_times.insert(pos, delivery_time);
+    }
 }
 
 impl Default for NetworkResource {
     fn default() -> Self {
         Self {
             connections: Default::default(),
-            idle_timeout: Duration::from_secs_f32(DEFAULT_IDLE_TIMEOUT_SECS),
+            idle_timeout: Some(Duration::from_secs_f32(DEFAULT_IDLE_TIMEOUT_SECS)),
+            heartbeats: Default::default(),
+            decode_errors: Default::default(),
         }
     }
 }
 
+impl NetworkResource {
+    /// Drops everything kept for a peer so its next packet is a fresh connect. Timeouts, kicks
+    /// and explicit disconnects all go through here so no per-peer state outlives the peer.
+    pub fn forget_peer(&mut self, peer: &SocketAddr, socket: &mut ResUdpSocket) {
+        self.connections.remove(peer);
+        self.heartbeats.remove(peer);
+        self.decode_errors.forget(peer);
+        socket.forget_peer(peer);
+    }
+}
+
 /// Label for network related systems.
 #[derive(Clone, Hash, Debug, PartialEq, Eq, SystemSet)]
 pub enum NetworkSystem {
     Receive,
     Send,
+    FlushOnExit,
 }
 
 /// Label for server specific systems.
@@ -75,7 +244,42 @@ pub enum ClientSystem {
 #[derive(Default, Clone)]
 pub struct SimLatency {
     pub base_ms: u32,
-    pub jitter_stddev_ms: u32
+    pub jitter_stddev_ms: u32,
+    pub distribution: JitterDistribution
+}
+
+/// Shape of the jitter on top of base_ms. Normal spreads both ways, the others only ever add delay
+/// and have a long tail, closer to real links spiking under load.
+#[derive(Default, Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum JitterDistribution {
+    /// Normal around base_ms with jitter_stddev_ms as the standard deviation
+    #[default]
+    Normal,
+    /// Log-normal with base_ms as the mean and jitter_stddev_ms as the standard deviation, skewed late
+    LogNormal,
+    /// base_ms plus an exponential tail averaging jitter_stddev_ms
+    Exponential,
+}
+
+impl SimLatency {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.distribution == JitterDistribution::LogNormal && self.jitter_stddev_ms > 0 && self.base_ms == 0 {
+            return Err("log-normal jitter needs a non-zero base latency, it's the distribution's mean".to_string());
+        }
+        Ok(())
+    }
+
+    fn sample_ms(&self, rng: &mut impl Rng) -> f64 {
+        let (base, stddev) = (self.base_ms as f64, self.jitter_stddev_ms as f64);
+        if stddev == 0.0 {
+            return base;
+        }
+        match self.distribution {
+            JitterDistribution::Normal => Normal::new(base, stddev).unwrap().sample(rng),
+            JitterDistribution::LogNormal => LogNormal::from_mean_cv(base, stddev / base).unwrap().sample(rng),
+            JitterDistribution::Exponential => base + Exp::new(1.0 / stddev).unwrap().sample(rng),
+        }
+    }
 }
 
 #[derive(Default, Clone)]
@@ -115,8 +319,7 @@ impl SimLatencySetting {
 
         let now = time::Instant::now();
         if self.latency.jitter_stddev_ms > 0 || self.latency.base_ms > 0 {
-            let normal = Normal::new(self.latency.base_ms as f64, self.latency.jitter_stddev_ms as f64).unwrap();
-            let value = normal.sample(rng);
+            let value = self.latency.sample_ms(rng);
             if value > 0.0 {
                 return SimLatencyRollResult::Delay(now + time::Duration::from_millis(value as u64));
             } else {
@@ -132,20 +335,64 @@ impl SimLatencySetting {
 pub struct SimLatencySettings {
     pub send: SimLatencySetting,
     pub receive: SimLatencySetting,
+    pub recv_queue: SimQueueLimit,
 }
 
+/// Called with every raw datagram as it comes off the socket, before heartbeats are filtered out
+/// and before sim latency delays or drops it. Meant for protocol analyzers and traffic logging.
+pub type DatagramObserver = Arc<dyn Fn(SocketAddr, &[u8], time::Instant) + Send + Sync>;
+
+/// Only inserted when a plugin was given observers, so the recv systems skip all of this otherwise.
+#[derive(Resource)]
+pub struct DatagramObservers(pub Vec<DatagramObserver>);
+
+impl DatagramObservers {
+    fn insert_if_any(app: &mut App, observers: &[DatagramObserver]) {
+        if !observers.is_empty() {
+            app.insert_resource(DatagramObservers(observers.to_vec()));
+        }
+    }
+
+    pub fn notify(&self, address: SocketAddr, datagram: &[u8], recv_time: time::Instant) {
+        for observer in &self.0 {
+            observer(address, datagram, recv_time);
+        }
+    }
+}
+
+/// When present, every datagram sent or received is logged as a hex dump at trace level.
+#[derive(Resource)]
+pub struct PacketLogging;
+
 #[derive(Default)]
 pub struct ServerPlugin {
     pub sim_settings: SimLatencySettings,
-    pub no_systems: bool
+    pub no_systems: bool,
+    pub datagram_observers: Vec<DatagramObserver>,
+    pub log_packets: bool,
+    pub client_send_budget: Option<u64>, // bytes per transport::BUDGET_WINDOW to each client
+    pub no_idle_timeout: bool, // For debugging clients, a paused one is never timed out
+    pub send_pacing: Option<Duration> // see Transport::with_pacing
 }
 impl Plugin for ServerPlugin {
     fn build(&self, app: &mut App) {
-        app.insert_resource(NetworkResource::default())
-            .insert_resource(transport::Transport::new(self.sim_settings.send.clone()))
+        DatagramObservers::insert_if_any(app, &self.datagram_observers);
+        if self.log_packets {
+            app.insert_resource(PacketLogging);
+        }
+        let mut net = NetworkResource::default();
+        if self.no_idle_timeout {
+            net.idle_timeout = None;
+        }
+        app.insert_resource(net)
+            .insert_resource(transport::Transport::new(self.sim_settings.send.clone())
+                .with_destination_budget(self.client_send_budget)
+                .with_pacing(self.send_pacing))
             .insert_resource(self.sim_settings.clone())
-            .insert_resource(SimLatencyReceiveQueue::default())
-            .add_event::<events::NetworkEvent>();
+            .insert_resource(SimLatencyReceiveQueue::new(self.sim_settings.recv_queue))
+            .insert_resource(SimLatencyInFlight::default())
+            .add_event::<events::NetworkEvent>()
+            .add_systems(Last, systems::flush_on_exit_system.in_set(NetworkSystem::FlushOnExit));
 
         if !self.no_systems {
             app.add_systems(
@@ -163,34 +410,141 @@ impl Plugin for ServerPlugin {
 #[derive(Resource)]
 pub struct HeartbeatTimer(pub Timer);
 
+/// Most sends we wait on for an ack at once, older ones are forgotten.
+const MAX_PENDING_ACKS: usize = 256;
+
+/// Datagrams game code couldn't make sense of. A spike usually means a client on another protocol
+/// version or someone poking at the port. The recv systems can't tell, decoding happens in the
+/// game's connection handlers, so they record here.
+#[derive(Default, Debug)]
+pub struct DecodeErrorStats {
+    pub total: u64,
+    pub total_bytes: u64,
+    pub by_addr: HashMap<SocketAddr, u64>,
+    /// Sender, byte length and when, for the latest one
+    pub last: Option<(SocketAddr, usize, time::Instant)>,
+}
+
+impl DecodeErrorStats {
+    pub fn record(&mut self, address: SocketAddr, len: usize) {
+        self.total += 1;
+        self.total_bytes += len as u64;
+        *self.by_addr.entry(address).or_default() += 1;
+        self.last = Some((address, len, time::Instant::now()));
+    }
+
+    /// Call when a peer goes away so by_addr doesn't grow forever on a public server.
+    pub fn forget(&mut self, address: &SocketAddr) {
+        self.by_addr.remove(address);
+    }
+}
+
+/// Latency measured passively from normal traffic: the time from a sequenced message being sent
+/// until the other side acks that sequence. Unlike a ping this includes however long the other
+/// side holds the message before acking it (e.g. the server's input buffer).
+#[derive(Resource, Default)]
+pub struct NetStats {
+    pub last_ack_latency: Option<Duration>,
+    /// Smoothed the same way TCP smooths RTT, 1/8 weight for each new sample.
+    pub smoothed_ack_latency: Option<Duration>,
+    pub ack_samples: u32,
+    pub decode_errors: DecodeErrorStats,
+    pending: VecDeque<(u32, time::Instant)>,
+}
+
+impl NetStats {
+    /// Sequences must go up.
+    pub fn record_send(&mut self, sequence: u32, send_time: time::Instant) {
+        self.pending.push_back((sequence, send_time));
+        while self.pending.len() > MAX_PENDING_ACKS {
+            self.pending.pop_front();
+        }
+    }
+
+    /// Acks are cumulative, everything through `sequence` is done. Only the acked sequence itself
+    /// is sampled, earlier ones were just waiting behind it.
+    pub fn record_ack(&mut self, sequence: u32, ack_time: time::Instant) {
+        let mut sample = None;
+        while let Some(&(pending_sequence, send_time)) = self.pending.front() {
+            if pending_sequence > sequence {
+                break;
+            }
+            self.pending.pop_front();
+            if pending_sequence == sequence {
+                sample = Some(ack_time.saturating_duration_since(send_time));
+            }
+        }
+
+        if let Some(latency) = sample {
+            self.last_ack_latency = Some(latency);
+            self.smoothed_ack_latency = Some(match self.smoothed_ack_latency {
+                Some(smoothed) => (smoothed * 7 + latency) / 8,
+                None => latency,
+            });
+            self.ack_samples += 1;
+        }
+    }
+}
+
 #[derive(Default)]
 pub struct ClientPlugin {
     pub sim_settings: SimLatencySettings,
-    pub no_systems: bool
+    pub no_systems: bool,
+    pub datagram_observers: Vec<DatagramObserver>,
+    pub log_packets: bool,
+    pub nonce_heartbeats: bool
+}
+
+/// How `ResUdpSocket::recv_from` waits for data.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum RecvMode {
+    /// Return `WouldBlock` right away when nothing is waiting. The game loop polls every tick, so
+    /// this is what the binaries use.
+    #[default]
+    NonBlocking,
+    /// Wait up to this long (per socket) for a datagram before returning `WouldBlock`. For test
+    /// harnesses that want to block instead of spinning. The recv systems drain until
+    /// `WouldBlock`, so every drain ends by waiting out one timeout.
+    Blocking(Duration),
 }
 
+/// One or more UDP sockets, non-blocking unless created with `RecvMode::Blocking`. Servers can bind
+/// several (e.g. an IPv4 and an IPv6 address), receives poll all of them and replies go out the
+/// socket the peer was last heard on.
 #[derive(Resource)]
-pub struct ResUdpSocket(pub UdpSocket);
+pub struct ResUdpSocket {
+    sockets: Vec<UdpSocket>,
+    peer_sockets: HashMap<SocketAddr, usize>,
+    recv_mode: RecvMode,
+}
 
 impl ResUdpSocket {
-    fn new(bind_addr: &str, remote_addr: Option<SocketAddr>) -> Self {
-        let socket = ResUdpSocket(UdpSocket::bind(bind_addr).expect("could not bind socket"));
-        //info!("UdpSocket bound to {}", socket.0.local_addr().unwrap());
+    fn bind(bind_addr: &str, remote_addr: Option<SocketAddr>, recv_mode: RecvMode) -> UdpSocket {
+        let socket = UdpSocket::bind(bind_addr).expect("could not bind socket");
+        //info!("UdpSocket bound to {}", socket.local_addr().unwrap());
         if let Some(r) = remote_addr {
-            socket.0
+            socket
                 .connect(r)
                 .expect("could not connect to server");
         }
-        socket.0
-            .set_nonblocking(true)
-            .expect("could not set socket to be nonblocking");
+        match recv_mode {
+            RecvMode::NonBlocking => socket
+                .set_nonblocking(true)
+                .expect("could not set socket to be nonblocking"),
+            RecvMode::Blocking(timeout) => {
+                assert!(!timeout.is_zero(), "blocking recv needs a non-zero timeout");
+                socket
+                    .set_read_timeout(Some(timeout))
+                    .expect("could not set socket read timeout");
+            }
+        }
 
         // We don't want windows to spam us with recv errors if a remote port is closed...
         // That spams logs and chokes the API, and is useless since we don't know which
         // client it's from anyways
         // SEE: https://github.com/mas-bandwidth/yojimbo/blob/b881662d72f21a171639fc6079052ce776cc9b2c/netcode/netcode.c#L519
         if cfg!(windows) {
-            let win_socket = WinSock::SOCKET(socket.0.as_raw_socket().try_into().unwrap());
+            let win_socket = WinSock::SOCKET(socket.as_raw_socket().try_into().unwrap());
             let value: Foundation::BOOL = false.into();
             let value_ptr: Option<*const c_void> = Some(&value as *const _ as *const c_void);
             let mut bytes_returned: u32 = 0;
@@ -218,28 +572,117 @@ impl ResUdpSocket {
 
     #[allow(dead_code)]
     pub fn new_client(remote_addr: SocketAddr) -> Self {
-        Self::new("0.0.0.0:0", Some(remote_addr))
+        Self::new_client_with_mode(remote_addr, RecvMode::default())
+    }
+
+    #[allow(dead_code)]
+    pub fn new_client_with_mode(remote_addr: SocketAddr, recv_mode: RecvMode) -> Self {
+        // Has to match the server's address family or connect fails
+        let bind_addr = if remote_addr.is_ipv6() { "[::]:0" } else { "0.0.0.0:0" };
+        ResUdpSocket {
+            sockets: vec![Self::bind(bind_addr, Some(remote_addr), recv_mode)],
+            peer_sockets: HashMap::new(),
+            recv_mode,
+        }
+    }
+
+    #[allow(dead_code)]
+    pub fn new_server(local_binds: &[String]) -> Self {
+        Self::new_server_with_mode(local_binds, RecvMode::default())
     }
 
     #[allow(dead_code)]
-    pub fn new_server(local_bind: &str) -> Self {
-        Self::new(local_bind, None)
+    pub fn new_server_with_mode(local_binds: &[String], recv_mode: RecvMode) -> Self {
+        assert!(!local_binds.is_empty(), "server needs at least one bind address");
+        ResUdpSocket {
+            sockets: local_binds.iter().map(|b| Self::bind(b, None, recv_mode)).collect(),
+            peer_sockets: HashMap::new(),
+            recv_mode,
+        }
+    }
+
+    #[allow(dead_code)]
+    pub fn recv_mode(&self) -> RecvMode {
+        self.recv_mode
+    }
+
+    /// Errors that only mean the OS had no room for the datagram right now, e.g. a burst filled the
+    /// send buffer. Sending again later can work, unlike an unreachable or closed peer.
+    pub fn is_transient_send_error(e: &io::Error) -> bool {
+        matches!(e.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::Interrupted | io::ErrorKind::TimedOut) ||
+            e.raw_os_error() == Some(WinSock::WSAENOBUFS.0)
+    }
+
+    /// Receives from the first socket with a datagram waiting. Returns `WouldBlock` once every
+    /// socket is drained, in blocking mode too.
+    pub fn recv_from(&mut self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        for (i, socket) in self.sockets.iter().enumerate() {
+            match socket.recv_from(buf) {
+                Ok((recv_len, address)) => {
+                    if self.sockets.len() > 1 {
+                        self.peer_sockets.insert(address, i);
+                    }
+                    return Ok((recv_len, address));
+                }
+                // A read timeout shows up as WouldBlock on unix and TimedOut on windows
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::TimedOut => continue,
+                Err(e) => return Err(e),
+            }
+        }
+        Err(io::ErrorKind::WouldBlock.into())
+    }
+
+    pub fn send_to(&self, buf: &[u8], destination: SocketAddr) -> io::Result<usize> {
+        self.socket_for(destination).send_to(buf, destination)
+    }
+
+    #[allow(dead_code)]
+    pub fn local_addrs(&self) -> Vec<SocketAddr> {
+        self.sockets.iter().filter_map(|s| s.local_addr().ok()).collect()
+    }
+
+    /// Forgets which socket a peer was using, call when it disconnects.
+    #[allow(dead_code)]
+    pub fn forget_peer(&mut self, peer: &SocketAddr) {
+        self.peer_sockets.remove(peer);
+    }
+
+    fn socket_for(&self, destination: SocketAddr) -> &UdpSocket {
+        if let Some(&i) = self.peer_sockets.get(&destination) {
+            return &self.sockets[i];
+        }
+
+        // Never heard from them, at least pick a socket of the same address family
+        self.sockets
+            .iter()
+            .find(|s| s.local_addr().is_ok_and(|a| a.is_ipv4() == destination.is_ipv4()))
+            .unwrap_or(&self.sockets[0])
     }
 }
 
 #[derive(Resource)]
-pub struct ResSocketAddr(pub(crate) SocketAddr);
+pub struct ResSocketAddr(pub SocketAddr);
 
 impl Plugin for ClientPlugin {
     fn build(&self, app: &mut App) {
+        DatagramObservers::insert_if_any(app, &self.datagram_observers);
+        if self.log_packets {
+            app.insert_resource(PacketLogging);
+        }
+        if self.nonce_heartbeats {
+            app.insert_resource(NonceHeartbeats::default());
+        }
         app.insert_resource(transport::Transport::new(self.sim_settings.send.clone())) // copy send settings for ease of use
             .insert_resource(self.sim_settings.clone())
             .insert_resource(HeartbeatTimer(Timer::from_seconds(
                 DEFAULT_HEARTBEAT_TICK_RATE_SECS,
                 TimerMode::Repeating,
             )))
-            .insert_resource(SimLatencyReceiveQueue::default())
-            .add_event::<events::NetworkEvent>();
+            .insert_resource(SimLatencyReceiveQueue::new(self.sim_settings.recv_queue))
+            .insert_resource(SimLatencyInFlight::default())
+            .insert_resource(NetStats::default())
+            .add_event::<events::NetworkEvent>()
+            .add_systems(Last, systems::flush_on_exit_system.in_set(NetworkSystem::FlushOnExit));
 
         if !self.no_systems {
             app.add_systems(
@@ -252,4 +695,155 @@ impl Plugin for ClientPlugin {
             );
         }
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_event(port: u16) -> NetworkEvent {
+        NetworkEvent::Connected(SocketAddr::from(([127, 0, 0, 1], port)))
+    }
+
+    fn queued_ports(queue: &SimLatencyReceiveQueue) -> Vec<u16> {
+        queue.sim_latency_delayed.iter().map(|e| match e {
+            NetworkEvent::Connected(addr) => addr.port(),
+            _ => unreachable!(),
+        }).collect()
+    }
+
+    #[test]
+    fn test_sim_queue_drop_oldest() {
+        let mut queue = SimLatencyReceiveQueue::new(SimQueueLimit { max_len: 2, overflow: SimQueueOverflow::DropOldest });
+        let now = time::Instant::now();
+        for port in 1..=4 {
+            queue.push(test_event(port), now + Duration::from_secs(2));
+        }
+        assert_eq!(queued_ports(&queue), vec![3, 4]);
+        assert_eq!(queue.sim_latency_delivery_times.len(), 2);
+        assert_eq!(queue.dropped_on_overflow, 2);
+    }
+
+    #[test]
+    fn test_sim_queue_drop_newest() {
+        let mut queue = SimLatencyReceiveQueue::new(SimQueueLimit { max_len: 2, overflow: SimQueueOverflow::DropNewest });
+        let now = time::Instant::now();
+        for port in 1..=4 {
+            queue.push(test_event(port), now + Duration::from_secs(2));
+        }
+        assert_eq!(queued_ports(&queue), vec![1, 2]);
+        assert_eq!(queue.sim_latency_delivery_times.len(), 2);
+        assert_eq!(queue.dropped_on_overflow, 2);
+    }
+
+    #[test]
+    fn test_tail_distributions_only_add_delay() {
+        let rng = &mut rand::thread_rng();
+        for distribution in [JitterDistribution::LogNormal, JitterDistribution::Exponential] {
+            let latency = SimLatency { base_ms: 50, jitter_stddev_ms: 20, distribution };
+            assert!(latency.validate().is_ok());
+            let samples: Vec<f64> = (0..1000).map(|_| latency.sample_ms(rng)).collect();
+            assert!(samples.iter().all(|&s| s > 0.0), "{:?}", distribution);
+            if distribution == JitterDistribution::Exponential {
+                assert!(samples.iter().all(|&s| s >= 50.0));
+            }
+        }
+
+        let no_mean = SimLatency { base_ms: 0, jitter_stddev_ms: 20, distribution: JitterDistribution::LogNormal };
+        assert!(no_mean.validate().is_err());
+    }
+
+    #[test]
+    fn test_heartbeat_round_trip() {
+        let heartbeat = Heartbeat { nonce: 7, sent_ms: 123_456 };
+        assert_eq!(Heartbeat::decode(&heartbeat.encode()), Some(heartbeat));
+        assert!(is_heartbeat(&heartbeat.encode()));
+        assert_eq!(Heartbeat::decode(&HEARTBEAT_PACKET), None);
+        assert!(is_heartbeat(&HEARTBEAT_PACKET));
+        assert_eq!(Heartbeat::decode(&heartbeat.encode()[1..]), None);
+        assert!(!is_heartbeat(&[]));
+    }
+
+    #[test]
+    fn test_heartbeat_stats() {
+        let mut stats = HeartbeatStats::default();
+        assert!(stats.record(Heartbeat { nonce: 0, sent_ms: 1000 }, Duration::from_millis(5050)));
+        assert_eq!(stats.one_way_excess, Some(Duration::ZERO));
+        assert!(stats.record(Heartbeat { nonce: 1, sent_ms: 3000 }, Duration::from_millis(7080)));
+        assert_eq!(stats.one_way_excess, Some(Duration::from_millis(30)));
+
+        // duplicate, then one that got passed by nonce 1
+        assert!(!stats.record(Heartbeat { nonce: 1, sent_ms: 3000 }, Duration::from_millis(7090)));
+        assert!(!stats.record(Heartbeat { nonce: 0, sent_ms: 1000 }, Duration::from_millis(7100)));
+        assert_eq!(stats.received, 2);
+        assert_eq!(stats.stale, 2);
+    }
+
+    #[test]
+    fn test_ipv6_loopback() {
+        let mut server = ResUdpSocket::new_server(&["[::1]:0".to_string()]);
+        let server_addr = server.local_addrs()[0];
+        let mut client = ResUdpSocket::new_client(server_addr);
+
+        client.send_to(b"ping", server_addr).unwrap();
+        let mut buf = [0; ETHERNET_MTU];
+        let (recv_len, client_addr) = recv_with_retry(&mut server, &mut buf);
+        assert_eq!(&buf[..recv_len], b"ping");
+        assert!(client_addr.is_ipv6());
+
+        server.send_to(b"pong", client_addr).unwrap();
+        let (recv_len, from) = recv_with_retry(&mut client, &mut buf);
+        assert_eq!(&buf[..recv_len], b"pong");
+        assert_eq!(from, server_addr);
+    }
+
+    #[test]
+    fn test_net_stats_samples_acked_sequence() {
+        let mut stats = NetStats::default();
+        let start = time::Instant::now();
+        stats.record_send(1, start);
+        stats.record_send(2, start + Duration::from_millis(10));
+        stats.record_send(3, start + Duration::from_millis(20));
+
+        stats.record_ack(2, start + Duration::from_millis(60));
+        assert_eq!(stats.last_ack_latency, Some(Duration::from_millis(50)));
+        assert_eq!(stats.ack_samples, 1);
+
+        // Repeated acks for the same sequence don't add samples
+        stats.record_ack(2, start + Duration::from_millis(80));
+        assert_eq!(stats.ack_samples, 1);
+
+        stats.record_ack(3, start + Duration::from_millis(100));
+        assert_eq!(stats.last_ack_latency, Some(Duration::from_millis(80)));
+        assert_eq!(stats.smoothed_ack_latency, Some((Duration::from_millis(50) * 7 + Duration::from_millis(80)) / 8));
+    }
+
+    #[test]
+    fn test_blocking_recv_times_out() {
+        let timeout = Duration::from_millis(50);
+        let mut server = ResUdpSocket::new_server_with_mode(&["127.0.0.1:0".to_string()], RecvMode::Blocking(timeout));
+        let server_addr = server.local_addrs()[0];
+        let client = ResUdpSocket::new_client(server_addr);
+        let mut buf = [0; ETHERNET_MTU];
+
+        let start = time::Instant::now();
+        let err = server.recv_from(&mut buf).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::WouldBlock);
+        // Timer resolution can cut the wait slightly short, it just has to have actually waited
+        assert!(start.elapsed() >= timeout / 2);
+
+        client.send_to(b"ping", server_addr).unwrap();
+        let (recv_len, _) = server.recv_from(&mut buf).unwrap();
+        assert_eq!(&buf[..recv_len], b"ping");
+    }
+
+    fn recv_with_retry(socket: &mut ResUdpSocket, buf: &mut [u8]) -> (usize, SocketAddr) {
+        for _ in 0..100 {
+            match socket.recv_from(buf) {
+                Ok(r) => return r,
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => std::thread::sleep(Duration::from_millis(10)),
+                Err(e) => panic!("recv failed: {:?}", e),
+            }
+        }
+        panic!("timed out waiting for datagram");
+    }
+}
@@ -0,0 +1,160 @@
+use std::collections::HashMap;
+use std::path::Path;
+
+use clap::ValueEnum;
+
+use super::{JitterDistribution, SimLatency, SimLatencySetting, SimLatencySettings, SimLoss};
+
+/// Names of the built in profiles, in the order `--help` lists them.
+pub const BUILTIN_PROFILE_NAMES: [&str; 4] = ["lan", "wifi", "4g", "satellite"];
+
+fn link(base_ms: u32, jitter_stddev_ms: u32, loss_chance: f32) -> SimLatencySetting {
+    SimLatencySetting {
+        latency: SimLatency { base_ms, jitter_stddev_ms, ..Default::default() },
+        loss: SimLoss { loss_chance },
+    }
+}
+
+/// Rough one way numbers for common links. Send and receive each get half the round trip.
+pub fn builtin_profile(name: &str) -> Option<SimLatencySettings> {
+    let (send, receive) = match name {
+        "lan" => (link(1, 0, 0.0), link(1, 0, 0.0)),
+        "wifi" => (link(5, 3, 0.005), link(5, 3, 0.005)),
+        "4g" => (link(35, 15, 0.01), link(35, 15, 0.01)),
+        "satellite" => (link(300, 40, 0.02), link(300, 40, 0.02)),
+        _ => return None,
+    };
+    Some(SimLatencySettings { send, receive, ..Default::default() })
+}
+
+/// Parses user defined profiles. One section per profile, any key left out is 0:
+///
+/// ```text
+/// # comments start with '#'
+/// [office-vpn]
+/// send_sim_latency_ms = 20
+/// send_jitter_stddev_ms = 5
+/// send_jitter_distribution = log-normal # or normal, exponential
+/// send_loss = 0.01
+/// recv_sim_latency_ms = 20
+/// recv_jitter_stddev_ms = 5
+/// recv_jitter_distribution = exponential
+/// recv_loss = 0.01
+/// ```
+pub fn parse_profiles(text: &str) -> Result<HashMap<String, SimLatencySettings>, String> {
+    let mut profiles = HashMap::new();
+    let mut current: Option<(String, SimLatencySettings)> = None;
+
+    for (i, line) in text.lines().enumerate() {
+        let line_num = i + 1;
+        let line = line.split('#').next().unwrap().trim();
+        if line.is_empty() {
+            continue;
+        }
+
+        if let Some(name) = line.strip_prefix('[').and_then(|l| l.strip_suffix(']')) {
+            if let Some((name, settings)) = current.take() {
+                profiles.insert(name, settings);
+            }
+            current = Some((name.trim().to_string(), SimLatencySettings::default()));
+            continue;
+        }
+
+        let Some((_, settings)) = current.as_mut() else {
+            return Err(format!("line {}: expected a [profile] header before any settings", line_num));
+        };
+        let Some((key, value)) = line.split_once('=') else {
+            return Err(format!("line {}: expected key = value", line_num));
+        };
+        let (key, value) = (key.trim(), value.trim());
+        let bad_value = || format!("line {}: bad value '{}' for {}", line_num, value, key);
+        match key {
+            "send_sim_latency_ms" => settings.send.latency.base_ms = value.parse().map_err(|_| bad_value())?,
+            "send_jitter_stddev_ms" => settings.send.latency.jitter_stddev_ms = value.parse().map_err(|_| bad_value())?,
+            "send_jitter_distribution" => settings.send.latency.distribution = parse_distribution(value).ok_or_else(bad_value)?,
+            "send_loss" => settings.send.loss.loss_chance = parse_loss(value).ok_or_else(bad_value)?,
+            "recv_sim_latency_ms" => settings.receive.latency.base_ms = value.parse().map_err(|_| bad_value())?,
+            "recv_jitter_stddev_ms" => settings.receive.latency.jitter_stddev_ms = value.parse().map_err(|_| bad_value())?,
+            "recv_jitter_distribution" => settings.receive.latency.distribution = parse_distribution(value).ok_or_else(bad_value)?,
+            "recv_loss" => settings.receive.loss.loss_chance = parse_loss(value).ok_or_else(bad_value)?,
+            _ => return Err(format!("line {}: unknown key '{}'", line_num, key)),
+        }
+    }
+
+    if let Some((name, settings)) = current {
+        profiles.insert(name, settings);
+    }
+    Ok(profiles)
+}
+
+fn parse_loss(value: &str) -> Option<f32> {
+    value.parse().ok().filter(|l: &f32| (0.0..=1.0).contains(l))
+}
+
+fn parse_distribution(value: &str) -> Option<JitterDistribution> {
+    JitterDistribution::from_str(value, true).ok()
+}
+
+/// Looks `name` up in `file` first, so user profiles can replace the built in ones.
+pub fn find_profile(name: &str, file: Option<&Path>) -> Result<SimLatencySettings, String> {
+    if let Some(path) = file {
+        let text = std::fs::read_to_string(path)
+            .map_err(|e| format!("couldn't read {}: {}", path.display(), e))?;
+        let mut profiles = parse_profiles(&text).map_err(|e| format!("{}: {}", path.display(), e))?;
+        if let Some(settings) = profiles.remove(name) {
+            return Ok(settings);
+        }
+    }
+
+    builtin_profile(name).ok_or_else(|| format!(
+        "unknown net profile '{}', built in ones are {}",
+        name,
+        BUILTIN_PROFILE_NAMES.join(", ")
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_builtin_profiles_exist() {
+        for name in BUILTIN_PROFILE_NAMES {
+            assert!(builtin_profile(name).is_some(), "{}", name);
+        }
+        let satellite = builtin_profile("satellite").unwrap();
+        assert_eq!(satellite.send.latency.base_ms, 300);
+        assert!(satellite.receive.loss.loss_chance > 0.0);
+    }
+
+    #[test]
+    fn test_parse_profiles() {
+        let text = "
+            # a comment
+            [vpn]
+            send_sim_latency_ms = 20
+            recv_loss = 0.5 # trailing comment
+
+            [lan]
+            recv_jitter_stddev_ms = 2
+            recv_jitter_distribution = log-normal
+        ";
+        let profiles = parse_profiles(text).unwrap();
+        assert_eq!(profiles.len(), 2);
+        assert_eq!(profiles["vpn"].send.latency.base_ms, 20);
+        assert_eq!(profiles["vpn"].receive.loss.loss_chance, 0.5);
+        assert_eq!(profiles["vpn"].send.latency.jitter_stddev_ms, 0);
+        assert_eq!(profiles["lan"].receive.latency.jitter_stddev_ms, 2);
+        assert_eq!(profiles["lan"].receive.latency.distribution, JitterDistribution::LogNormal);
+        assert_eq!(profiles["lan"].send.latency.distribution, JitterDistribution::Normal);
+    }
+
+    #[test]
+    fn test_parse_profiles_errors() {
+        assert!(parse_profiles("send_loss = 0.1").is_err());
+        assert!(parse_profiles("[a]\nsend_loss = 2").is_err());
+        assert!(parse_profiles("[a]\nbogus = 1").is_err());
+        assert!(parse_profiles("[a]\nsend_sim_latency_ms").is_err());
+        assert!(parse_profiles("[a]\nsend_jitter_distribution = pareto").is_err());
+    }
+}
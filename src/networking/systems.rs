@@ -1,13 +1,68 @@
 use crate::networking::{SimLatencyRollResult, SimLatencySetting, SimLatencySettings};
 use std::{io, time};
 use bevy::prelude::*;
-use bytes::Bytes;
+use bytes::{Bytes, BytesMut};
 
-use crate::networking::{HeartbeatTimer, ETHERNET_MTU};
+use crate::networking::{is_heartbeat, Heartbeat, HeartbeatTimer, NonceHeartbeats, ETHERNET_MTU, HEARTBEAT_PACKET};
 use crate::networking::ResUdpSocket;
 use crate::networking::ResSocketAddr;
 
-use super::{events::NetworkEvent, transport::Transport, NetworkResource, SimLatencyReceiveQueue};
+use super::{codec, events::NetworkEvent, transport::Transport, DatagramObservers, NetworkResource, PacketLogging, SimLatencyInFlight, SimLatencyReceiveQueue};
+use std::fmt::Write as _;
+use std::net::SocketAddr;
+
+// Received payloads are split off the front of one shared block, so we only allocate about once
+// every RECV_BLOCK_LEN / ETHERNET_MTU packets instead of once per packet
+const RECV_BLOCK_LEN: usize = ETHERNET_MTU * 64;
+
+fn prepare_recv_buf(buf: &mut BytesMut) {
+    buf.clear();
+    if buf.capacity() < ETHERNET_MTU {
+        buf.reserve(RECV_BLOCK_LEN);
+    }
+    buf.resize(ETHERNET_MTU, 0);
+}
+
+const HEX_DUMP_WIDTH: usize = 16;
+
+// Offset, hex and printable ASCII, 16 bytes a line like hexdump -C
+fn hex_dump(bytes: &[u8]) -> String {
+    let mut out = String::with_capacity(bytes.len() * 4 + bytes.len() / HEX_DUMP_WIDTH * 12);
+    for (line, chunk) in bytes.chunks(HEX_DUMP_WIDTH).enumerate() {
+        let _ = write!(out, "{:04x}  ", line * HEX_DUMP_WIDTH);
+        for i in 0..HEX_DUMP_WIDTH {
+            match chunk.get(i) {
+                Some(b) => { let _ = write!(out, "{:02x} ", b); }
+                None => out.push_str("   "),
+            }
+            if i == HEX_DUMP_WIDTH / 2 - 1 {
+                out.push(' ');
+            }
+        }
+        out.push_str(" |");
+        out.extend(chunk.iter().map(|&b| if b.is_ascii_graphic() || b == b' ' { b as char } else { '.' }));
+        out.push_str("|\n");
+    }
+    out
+}
+
+// Payloads as game code sees them, before codec::encode on send and after codec::decode on receive
+fn log_packet(logging: &Option<Res<PacketLogging>>, direction: &str, address: SocketAddr, bytes: &[u8]) {
+    if logging.is_some() {
+        trace!("{} {} ({} bytes)\n{}", direction, address, bytes.len(), hex_dump(bytes));
+    }
+}
+
+// Undoes codec::encode, None (and a warning) if the datagram is garbage
+fn decode_datagram(address: SocketAddr, datagram: Bytes) -> Option<Bytes> {
+    match codec::decode(datagram) {
+        Ok(payload) => Some(payload),
+        Err(e) => {
+            warn!("{}: dropping undecodable datagram: {:?}", address, e);
+            None
+        }
+    }
+}
 
 fn recv_with_sim_latency(
     receive_setting: &SimLatencySetting,
@@ -21,10 +76,7 @@ fn recv_with_sim_latency(
         },
         SimLatencyRollResult::Drop => {},
         SimLatencyRollResult::Delay(t) => {
-            queue.sim_latency_delayed.push_back(event);
-
-            let pos = queue.sim_latency_delivery_times.binary_search(&t).unwrap_or_else(|p| p);
-            queue.sim_latency_delivery_times.insert(pos, t);
+            queue.push(event, t);
         }
     };
 }
@@ -40,8 +92,13 @@ fn process_sim_latency(
     let mut i = 0;
     while i != delayed_events.len() {
         if now >= queue.sim_latency_delivery_times[i] {
-            events.send(delayed_events.remove(i).unwrap());
-            queue.sim_latency_delivery_times.remove(i);
+            let delivery_time = queue.sim_latency_delivery_times.remove(i).unwrap();
+            // As far as anyone reading events can tell, this is when it came off the socket
+            let event = match delayed_events.remove(i).unwrap() {
+                NetworkEvent::Message(address, payload, _) => NetworkEvent::Message(address, payload, delivery_time),
+                event => event
+            };
+            events.send(event);
         } else {
             i += 1;
         }
@@ -49,18 +106,30 @@ fn process_sim_latency(
 }
 
 pub fn client_recv_packet_system(
-    socket: Res<ResUdpSocket>,
+    mut socket: ResMut<ResUdpSocket>,
     mut events: EventWriter<NetworkEvent>,
     mut queue: ResMut<SimLatencyReceiveQueue>,
-    sim_settings: Res<SimLatencySettings>
+    sim_settings: Res<SimLatencySettings>,
+    observers: Option<Res<DatagramObservers>>,
+    logging: Option<Res<PacketLogging>>,
+    mut buf: Local<BytesMut>
 ) {
     //let mut recv_count = 0;
     loop {
-        let mut buf = [0; ETHERNET_MTU];
-        match socket.0.recv_from(&mut buf) {
+        prepare_recv_buf(&mut buf);
+        match socket.recv_from(&mut buf[..]) {
             Ok((recv_len, address)) => {
-                let payload = Bytes::copy_from_slice(&buf[..recv_len]);
-                if payload.len() == 0 {
+                if let Some(observers) = &observers {
+                    observers.notify(address, &buf[..recv_len], time::Instant::now());
+                }
+
+                buf.truncate(recv_len);
+                let Some(payload) = decode_datagram(address, buf.split().freeze()) else {
+                    continue;
+                };
+                log_packet(&logging, "recv from", address, &payload);
+
+                if is_heartbeat(&payload) {
                     debug!("{}: received heartbeat packet", address);
                     // discard without sending a NetworkEvent
                     continue;
@@ -97,33 +166,51 @@ pub fn client_recv_packet_system(
 
 pub fn server_recv_packet_system(
     time: Res<Time>,
-    socket: Res<ResUdpSocket>,
+    mut socket: ResMut<ResUdpSocket>,
     mut events: EventWriter<NetworkEvent>,
     mut net: ResMut<NetworkResource>,
     mut queue: ResMut<SimLatencyReceiveQueue>,
-    sim_settings: Res<SimLatencySettings>
+    sim_settings: Res<SimLatencySettings>,
+    observers: Option<Res<DatagramObservers>>,
+    logging: Option<Res<PacketLogging>>,
+    mut buf: Local<BytesMut>
 ) {
     loop {
-        let mut buf = [0; ETHERNET_MTU];
-        match socket.0.recv_from(&mut buf) {
+        prepare_recv_buf(&mut buf);
+        match socket.recv_from(&mut buf[..]) {
             Ok((recv_len, address)) => {
-                let payload = Bytes::copy_from_slice(&buf[..recv_len]);
+                if let Some(observers) = &observers {
+                    observers.notify(address, &buf[..recv_len], time::Instant::now());
+                }
+
                 if net
                     .connections
                     .insert(address, time.elapsed())
                     .is_none()
                 {
-                    // connection established
-                    //events.send(NetworkEvent::Connected(address));
-                    recv_with_sim_latency(
-                        &sim_settings.receive,
-                        &mut events,
-                        &mut queue,
-                        NetworkEvent::Connected(address)
-                    );
+                    // connection established. Never goes through sim latency, or sim loss could drop
+                    // it and leave a connection here that game code never spawned anything for.
+                    // Anything sim latency delays is still delivered after it.
+                    events.send(NetworkEvent::Connected(address));
                 }
-                if payload.len() == 0 {
-                    debug!("{}: received heartbeat packet", address);
+                buf.truncate(recv_len);
+                let Some(payload) = decode_datagram(address, buf.split().freeze()) else {
+                    continue;
+                };
+                log_packet(&logging, "recv from", address, &payload);
+
+                if is_heartbeat(&payload) {
+                    match Heartbeat::decode(&payload) {
+                        Some(heartbeat) => {
+                            let stats = net.heartbeats.entry(address).or_default();
+                            if stats.record(heartbeat, time.elapsed()) {
+                                debug!("{}: received heartbeat {} one_way_excess {:?}", address, heartbeat.nonce, stats.one_way_excess);
+                            } else {
+                                debug!("{}: received stale heartbeat {} (last {:?})", address, heartbeat.nonce, stats.last_nonce);
+                            }
+                        }
+                        None => debug!("{}: received heartbeat packet", address),
+                    }
                     // discard without sending a NetworkEvent
                     continue;
                 }
@@ -160,12 +247,49 @@ pub fn send_packet_system(
     socket: Res<ResUdpSocket>,
     mut events: EventWriter<NetworkEvent>,
     mut transport: ResMut<Transport>,
+    logging: Option<Res<PacketLogging>>,
+    receive_queue: Res<SimLatencyReceiveQueue>,
+    mut in_flight: ResMut<SimLatencyInFlight>,
 ) {
     let messages = transport.drain_messages_to_send(|_| true);
     for message in messages {
-        debug!("{} Send packet {:?} at {:?}", message.destination, message.payload, time::Instant::now());
-        if let Err(e) = socket.0.send_to(&message.payload, message.destination) {
-            events.send(NetworkEvent::SendError(socket.0.peer_addr().unwrap(), e, message));
+        debug!("{} Send packet ({} bytes) at {:?}", message.destination, message.payload.len(), time::Instant::now());
+        log_packet(&logging, "send to", message.destination, &message.payload);
+        if let Err(e) = socket.send_to(&codec::encode(&message.payload), message.destination) {
+            if !ResUdpSocket::is_transient_send_error(&e) {
+                events.send(NetworkEvent::SendError(message.destination, e, message));
+                continue;
+            }
+            debug!("{}: transient send error, retrying next pass: {:?}", message.destination, e);
+            if let Err(message) = transport.retry(message) {
+                warn!("{}: still failing after {} retries, dropping message: {:?}", message.destination, message.retries, e);
+            }
+        }
+    }
+
+    *in_flight = SimLatencyInFlight {
+        send: transport.sim_delayed_len(),
+        receive: receive_queue.sim_latency_delayed.len(),
+        send_dropped_on_overflow: transport.dropped_on_overflow(),
+        receive_dropped_on_overflow: receive_queue.dropped_on_overflow,
+    };
+}
+
+/// Sends everything still queued when the app is exiting, ignoring simulated send latency.
+pub fn flush_on_exit_system(
+    mut exit_events: EventReader<AppExit>,
+    socket: Res<ResUdpSocket>,
+    mut transport: ResMut<Transport>,
+) {
+    if exit_events.read().next().is_none() {
+        return;
+    }
+
+    let messages = transport.drain_all_messages();
+    debug!("Flushing {} messages on exit", messages.len());
+    for message in messages {
+        if let Err(e) = socket.send_to(&codec::encode(&message.payload), message.destination) {
+            warn!("Failed to flush message to {} on exit: {:?}", message.destination, e);
         }
     }
 }
@@ -173,16 +297,20 @@ pub fn send_packet_system(
 pub fn idle_timeout_system(
     time: Res<Time>,
     mut net: ResMut<NetworkResource>,
+    mut socket: ResMut<ResUdpSocket>,
     mut events: EventWriter<NetworkEvent>,
 ) {
-    let idle_timeout = net.idle_timeout.clone();
-    net.connections.retain(|addr, last_update| {
-        let reached_idle_timeout = time.elapsed() - *last_update > idle_timeout;
-        if reached_idle_timeout {
-            events.send(NetworkEvent::Disconnected(*addr));
-        }
-        !reached_idle_timeout
-    });
+    let Some(idle_timeout) = net.idle_timeout else {
+        return;
+    };
+    let timed_out: Vec<_> = net.connections.iter()
+        .map(|(addr, last_update)| (*addr, time.elapsed() - *last_update))
+        .filter(|(_, idle)| *idle > idle_timeout)
+        .collect();
+    for (addr, idle) in timed_out {
+        net.forget_peer(&addr, &mut socket);
+        events.send(NetworkEvent::TimedOut(addr, idle));
+    }
 }
 
 pub fn auto_heartbeat_system(
@@ -190,8 +318,33 @@ pub fn auto_heartbeat_system(
     mut timer: ResMut<HeartbeatTimer>,
     remote_addr: Res<ResSocketAddr>,
     mut transport: ResMut<Transport>,
+    nonce_heartbeats: Option<ResMut<NonceHeartbeats>>,
 ) {
     if timer.0.tick(time.delta()).just_finished() {
-        transport.send(remote_addr.0, Default::default());
+        match nonce_heartbeats {
+            Some(mut nonce_heartbeats) => {
+                let heartbeat = Heartbeat { nonce: nonce_heartbeats.next_nonce, sent_ms: time.elapsed().as_millis() as u32 };
+                nonce_heartbeats.next_nonce += 1;
+                transport.send(remote_addr.0, &heartbeat.encode());
+            }
+            None => {
+                transport.send(remote_addr.0, &HEARTBEAT_PACKET);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_hex_dump() {
+        let dump = hex_dump(b"\xba\x11\xba\x11hello, world!\x00\x01");
+        assert_eq!(
+            dump,
+            "0000  ba 11 ba 11 68 65 6c 6c  6f 2c 20 77 6f 72 6c 64  |....hello, world|\n\
+             0010  21 00 01                                          |!..|\n"
+        );
     }
 }
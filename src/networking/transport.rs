@@ -1,17 +1,75 @@
 use crate::networking::SimLatencyRollResult;
 use crate::networking::SimLatencySetting;
-use std::{collections::VecDeque, net::SocketAddr};
+use std::{collections::{HashMap, VecDeque}, net::SocketAddr};
 
 use super::message::Message;
 use std::time;
 
+/// Past this many queued messages the oldest ones are dropped. Only reachable when
+/// sends can't keep up (e.g. a long simulated send delay), and by then those packets are stale anyway.
+pub const DEFAULT_MAX_QUEUED_MESSAGES: usize = 1024;
+
+/// A message whose send keeps failing with a transient error is given up on after this many retries.
+pub const MAX_SEND_RETRIES: u8 = 3;
+
+/// Byte budgets are per destination per window of this length.
+pub const BUDGET_WINDOW: time::Duration = time::Duration::from_secs(1);
+
+/// Usage for destinations we haven't sent to in this long is forgotten, they've most likely disconnected.
+const USAGE_EXPIRY: time::Duration = time::Duration::from_secs(10);
+
+/// What's been sent to one destination, for per-destination budgets and diagnostics.
+#[derive(Debug, Clone, Copy)]
+pub struct DestinationUsage {
+    window_start: time::Instant,
+    /// Bytes sent so far in the current window.
+    pub bytes_in_window: u64,
+    /// Bytes sent in the last full window.
+    pub bytes_last_window: u64,
+    /// Messages dropped because this destination was over its budget.
+    pub throttled: u64,
+}
+
+impl DestinationUsage {
+    fn new(now: time::Instant) -> Self {
+        Self { window_start: now, bytes_in_window: 0, bytes_last_window: 0, throttled: 0 }
+    }
+
+    fn roll_window(&mut self, now: time::Instant) {
+        let elapsed = now - self.window_start;
+        if elapsed >= BUDGET_WINDOW {
+            // Nothing sent for a whole window means the last one was empty
+            self.bytes_last_window = if elapsed >= BUDGET_WINDOW * 2 { 0 } else { self.bytes_in_window };
+            self.bytes_in_window = 0;
+            self.window_start = now;
+        }
+    }
+}
+
 /// Resource serving as the owner of the queue of messages to be sent. This resource also serves
 /// as the interface for other systems to send messages.
 #[derive(bevy::prelude::Resource)]
 pub struct Transport {
     messages: VecDeque<Message>,
+    immediate: VecDeque<Message>, // see send_immediate, also holds retries of those
+    retries: VecDeque<Message>, // see retry, older than anything in messages
+    retried: u64,
     sim_send_times: VecDeque<time::Instant>, // parallel to messages
     sim_send_settings: SimLatencySetting,
+    max_queued: usize,
+    dropped_on_overflow: u64,
+    destination_budget: Option<u64>, // bytes per BUDGET_WINDOW
+    usage: HashMap<SocketAddr, DestinationUsage>,
+    pacing: Option<time::Duration>, // see with_pacing
+    pass: u64, // bumped on every drain
+    paced: HashMap<SocketAddr, PacedDestination>,
+}
+
+/// When a destination last had a burst released under pacing.
+#[derive(Debug, Clone, Copy)]
+struct PacedDestination {
+    pass: u64,
+    next_release: time::Instant,
 }
 
 impl Transport {
@@ -19,14 +77,107 @@ impl Transport {
     pub fn new(sim_send_settings: SimLatencySetting) -> Self {
         Self {
             messages: VecDeque::new(),
+            immediate: VecDeque::new(),
+            retries: VecDeque::new(),
+            retried: 0,
             sim_send_times: VecDeque::new(),
             sim_send_settings,
+            max_queued: DEFAULT_MAX_QUEUED_MESSAGES,
+            dropped_on_overflow: 0,
+            destination_budget: None,
+            usage: HashMap::new(),
+            pacing: None,
+            pass: 0,
+            paced: HashMap::new(),
         }
     }
 
+    /// Caps how many bytes are sent to any one destination per `BUDGET_WINDOW`. Messages from
+    /// `send` over the cap are dropped instead of sent, so a client that can't keep up stops
+    /// costing us anything past its budget and never holds up anyone else.
+    #[allow(dead_code)]
+    pub fn with_destination_budget(mut self, bytes_per_window: Option<u64>) -> Self {
+        self.destination_budget = bytes_per_window;
+        self
+    }
+
+    /// Spaces out what's sent to each destination. Everything queued for one drain still goes out
+    /// together, but a later burst is held until `gap` after the previous one went out. Draining
+    /// more often than messages are queued then turns bunched up bursts into evenly spaced ones.
+    pub fn with_pacing(mut self, gap: Option<time::Duration>) -> Self {
+        self.pacing = gap;
+        self
+    }
+
+    /// True if sends are paced with `with_pacing`.
+    pub fn is_paced(&self) -> bool {
+        self.pacing.is_some()
+    }
+
     /// Creates a `Message` with the default guarantees provided by the `Socket` implementation and
-    /// pushes it onto the messages queue to be sent on the next frame.
-    pub fn send(&mut self, destination: SocketAddr, payload: &[u8]) {
+    /// pushes it onto the messages queue to be sent on the next frame. Returns when it was queued,
+    /// for measuring send-to-ack latency.
+    pub fn send(&mut self, destination: SocketAddr, payload: &[u8]) -> time::Instant {
+        let send_time = time::Instant::now();
+        self.enqueue(Message::new(destination, payload));
+        send_time
+    }
+
+    /// Sends `payload` once per target. `prepare` gets each target and the buffer before it's sent,
+    /// writes anything that differs per target (e.g. a header) and returns where to send it. The
+    /// buffer is shared between targets, so `prepare` has to overwrite everything it customizes.
+    #[allow(dead_code)]
+    pub fn broadcast_with<T>(
+        &mut self,
+        targets: impl IntoIterator<Item = T>,
+        payload: &mut [u8],
+        mut prepare: impl FnMut(&T, &mut [u8]) -> SocketAddr,
+    ) {
+        for target in targets {
+            let destination = prepare(&target, payload);
+            self.send(destination, payload);
+        }
+    }
+
+    /// Sends `payload` on the next drain no matter what: no simulated send latency or loss, no
+    /// byte budget or pacing and never shed on overflow. For control messages that have to go out this tick.
+    ///
+    /// Ordering: immediate messages go out ahead of everything else drained in the same pass, in
+    /// the order they were queued. So they can overtake earlier `send`s to the same destination that
+    /// are still queued or held back by sim latency. Nothing is ever reordered among normal sends.
+    pub fn send_immediate(&mut self, destination: SocketAddr, payload: &[u8]) {
+        let mut message = Message::new(destination, payload);
+        message.critical = true;
+        self.immediate.push_back(message);
+    }
+
+    /// Puts back a message whose send failed with a transient error, to go out ahead of everything
+    /// from `send` on the next drain. It's still paced, charged against the budget and shed on
+    /// overflow like any other, only ones from `send_immediate` skip that again. Returns it instead
+    /// once it's been retried `MAX_SEND_RETRIES` times.
+    pub fn retry(&mut self, mut message: Message) -> Result<(), Message> {
+        if message.retries >= MAX_SEND_RETRIES {
+            return Err(message);
+        }
+        message.retries += 1;
+        self.retried += 1;
+        if message.critical {
+            self.immediate.push_back(message);
+        } else {
+            self.retries.push_back(message);
+            self.shed_overflow();
+        }
+        Ok(())
+    }
+
+    /// Total sends that failed with a transient error and were put back.
+    #[allow(dead_code)]
+    pub fn retried(&self) -> u64 {
+        self.retried
+    }
+
+    fn enqueue(&mut self, mut message: Message) {
+        message.pass = self.pass;
         match self.sim_send_settings.roll() {
             SimLatencyRollResult::NoOp => {},
             SimLatencyRollResult::Drop => return,
@@ -39,17 +190,120 @@ impl Transport {
             }
         };
 
-        let message = Message::new(destination, payload);
         self.messages.push_back(message);
+        self.shed_overflow();
+    }
+
+    fn shed_overflow(&mut self) {
+        while self.retries.len() + self.messages.len() > self.max_queued {
+            // Shed from whoever has the most queued, so one backed up destination can't push out
+            // everyone else's messages. Retries are the oldest of all.
+            let mut queued_per_destination: HashMap<SocketAddr, usize> = HashMap::new();
+            for m in self.retries.iter().chain(self.messages.iter()) {
+                *queued_per_destination.entry(m.destination).or_default() += 1;
+            }
+            let Some((&busiest, _)) = queued_per_destination.iter().max_by_key(|(_, &count)| count) else {
+                return;
+            };
+            if let Some(oldest) = self.retries.iter().position(|m| m.destination == busiest) {
+                self.retries.remove(oldest);
+            } else {
+                let oldest = self.messages.iter().position(|m| m.destination == busiest).unwrap();
+                self.messages.remove(oldest);
+                // Send times are sorted separately from messages and only matched up by index on
+                // drain, so dropping the same index keeps the two the same length and in order
+                if !self.sim_send_times.is_empty() {
+                    self.sim_send_times.remove(oldest);
+                }
+            }
+
+            if self.dropped_on_overflow == 0 {
+                bevy::log::warn!("Send queue over {} messages, dropping the oldest", self.max_queued);
+            }
+            self.dropped_on_overflow += 1;
+        }
+    }
+
+    /// Number of queued messages.
+    #[allow(dead_code)]
+    pub fn queued_len(&self) -> usize {
+        self.messages.len() + self.immediate.len() + self.retries.len()
+    }
+
+    /// Number of queued messages still waiting out a simulated send delay.
+    #[allow(dead_code)]
+    pub fn sim_delayed_len(&self) -> usize {
+        if !self.sim_send_settings.is_set() {
+            return 0;
+        }
+        let now = time::Instant::now();
+        self.sim_send_times.iter().filter(|&&t| t > now).count()
+    }
+
+    /// Total messages dropped because the queue overflowed.
+    #[allow(dead_code)]
+    pub fn dropped_on_overflow(&self) -> u64 {
+        self.dropped_on_overflow
+    }
+
+    /// What's been sent to `destination` recently, if anything.
+    #[allow(dead_code)]
+    pub fn usage(&self, destination: &SocketAddr) -> Option<&DestinationUsage> {
+        self.usage.get(destination)
+    }
+
+    // Counts the message against its destination's budget. False if it's over budget and should be dropped.
+    fn charge(&mut self, message: &Message, now: time::Instant) -> bool {
+        let usage = self.usage.entry(message.destination).or_insert_with(|| DestinationUsage::new(now));
+        usage.roll_window(now);
+
+        let bytes = message.payload.len() as u64;
+        let over_budget = self.destination_budget.is_some_and(|budget| usage.bytes_in_window + bytes > budget);
+        if over_budget && !message.critical {
+            if usage.throttled == 0 {
+                bevy::log::warn!("{}: over its send budget, dropping messages", message.destination);
+            }
+            usage.throttled += 1;
+            return false;
+        }
+
+        usage.bytes_in_window += bytes;
+        true
+    }
+
+    // False if pacing holds the message back. A held message holds back everything after it to the
+    // same destination too, since those were queued in the same pass or later.
+    fn pace(&mut self, destination: SocketAddr, pass: u64, now: time::Instant) -> bool {
+        let Some(gap) = self.pacing else {
+            return true;
+        };
+        // Queued since the last drain, anything older was held back
+        let fresh = pass + 1 >= self.pass;
+        match self.paced.get_mut(&destination) {
+            Some(paced) if pass <= paced.pass => true,
+            Some(paced) if now < paced.next_release => false,
+            Some(paced) => {
+                // A held pass was due at next_release, so the one after it is due a gap after that
+                // rather than a gap after now. Otherwise a held pass would push every later one back
+                // a drain for good, this lets the next one go out in the same drain once it's due.
+                let next_release = if fresh { now + gap } else { paced.next_release + gap };
+                *paced = PacedDestination { pass, next_release };
+                true
+            }
+            None => {
+                self.paced.insert(destination, PacedDestination { pass, next_release: now + gap });
+                true
+            }
+        }
     }
 
     /// Returns true if there are messages enqueued to be sent.
     #[allow(dead_code)]
     pub fn has_messages(&self) -> bool {
-        !self.messages.is_empty()
+        !self.messages.is_empty() || !self.immediate.is_empty() || !self.retries.is_empty()
     }
 
-    /// Returns a reference to the owned messages.
+    /// Returns a reference to the owned messages, not including ones from `send_immediate` or retries.
     #[allow(dead_code)]
     pub fn get_messages(&self) -> &VecDeque<Message> {
         &self.messages
@@ -75,14 +329,52 @@ impl Transport {
             if using_send_sim { now >= send_times[idx] } else { true }
         };
 
+        self.usage.retain(|_, u| now - u.window_start < USAGE_EXPIRY);
+        self.paced.retain(|_, p| now < p.next_release + USAGE_EXPIRY);
+        self.pass += 1;
+
+        let mut j = 0;
+        while j != self.immediate.len() {
+            if filter(&mut self.immediate[j]) {
+                let m = self.immediate.remove(j).unwrap();
+                // Critical, so this only records usage and never drops it
+                self.charge(&m, now);
+                drained.push(m);
+            } else {
+                j += 1;
+            }
+        }
+
+        // Already past sim latency, everything else applies
+        let mut k = 0;
+        while k != self.retries.len() {
+            let msg = &mut self.retries[k];
+            let (destination, pass) = (msg.destination, msg.pass);
+            if !filter(msg) || !self.pace(destination, pass, now) {
+                k += 1;
+                continue;
+            }
+            let m = self.retries.remove(k).unwrap();
+            if self.charge(&m, now) {
+                drained.push(m);
+            }
+        }
+
         while i != self.messages.len() {
             let msg = &mut self.messages[i];
             if sim_time_valid(i, &self.sim_send_times) && filter(msg) {
+                let (destination, pass) = (msg.destination, msg.pass);
+                if !self.pace(destination, pass, now) {
+                    i += 1;
+                    continue;
+                }
                 if let Some(m) = self.messages.remove(i) {
-                    drained.push(m);
                     if using_send_sim {
                         self.sim_send_times.remove(i);
                     }
+                    if self.charge(&m, now) {
+                        drained.push(m);
+                    }
                 }
             } else {
                 i += 1;
@@ -90,14 +382,31 @@ impl Transport {
         }
         drained
     }
+
+    /// Drains every queued message regardless of simulated send delays. Used on shutdown, when
+    /// anything still queued would otherwise be dropped.
+    pub fn drain_all_messages(&mut self) -> Vec<Message> {
+        self.sim_send_times.clear();
+        self.immediate.drain(..).chain(self.retries.drain(..)).chain(self.messages.drain(..)).collect()
+    }
 }
 
 impl Default for Transport {
     fn default() -> Self {
         Self {
             messages: VecDeque::new(),
+            immediate: VecDeque::new(),
+            retries: VecDeque::new(),
+            retried: 0,
             sim_send_settings: Default::default(),
             sim_send_times: VecDeque::new(),
+            max_queued: DEFAULT_MAX_QUEUED_MESSAGES,
+            dropped_on_overflow: 0,
+            destination_budget: None,
+            usage: HashMap::new(),
+            pacing: None,
+            pass: 0,
+            paced: HashMap::new(),
         }
     }
 }
@@ -156,8 +465,247 @@ mod tests {
         assert_eq!(transport.drain_messages_to_send(|_| true).len(), 0);
     }
 
+    #[test]
+    fn test_drain_all_ignores_sim_delay() {
+        let mut transport = Transport::new(SimLatencySetting {
+            latency: crate::networking::SimLatency { base_ms: 10_000, ..Default::default() },
+            ..Default::default()
+        });
+
+        let addr = "127.0.0.1:3000".parse().unwrap();
+        transport.send(addr, test_payload());
+        transport.send(addr, test_payload());
+
+        assert_eq!(transport.drain_messages_to_send(|_| true).len(), 0);
+        assert_eq!(transport.drain_all_messages().len(), 2);
+        assert_eq!(transport.has_messages(), false);
+    }
+
+    #[test]
+    fn test_overflow_drops_oldest_but_not_immediate() {
+        let mut transport = create_test_transport();
+        transport.max_queued = 3;
+
+        let addr = "127.0.0.1:3000".parse().unwrap();
+        transport.send_immediate(addr, b"now");
+        transport.send(addr, b"a");
+        transport.send(addr, b"b");
+        transport.send(addr, b"c");
+        transport.send(addr, b"d");
+
+        assert_eq!(transport.queued_len(), 4);
+        assert_eq!(transport.dropped_on_overflow(), 1);
+        let payloads: Vec<_> = transport.drain_messages_to_send(|_| true).into_iter().map(|m| m.payload).collect();
+        assert_eq!(payloads, vec![&b"now"[..], &b"b"[..], &b"c"[..], &b"d"[..]]);
+    }
+
+    #[test]
+    fn test_budget_throttles_only_that_destination() {
+        let mut transport = create_test_transport().with_destination_budget(Some(8));
+
+        let slow = "127.0.0.1:3000".parse().unwrap();
+        let fast = "127.0.0.1:3001".parse().unwrap();
+        transport.send(slow, b"12345");
+        transport.send(slow, b"12345");
+        transport.send(fast, b"12345");
+
+        let sent: Vec<_> = transport.drain_messages_to_send(|_| true).into_iter().map(|m| m.destination).collect();
+        assert_eq!(sent, vec![slow, fast]);
+        assert_eq!(transport.usage(&slow).unwrap().throttled, 1);
+        assert_eq!(transport.usage(&slow).unwrap().bytes_in_window, 5);
+        assert_eq!(transport.usage(&fast).unwrap().throttled, 0);
+    }
+
+    #[test]
+    fn test_overflow_sheds_busiest_destination() {
+        let mut transport = create_test_transport();
+        transport.max_queued = 3;
+
+        let slow = "127.0.0.1:3000".parse().unwrap();
+        let fast = "127.0.0.1:3001".parse().unwrap();
+        transport.send(fast, b"a");
+        transport.send(slow, b"b");
+        transport.send(slow, b"c");
+        transport.send(slow, b"d");
+
+        let payloads: Vec<_> = transport.drain_messages_to_send(|_| true).into_iter().map(|m| m.payload).collect();
+        assert_eq!(payloads, vec![&b"a"[..], &b"c"[..], &b"d"[..]]);
+    }
+
+    #[test]
+    fn test_broadcast_with_per_target_header() {
+        let mut transport = create_test_transport();
+
+        let targets: Vec<(SocketAddr, u8)> = vec![
+            ("127.0.0.1:3000".parse().unwrap(), 1),
+            ("127.0.0.1:3001".parse().unwrap(), 2),
+        ];
+        let mut buf = [0, b'x', b'y'];
+        transport.broadcast_with(targets.iter(), &mut buf, |(addr, header), buf| {
+            buf[0] = *header;
+            *addr
+        });
+
+        let sent: Vec<_> = transport.drain_messages_to_send(|_| true).into_iter().map(|m| (m.destination, m.payload.to_vec())).collect();
+        assert_eq!(sent, vec![
+            (targets[0].0, vec![1, b'x', b'y']),
+            (targets[1].0, vec![2, b'x', b'y']),
+        ]);
+    }
+
+    #[test]
+    fn test_immediate_skips_sim_delay_and_budget() {
+        let mut transport = Transport::new(SimLatencySetting {
+            latency: crate::networking::SimLatency { base_ms: 10_000, ..Default::default() },
+            ..Default::default()
+        }).with_destination_budget(Some(1));
+
+        let addr = "127.0.0.1:3000".parse().unwrap();
+        transport.send(addr, b"queued");
+        transport.send_immediate(addr, b"now");
+        transport.send_immediate(addr, b"next");
+
+        let payloads: Vec<_> = transport.drain_messages_to_send(|_| true).into_iter().map(|m| m.payload).collect();
+        assert_eq!(payloads, vec![&b"now"[..], &b"next"[..]]);
+        assert_eq!(transport.usage(&addr).unwrap().throttled, 0);
+        assert_eq!(transport.queued_len(), 1);
+    }
+
+    #[test]
+    fn test_sim_delayed_len() {
+        let mut transport = Transport::new(SimLatencySetting {
+            latency: crate::networking::SimLatency { base_ms: 10_000, ..Default::default() },
+            ..Default::default()
+        });
+        assert_eq!(transport.sim_delayed_len(), 0);
+
+        let addr = "127.0.0.1:3000".parse().unwrap();
+        transport.send(addr, test_payload());
+        transport.send(addr, test_payload());
+        assert_eq!(transport.sim_delayed_len(), 2);
+        assert_eq!(create_test_transport().sim_delayed_len(), 0);
+    }
+
+    #[test]
+    fn test_retry_gives_up_after_max_retries() {
+        let mut transport = create_test_transport();
+
+        let addr = "127.0.0.1:3000".parse().unwrap();
+        transport.send(addr, test_payload());
+        let mut message = transport.drain_messages_to_send(|_| true).pop().unwrap();
+        for _ in 0..MAX_SEND_RETRIES {
+            assert!(transport.retry(message).is_ok());
+            message = transport.drain_messages_to_send(|_| true).pop().unwrap();
+        }
+        assert_eq!(transport.retry(message).map_err(|m| m.retries), Err(MAX_SEND_RETRIES));
+        assert_eq!(transport.retried(), MAX_SEND_RETRIES as u64);
+    }
+
+    #[test]
+    fn test_retries_are_charged_but_immediate_retries_are_not() {
+        let mut transport = create_test_transport().with_destination_budget(Some(8));
+
+        let addr = "127.0.0.1:3000".parse().unwrap();
+        transport.send(addr, b"12345");
+        transport.send_immediate(addr, b"now");
+        let mut drained = transport.drain_messages_to_send(|_| true);
+        assert_eq!(drained.len(), 2);
+
+        // Both already count against the budget, so sending the normal one again goes over it
+        let queued = drained.pop().unwrap();
+        assert!(transport.retry(queued).is_ok());
+        assert!(transport.retry(drained.pop().unwrap()).is_ok());
+        let payloads: Vec<_> = transport.drain_messages_to_send(|_| true).into_iter().map(|m| m.payload).collect();
+        assert_eq!(payloads, vec![&b"now"[..]]);
+        assert_eq!(transport.usage(&addr).unwrap().throttled, 1);
+        assert_eq!(transport.queued_len(), 0);
+    }
+
+    #[test]
+    fn test_retries_are_paced_and_shed() {
+        let mut transport = create_test_transport().with_pacing(Some(time::Duration::from_secs(10)));
+        transport.max_queued = 2;
+
+        let addr = "127.0.0.1:3000".parse().unwrap();
+        transport.send(addr, b"a");
+        let a = transport.drain_messages_to_send(|_| true).pop().unwrap();
+        transport.send(addr, b"b");
+        assert!(transport.drain_messages_to_send(|_| true).is_empty());
+
+        // A retry is still part of the burst it was released with, later ones still wait
+        assert!(transport.retry(a).is_ok());
+        let payloads: Vec<_> = transport.drain_messages_to_send(|_| true).into_iter().map(|m| m.payload).collect();
+        assert_eq!(payloads, vec![&b"a"[..]]);
+
+        transport.send(addr, b"c");
+        transport.paced.get_mut(&addr).unwrap().next_release = time::Instant::now();
+        let b = transport.drain_messages_to_send(|_| true).pop().unwrap();
+        assert_eq!(b.payload, &b"b"[..]);
+        assert!(transport.retry(b).is_ok());
+        transport.send(addr, b"d");
+
+        // Over max_queued the retry is the oldest, so it goes first
+        assert_eq!(transport.dropped_on_overflow(), 1);
+        assert_eq!(transport.queued_len(), 2);
+        transport.paced.get_mut(&addr).unwrap().next_release = time::Instant::now();
+        let payloads: Vec<_> = transport.drain_messages_to_send(|_| true).into_iter().map(|m| m.payload).collect();
+        assert_eq!(payloads, vec![&b"c"[..]]);
+    }
+
+    #[test]
+    fn test_pacing_spaces_out_bursts() {
+        let mut transport = create_test_transport().with_pacing(Some(time::Duration::from_secs(10)));
+
+        let addr = "127.0.0.1:3000".parse().unwrap();
+        let other = "127.0.0.1:3001".parse().unwrap();
+        // Two ticks in one frame: the first burst goes out whole, the second waits for the gap
+        transport.send(addr, b"a1");
+        transport.send(addr, b"a2");
+        transport.send(other, b"x");
+        let payloads: Vec<_> = transport.drain_messages_to_send(|_| true).into_iter().map(|m| m.payload).collect();
+        assert_eq!(payloads, vec![&b"a1"[..], &b"a2"[..], &b"x"[..]]);
+
+        transport.send(addr, b"b");
+        transport.send_immediate(addr, b"now");
+        let payloads: Vec<_> = transport.drain_messages_to_send(|_| true).into_iter().map(|m| m.payload).collect();
+        assert_eq!(payloads, vec![&b"now"[..]]);
+        assert_eq!(transport.queued_len(), 1);
+
+        transport.paced.get_mut(&addr).unwrap().next_release = time::Instant::now();
+        let payloads: Vec<_> = transport.drain_messages_to_send(|_| true).into_iter().map(|m| m.payload).collect();
+        assert_eq!(payloads, vec![&b"b"[..]]);
+    }
+
+    #[test]
+    fn test_pacing_recovers_after_held_burst() {
+        let gap = time::Duration::from_millis(8);
+        let frame = time::Duration::from_millis(16);
+        let mut transport = create_test_transport().with_pacing(Some(gap));
+        let addr = "127.0.0.1:3000".parse().unwrap();
+        let start = time::Instant::now();
+
+        // Two ticks in one frame, the second tick's burst is held
+        transport.pass += 1;
+        assert!(transport.pace(addr, 0, start));
+        transport.pass += 1;
+        assert!(!transport.pace(addr, 1, start));
+
+        // Next frame the held burst is long due, and so is this frame's, both go out
+        transport.pass += 1;
+        assert!(transport.pace(addr, 1, start + frame));
+        assert!(transport.pace(addr, 2, start + frame));
+
+        // Then back to one burst per frame, none of them a frame late
+        transport.pass += 1;
+        assert!(transport.pace(addr, 3, start + frame * 2));
+        transport.pass += 1;
+        assert!(transport.pace(addr, 4, start + frame * 3));
+        // An old pass being retried was already released
+        assert!(transport.pace(addr, 3, start + frame * 3));
+    }
+
     fn heartbeat_payload() -> &'static [u8] {
-        b""
+        &crate::networking::HEARTBEAT_PACKET
     }
 
     fn test_payload() -> &'static [u8] {
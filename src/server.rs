@@ -1,61 +1,237 @@
-use clap::Parser;
+use clap::builder::TypedValueParser;
+use clap::{CommandFactory, Parser};
 mod networking;
 mod server_types;
 mod server_util;
+mod server_diagnostics;
 mod common;
+#[cfg(feature = "fixed_point_sim")]
+mod fixed_point;
 
 use common::*;
+use std::collections::VecDeque;
 use std::time;
 use bevy::prelude::*;
+use bevy::utils::tracing::field;
 use bincode;
-use bincode::config;
 use bincode::error::DecodeError;
-use networking::{NetworkEvent, Transport, ResUdpSocket};
+use networking::{NetworkEvent, NetworkResource, Transport, ResUdpSocket};
 use rand::prelude::*;
 use rand_chacha::ChaCha8Rng;
 use rand_chacha::rand_core::SeedableRng;
 use crate::networking::NetworkSystem;
-use byteorder::ByteOrder;
 
 use crate::server_types::*;
 use crate::server_util as util;
+use crate::server_diagnostics::{DiagnosticsListener, DIAGNOSTICS_ADDRESS};
 
 #[derive(Parser, Debug)]
 #[command(version, about, long_about = None)]
 struct Args {
+    /// Pass more than once to listen on several addresses, e.g. an IPv4 and an IPv6 one
     #[arg(long, default_value = LISTEN_ADDRESS)]
-    bind: String,
+    bind: Vec<String>,
 
     #[command(flatten)]
-    sim_latency: SimLatencyArgs
+    sim_latency: SimLatencyArgs,
+
+    #[command(flatten)]
+    window: WindowArgs,
+
+    #[command(flatten)]
+    jitter: JitterArgs,
+
+    #[command(flatten)]
+    tick: TickArgs,
+
+    /// Balls are served straight up, plus or minus a random angle up to this many degrees
+    #[arg(long, default_value_t = DEFAULT_SERVE_HALF_ANGLE_DEG)]
+    serve_half_angle_deg: f32,
+
+    /// Serve connection and tick stats as plain text over TCP
+    #[arg(long, default_value_t = false)]
+    diagnostics: bool,
+
+    #[arg(long, default_value = DIAGNOSTICS_ADDRESS)]
+    diagnostics_bind: String,
+
+    /// Disconnect clients that send messages past MAX_DECODE_BYTES instead of just dropping the message
+    #[arg(long, default_value_t = false)]
+    kick_on_oversized: bool,
+
+    /// Never time out quiet clients, e.g. one paused in a debugger. They only leave by disconnecting.
+    #[arg(long, default_value_t = false)]
+    no_timeout: bool,
+
+    /// Split players into teams, one per color index into COLORS, e.g. --team-colors 0,2 for 2v2
+    #[arg(long, value_delimiter = ',', value_parser = clap::value_parser!(u8).range(0..NUM_COLORS as i64))]
+    team_colors: Vec<u8>,
+
+    /// Sort world state entities by NetId so identical worlds encode to identical bytes
+    #[arg(long, action = clap::ArgAction::Set, default_value_t = true)]
+    sort_entities: bool,
+
+    /// Send world states this many times a second, rounded to a whole number of ticks. Lower
+    /// than the tick rate saves bandwidth, clients buffer a little more to cover the gaps.
+    #[arg(long, default_value_t = TICK_RATE_HZ)]
+    send_hz: f64,
+
+    /// Space world states to each client at least half a send interval apart, instead of sending
+    /// every tick's packets the moment it ends. Smooths out bursts when several ticks run in one frame.
+    #[arg(long, default_value_t = false)]
+    send_pacing: bool,
+
+    /// Also split world states with more entities than this into several packets. They're always
+    /// split when they wouldn't fit in one.
+    #[arg(long, value_parser = clap::value_parser!(u64).range(1..).map(|v| v as usize))]
+    max_entities_per_packet: Option<usize>,
+
+    /// Hex dump every packet sent and received, logged at trace level
+    #[arg(long, default_value_t = false)]
+    log_packets: bool,
+
+    /// Hold the balls and count down this long before the match starts, 0 starts right away
+    #[arg(long, default_value_t = DEFAULT_COUNTDOWN_S)]
+    countdown_s: f32,
+
+    /// Players needed before the countdown starts
+    #[arg(long, default_value_t = 1)]
+    countdown_players: usize,
+
+    /// Most bytes per second sent to any one client. Past it, that client's world states are dropped
+    /// instead of queued, without affecting anyone else
+    #[arg(long)]
+    client_bytes_per_s: Option<u64>,
+
+    /// What to do with nobody connected. slow ticks at IDLE_TICK_RATE_HZ, so the first packet from a
+    /// new client can wait up to one slow tick
+    #[arg(long, value_enum, default_value_t = IdleMode::Pause)]
+    idle: IdleMode,
+
+    /// Periodically print a text view of the arena to stdout, for headless servers
+    #[arg(long, default_value_t = false)]
+    ascii_view: bool,
+
+    /// Seconds between --ascii-view prints, at least MIN_ASCII_VIEW_INTERVAL_S
+    #[arg(long, default_value_t = 1.0, value_parser = util::parse_ascii_view_interval)]
+    ascii_view_interval_s: f32,
+
+    /// Paddles each player controls, stacked in rows above the usual one
+    #[arg(long, default_value_t = 1, value_parser = clap::value_parser!(u8).range(1..=MAX_PADDLES_PER_PLAYER as i64))]
+    paddles_per_player: u8,
+
+    /// How fast paddles move, in px per second
+    #[arg(long, default_value_t = PADDLE_SPEED)]
+    paddle_speed: f32,
+
+    /// How wide paddles are, in px
+    #[arg(long, default_value_t = PADDLE_SIZE.x)]
+    paddle_width: f32,
+
+    /// How tall paddles are, in px
+    #[arg(long, default_value_t = PADDLE_SIZE.y)]
+    paddle_height: f32,
+
+    /// Where balls are served from: fixed (the middle of the arena) or paddle (just above the owner's paddle)
+    #[arg(long, value_enum, default_value_t = BallSpawn::Fixed)]
+    ball_spawn: BallSpawn,
+
+    /// Seeds paddle placement and serves. Clients get it in world states so they can work out serves themselves.
+    #[arg(long, default_value_t = DEFAULT_SEED)]
+    seed: u64,
+
+    /// What bricks are worth: flat (1 each) or rows (1 for the bottom row, one more per row up)
+    #[arg(long, value_enum, default_value_t = BrickValues::Flat)]
+    brick_values: BrickValues,
+
+    /// Survival mode: every player starts with this many lives, loses one each time their ball hits
+    /// the bottom wall and is out at zero
+    #[arg(long, value_parser = clap::value_parser!(u8).range(1..))]
+    lives: Option<u8>,
+
+    /// Accelerate balls down by this many px/s^2, negative pulls them up. Clients predict it too.
+    #[arg(long, default_value_t = 0.0, allow_negative_numbers = true)]
+    gravity: f32,
+
+    /// How balls are colored: player (same as their paddles) or white
+    #[arg(long, value_enum, default_value_t = BallColors::Player)]
+    ball_colors: BallColors
 }
 
 fn main() {
     let args = Args::parse();
+    let paddle_tuning = PaddleTuning { speed: args.paddle_speed, size: Vec2::new(args.paddle_width, args.paddle_height) };
+    if let Err(e) = paddle_tuning.validate().and(util::check_paddle_height(&paddle_tuning)).and(validate_gravity(args.gravity)) {
+        Args::command().error(clap::error::ErrorKind::ValueValidation, e).exit();
+    }
+    report_timing_issues(util::check_server_timing(&JitterPadding::from(args.jitter), args.countdown_s, args.send_hz))
+        .unwrap_or_else(|msg| Args::command().error(clap::error::ErrorKind::ValueValidation, msg).exit());
     let socket = ResUdpSocket::new_server(&args.bind);
-    let rng = RandomGen{ r: ChaCha8Rng::seed_from_u64(1337) };
+    let rng = RandomGen{ r: ChaCha8Rng::seed_from_u64(args.seed) };
     let generator = NetIdGenerator::default();
 
-    let sim_settings = args.sim_latency.into();
+    let sim_settings = args.sim_latency.settings()
+        .unwrap_or_else(|msg| Args::command().error(clap::error::ErrorKind::InvalidValue, msg).exit());
 
-    println!("Server now listening on {}", args.bind);
+    println!("Server now listening on {}", args.bind.join(", "));
 
-    App::new()
-        .insert_resource(bevy::winit::WinitSettings {
-            focused_mode: bevy::winit::UpdateMode::Continuous,
-            unfocused_mode: bevy::winit::UpdateMode::Continuous,
-        })
-        .add_plugins(DefaultPlugins)
-        .add_plugins(networking::ServerPlugin{sim_settings, no_systems: true})
+    let mut app = App::new();
+    if args.ascii_view {
+        app.insert_resource(AsciiView { timer: Timer::from_seconds(args.ascii_view_interval_s, TimerMode::Repeating) });
+    }
+    if args.diagnostics {
+        println!("Serving diagnostics on {}", args.diagnostics_bind);
+        app.insert_resource(DiagnosticsListener::new(&args.diagnostics_bind));
+    }
+
+    app
+        .insert_resource(args.window.winit_settings(false))
+        .add_plugins(DefaultPlugins.set(args.window.window_plugin()))
+        .add_plugins(networking::ServerPlugin{sim_settings, no_systems: true, log_packets: args.log_packets, client_send_budget: args.client_bytes_per_s, no_idle_timeout: args.no_timeout, send_pacing: args.send_pacing.then(|| send_pacing_gap(args.send_hz)), ..default()})
         .insert_resource(socket)
         .insert_resource(rng)
         .insert_resource(Time::<Fixed>::from_hz(TICK_RATE_HZ))
+        .insert_resource(args.tick.virtual_time())
         .insert_resource(Score(0))
         .insert_resource(ClearColor(BACKGROUND_COLOR))
         .insert_resource(generator)
         .insert_resource(NetConnections::default())
+        .insert_resource(BallAssetCache::default())
         .insert_resource(FixedTickWorldResource::default())
+        .insert_resource(TickTimeHistogram::default())
+        .insert_resource(MatchPause::default())
+        .insert_resource(MatchReset::default())
+        .insert_resource(MatchCountdown::new(args.countdown_s, args.countdown_players))
+        .insert_resource(JitterPadding::from(args.jitter))
+        .insert_resource(WorldStateLayout {
+            sort_by_net_id: args.sort_entities,
+            max_entities_per_packet: args.max_entities_per_packet,
+            send_interval_ticks: send_interval_ticks(args.send_hz)
+        })
+        .add_event::<CollisionEvent>()
+        .insert_resource(PacketPolicy { kick_on_oversized: args.kick_on_oversized })
+        .insert_resource(ServeSettings {
+            half_angle_rad: args.serve_half_angle_deg.to_radians(),
+            seed: args.seed,
+            round: 0,
+            spawn: args.ball_spawn
+        })
+        .insert_resource(PaddleSettings { per_player: args.paddles_per_player as usize, tuning: paddle_tuning, lives: args.lives })
+        .insert_resource(BallSettings { gravity: args.gravity })
+        .insert_resource(BrickSettings { values: args.brick_values })
+        .insert_resource(IdleSettings { mode: args.idle })
+        .insert_resource(ColorAssignment {
+            team_colors: args.team_colors.iter().map(|&c| NetColorIndex(c)).collect(),
+            balls: args.ball_colors
+        })
         .add_systems(Startup, setup)
+        .add_systems(Update, (
+            toggle_pause_on_key,
+            request_reset_on_key,
+            print_ascii_view.run_if(resource_exists::<AsciiView>),
+            // Paced packets held at the end of a tick go out on a later frame
+            networking::systems::send_packet_system.run_if(send_paced)
+        ))
         .add_systems(
             FixedUpdate,
             (
@@ -63,13 +239,28 @@ fn main() {
                 networking::systems::server_recv_packet_system.in_set(NetworkSystem::Receive),
                 networking::systems::idle_timeout_system.in_set(networking::ServerSystem::IdleTimeout),
                 connection_handler,
-                process_input,
-                apply_velocity_system,
-                check_for_collisions,
-                update_scoreboard,
-                broadcast_world_state,
+                send_server_info,
+                update_idle,
+                // With --idle pause and nobody connected only the network keeps going
+                (
+                    reset_match.run_if(reset_requested),
+                    advance_countdown.run_if(match_running),
+                    discard_input_while_paused.run_if(not(match_running)),
+                    process_input.run_if(match_running),
+                    carry_held_balls,
+                    (
+                        apply_spin_system,
+                        apply_gravity_system,
+                        apply_velocity_system,
+                        check_for_collisions,
+                        detect_ball_loss.run_if(survival_mode)
+                    ).chain().run_if(balls_moving),
+                    update_scoreboard,
+                    broadcast_world_state,
+                ).chain().run_if(simulating),
                 networking::systems::send_packet_system.in_set(NetworkSystem::Send),
-                common::end_tick
+                common::end_tick,
+                server_diagnostics::serve_diagnostics.run_if(resource_exists::<DiagnosticsListener>)
             ).chain()
         )
         .run();
@@ -77,7 +268,8 @@ fn main() {
 
 fn setup(
     mut commands: Commands,
-    mut net_id_gen: ResMut<NetIdGenerator>
+    mut net_id_gen: ResMut<NetIdGenerator>,
+    brick_settings: Res<BrickSettings>
 ) {
     // Camera
     commands.spawn(Camera2dBundle::default());
@@ -96,42 +288,7 @@ fn setup(
     commands.spawn(WallBundle::new(WallLocation::Top));
 
     // Bricks
-    let total_width_of_bricks = (RIGHT_WALL - LEFT_WALL) - 2. * GAP_BETWEEN_BRICKS_AND_SIDES;
-    let bottom_edge_of_bricks = PADDLE_Y + GAP_BETWEEN_PADDLE_AND_BRICKS;
-    let total_height_of_bricks = TOP_WALL - bottom_edge_of_bricks - GAP_BETWEEN_BRICKS_AND_CEILING;
-
-    assert!(total_width_of_bricks > 0.0);
-    assert!(total_height_of_bricks > 0.0);
-
-    // Given the space available, compute how many rows and columns of bricks we can fit
-    let n_columns = (total_width_of_bricks / (BRICK_SIZE.x + GAP_BETWEEN_BRICKS)).floor() as usize;
-    let n_rows = (total_height_of_bricks / (BRICK_SIZE.y + GAP_BETWEEN_BRICKS)).floor() as usize;
-    let n_vertical_gaps = n_columns - 1;
-
-    // Because we need to round the number of columns,
-    // the space on the top and sides of the bricks only captures a lower bound, not an exact value
-    let center_of_bricks = (LEFT_WALL + RIGHT_WALL) / 2.0;
-    let left_edge_of_bricks = center_of_bricks
-        // Space taken up by the bricks
-        - (n_columns as f32 / 2.0 * BRICK_SIZE.x)
-        // Space taken up by the gaps
-        - n_vertical_gaps as f32 / 2.0 * GAP_BETWEEN_BRICKS;
-
-    // In Bevy, the `translation` of an entity describes the center point,
-    // not its bottom-left corner
-    let offset_x = left_edge_of_bricks + BRICK_SIZE.x / 2.;
-    let offset_y = bottom_edge_of_bricks + BRICK_SIZE.y / 2.;
-
-    for row in 0..n_rows {
-        for column in 0..n_columns {
-            let brick_position = Vec2::new(
-                offset_x + column as f32 * (BRICK_SIZE.x + GAP_BETWEEN_BRICKS),
-                offset_y + row as f32 * (BRICK_SIZE.y + GAP_BETWEEN_BRICKS),
-            );
-
-            commands.spawn(BrickBundle::new(brick_position, net_id_gen.next()));
-        }
-    }
+    util::spawn_bricks(&mut commands, &mut net_id_gen, brick_settings.values);
 }
 
 fn connection_handler(
@@ -141,45 +298,107 @@ fn connection_handler(
     mut net_id_gen: ResMut<NetIdGenerator>,
     mut client_query: Query<(&mut NetConnection, &mut NetInput)>,
     mut connections: ResMut<NetConnections>,
-    mut meshes: ResMut<Assets<Mesh>>,
-    mut materials: ResMut<Assets<ColorMaterial>>,
+    mut ball_assets: BallAssets,
     mut world_resource: ResMut<FixedTickWorldResource>,
+    mut pause: ResMut<MatchPause>,
+    serve_settings: Res<ServeSettings>,
+    paddle_settings: Res<PaddleSettings>,
+    packet_policy: Res<PacketPolicy>,
+    color_assignment: Res<ColorAssignment>,
+    mut net: ResMut<NetworkResource>,
+    mut socket: ResMut<ResUdpSocket>,
     real_time: Res<Time<Real>>
 ) {
     world_resource.frame_counter += 1;
+    let recv_span = info_span!(
+        parent: &world_resource.tick_span(),
+        "recv",
+        frame = world_resource.frame_counter,
+        inputs = field::Empty
+    ).entered();
     debug!("[{}]", world_resource.frame_counter);
 
     let mut num_inputs_processed = 0;
+    let mut to_disconnect = Vec::new();
+    // Spawns are deferred, so the query won't see players that connected earlier this tick
+    let mut assigned_this_tick = Vec::new();
     for event in events.read() {
         match event {
             NetworkEvent::Connected(handle) => {
                 info!("{}: connected!", handle);
 
                 let next_player = NetPlayerIndex(connections.next_player_index);
-                let paddle_x = rng.r.gen_range(PADDLE_LEFT_BOUND..=PADDLE_RIGHT_BOUND);
-                let paddle_entity = commands.spawn(PaddleBundle::new(Vec2::new(paddle_x, PADDLE_Y), net_id_gen.next(), next_player)).id();
-                let ball_entity = commands.spawn(BallBundle::new(&mut meshes, &mut materials, BALL_STARTING_POSITION, net_id_gen.next(), next_player)).id();
+                let (team, color) = util::assign_color(
+                    &color_assignment,
+                    client_query.iter().map(|(c, _)| (c.team, c.color)).chain(assigned_this_tick.iter().copied())
+                );
+                assigned_this_tick.push((team, color));
+                let mut top_paddle = None;
+                let paddle_entities = (0..paddle_settings.per_player).map(|i| {
+                    let tuning = paddle_settings.tuning;
+                    let paddle_x = rng.r.gen_range(tuning.left_bound()..=tuning.right_bound());
+                    let pos = Vec2::new(paddle_x, util::paddle_row_y(i));
+                    let net_id = net_id_gen.next();
+                    let mut paddle = commands.spawn(PaddleBundle::new(pos, net_id, next_player, color, tuning));
+                    if let Some(lives) = paddle_settings.lives {
+                        paddle.insert(Lives(lives));
+                    }
+                    top_paddle = Some((pos, HeldBy { paddle: paddle.id(), paddle_net_id: net_id }));
+                    paddle.id()
+                }).collect();
+                let ball_net_id = net_id_gen.next();
+                let ball_velocity = serve_velocity(&serve_settings.serve(), ball_net_id);
+                let ball_pos = util::ball_spawn_position(serve_settings.spawn, top_paddle.map(|(pos, _)| (pos, &paddle_settings.tuning)));
+                let mut ball = commands.spawn(BallBundle::new(&mut ball_assets, ball_pos, ball_velocity, ball_net_id, next_player, color_assignment.ball_color(color), 0.0));
+                // If the match is already running it waits on the paddle for this player to serve
+                if let (BallSpawn::Paddle, Some((_, held))) = (serve_settings.spawn, top_paddle) {
+                    ball.insert(held);
+                }
+                let ball_entity = ball.id();
 
                 let id = commands.spawn((
                     NetConnection {
                         addr: *handle,
-                        paddle_entity,
-                        ball_entity,
+                        paddle_entities,
+                        ball_entity: Some(ball_entity),
                         last_applied_input: 0,
-                        player_index: next_player.0
+                        player_index: next_player.0,
+                        suspicious_inputs: 0,
+                        team,
+                        color,
+                        last_input_s: None,
+                        serve_requested: false
                     },
                     NetInput::default()
                 )).id();
                 connections.addr_to_entity.insert(handle.clone(), id);
                 connections.next_player_index += 1;
+
+                if pause.reason == Some(PauseReason::PlayerDropped) &&
+                    connections.addr_to_entity.len() >= pause.resume_player_count {
+                    info!("{} players connected, resuming match", connections.addr_to_entity.len());
+                    pause.reason = None;
+                }
             }
             NetworkEvent::Disconnected(handle) => {
-                info!("{}: disconnected!", handle);
                 util::handle_client_disconnected(
                     handle,
+                    DisconnectCause::Transport,
+                    &mut commands,
+                    &mut client_query,
+                    &mut connections,
+                    &mut pause,
+                );
+            }
+            NetworkEvent::TimedOut(handle, idle) => {
+                info!("{}: timed out after {:.1}s", handle, idle.as_secs_f32());
+                util::handle_client_disconnected(
+                    handle,
+                    DisconnectCause::Timeout,
                     &mut commands,
                     &mut client_query,
                     &mut connections,
+                    &mut pause,
                 );
             }
             NetworkEvent::Message(handle, msg, recv_time) => {
@@ -188,16 +407,20 @@ fn connection_handler(
                     warn!("NetworkEvent::Message received from {}, but player was not found", handle);
                 } else {
                     let id = id.unwrap();
-                    let config = config::standard();
-                    type ClientToServerResult = Result<(ClientToServerPacket, usize), DecodeError>;
-                    let decode_result: ClientToServerResult = bincode::serde::decode_from_slice(msg.as_ref(), config);
-                    match decode_result {
-                        Ok((packet, _)) => {
+                    match ClientToServerPacket::decode(msg.as_ref()) {
+                        Ok(packet) => {
                             match packet {
                                 ClientToServerPacket::Input(input) => {
                                     num_inputs_processed += 1;
                                     //debug!("recv: {}", real_time.elapsed_seconds());
-                                    client_query.get_mut(*id).unwrap().1.inputs.push_back(
+                                    let (mut conn, mut net_input) = client_query.get_mut(*id).unwrap();
+                                    conn.last_input_s = Some(real_time.elapsed_seconds());
+                                    net_input.jitter.record_arrival(*recv_time, TICK_S);
+                                    if net_input.inputs.len() >= MAX_BUFFERED_INPUTS {
+                                        net_input.inputs.pop_front();
+                                        util::flag_suspicious_input(&mut conn, SuspiciousInput::InputFlood);
+                                    }
+                                    net_input.inputs.push_back(
                                         ReceivedPlayerInput {
                                             data: input,
                                             time_received: real_time.elapsed_seconds()
@@ -211,9 +434,33 @@ fn connection_handler(
                                         recv_time.elapsed().as_millis());
                                     client_query.get_mut(*id).unwrap().1.pings.push_back(rtt);
                                 }
+                                ClientToServerPacket::Disconnect => {
+                                    to_disconnect.push((*handle, DisconnectCause::Explicit));
+                                }
+                                ClientToServerPacket::ResyncRequest => {
+                                    let resync = &mut client_query.get_mut(*id).unwrap().1.resync;
+                                    if resync.request(real_time.elapsed_seconds()) {
+                                        info!("{}: resync requested", handle);
+                                    } else {
+                                        debug!("{}: resync refused, {} so far", handle, resync.refused);
+                                    }
+                                }
+                            }
+                        }
+                        Err(PacketDecodeError::Decode(DecodeError::LimitExceeded)) => {
+                            net.decode_errors.record(*handle, msg.len());
+                            warn!("{}: Message from {} decodes past {} bytes, dropping it", id, handle, MAX_DECODE_BYTES);
+                            if packet_policy.kick_on_oversized {
+                                warn!("{}: kicked for oversized message", handle);
+                                to_disconnect.push((*handle, DisconnectCause::Kicked));
                             }
                         }
+                        Err(PacketDecodeError::TrailingBytes(extra)) => {
+                            net.decode_errors.record(*handle, msg.len());
+                            warn!("{}: Message from {} has {} trailing bytes, dropping it {:?}", id, handle, extra, msg);
+                        }
                         Err(err) => {
+                            net.decode_errors.record(*handle, msg.len());
                             warn!("{}: Error parsing message from {}: {:?} {:?}", id, handle, err, msg);
                         }
                     }
@@ -224,9 +471,11 @@ fn connection_handler(
             NetworkEvent::SendError(handle, err, msg) => {
                 util::handle_client_disconnected(
                     handle,
+                    DisconnectCause::SendError,
                     &mut commands,
                     &mut client_query,
                     &mut connections,
+                    &mut pause,
                 );
                 error!(
                     "NetworkEvent::SendError (payload [{:?}]): {:?}",
@@ -239,44 +488,105 @@ fn connection_handler(
         }
     }
 
+    for (handle, cause) in to_disconnect {
+        // Forgetting the connection means their next packet is a fresh connect, same as a timeout
+        net.forget_peer(&handle, &mut socket);
+        util::handle_client_disconnected(
+            &handle,
+            cause,
+            &mut commands,
+            &mut client_query,
+            &mut connections,
+            &mut pause,
+        );
+    }
+
+    recv_span.record("inputs", num_inputs_processed);
     debug!("{} inputs processed!", num_inputs_processed);
 }
 
 fn broadcast_world_state(
-    bricks: Query<(&Transform, &NetId), With<Brick>>,
-    balls: Query<(&Transform, &NetId, &Velocity, &NetPlayerIndex) , With<Ball>>,
-    paddles: Query<(&Transform, &NetId, &NetPlayerIndex), With<Paddle>>,
+    bricks: Query<(&Transform, &NetId, &Brick)>,
+    balls: Query<(&Transform, &NetId, &Velocity, &Spin, &NetPlayerIndex, &NetColorIndex, Option<&HeldBy>) , With<Ball>>,
+    paddles: Query<(&Transform, &NetId, &NetPlayerIndex, &NetColorIndex, Option<&Lives>), With<Paddle>>,
     score: Res<Score>,
     mut transport: ResMut<Transport>,
     world_resource: Res<FixedTickWorldResource>,
     connections: ResMut<NetConnections>,
+    pause: Res<MatchPause>,
     mut client_query: Query<(&NetConnection, &mut NetInput)>,
+    mut collision_events: EventReader<CollisionEvent>,
+    mut recent_collisions: Local<VecDeque<CollisionEvent>>,
+    real_time: Res<Time<Real>>,
+    padding: Res<JitterPadding>,
+    (layout, hook): (Res<WorldStateLayout>, Option<Res<WorldStateHook>>),
+    countdown: Res<MatchCountdown>,
+    serve_settings: Res<ServeSettings>,
 ) {
-    if connections.addr_to_entity.is_empty() {
+    // Keep recent collisions even with nobody connected so the window doesn't start stale
+    recent_collisions.extend(collision_events.read().copied());
+    while recent_collisions.front().is_some_and(|c| world_resource.frame_counter - c.frame > COLLISION_RESEND_FRAMES) {
+        recent_collisions.pop_front();
+    }
+
+    // Collisions from the ticks in between are still in recent_collisions for the next send
+    let send_tick = world_resource.frame_counter % layout.send_interval_ticks as u32 == 0;
+    if connections.addr_to_entity.is_empty() || !send_tick {
         return;
     }
 
+    let broadcast_span = info_span!(
+        parent: &world_resource.tick_span(),
+        "broadcast",
+        frame = world_resource.frame_counter,
+        connections = connections.addr_to_entity.len(),
+        entities = field::Empty,
+        bytes = field::Empty
+    ).entered();
+
     // This is definitely not as fast as it could be. Hand-serializing
     // directly into a buffer is probably faster than first copying into here?
     let mut world = NetWorldStateData::default();
     world.frame = world_resource.frame_counter;
-    for (transform, &id) in bricks.iter() {
+    world.paused = pause.is_paused();
+    world.phase = countdown.phase;
+    if countdown.phase != MatchPhase::Playing {
+        world.serve = Some(serve_settings.serve());
+    }
+    for (transform, &id, brick) in bricks.iter() {
         world.entities.push(NetEntity {
-            entity_type: NetEntityType::Brick(NetBrickData { pos: transform.translation.xy() }),
+            entity_type: NetEntityType::Brick(NetBrickData { pos: transform.translation.xy(), value: brick.value }),
             net_id: id
         });
     }
 
-    for (transform, &id, velocity, &player) in balls.iter() {
+    // advance_countdown runs before the ball systems, so balls move on the same tick the countdown ends
+    let launch_frame = countdown.phase.ticks_until_play().map(|t| world.frame + t);
+    for (transform, &id, velocity, spin, &player, &color, held) in balls.iter() {
+        // Still held during play means it's waiting for a Serve nobody can schedule ahead of time
+        let waiting_for_serve = held.is_some() && countdown.phase == MatchPhase::Playing;
         world.entities.push(NetEntity {
-            entity_type: NetEntityType::Ball(NetBallData { pos: transform.translation.xy(), velocity: velocity.0, player_index: player }),
+            entity_type: NetEntityType::Ball(NetBallData {
+                pos: transform.translation.xy(),
+                velocity: velocity.0,
+                spin: spin.0,
+                launch_frame: if waiting_for_serve { None } else { launch_frame },
+                held_by: held.map(|h| h.paddle_net_id),
+                player_index: player,
+                color
+            }),
             net_id: id
         });
     }
 
-    for (transform, &id, &player) in paddles.iter() {
+    for (transform, &id, &player, &color, lives) in paddles.iter() {
         world.entities.push(NetEntity {
-            entity_type: NetEntityType::Paddle(NetPaddleData { pos: transform.translation.xy(), player_index: player }),
+            entity_type: NetEntityType::Paddle(NetPaddleData {
+                pos: transform.translation.xy(),
+                player_index: player,
+                color,
+                lives: lives.map(|l| l.0)
+            }),
             net_id: id
         });
     }
@@ -286,26 +596,69 @@ fn broadcast_world_state(
         net_id: NetId(0) // Singleton entity
     });
 
-    let packet = ServerToClientPacket::WorldState(world);
-    let mut world_state_buf = [0; networking::ETHERNET_MTU];
-    byteorder::NetworkEndian::write_u32(&mut world_state_buf, WORLD_PACKET_HEADER_TAG);
-    // A U32 HERE will be the only one changed, min serialization overhead
+    if layout.sort_by_net_id {
+        world.entities.sort_unstable_by_key(|e| e.net_id);
+    }
 
-    // Will just blow up if world state gets to big, fine by me right now
-    let num_bytes = HEADER_LEN + bincode::serde::encode_into_slice(packet, &mut world_state_buf[HEADER_LEN..], config::standard()).unwrap();
+    // Newest first, if there are too many the oldest ones have already been sent a few times
+    world.collisions.extend(recent_collisions.iter().rev().take(MAX_COLLISIONS_PER_PACKET).copied());
+
+    broadcast_span.record("entities", world.entities.len());
+    // Only the header changes per client, min serialization overhead
+    let now = real_time.elapsed_seconds();
+    let mut total_bytes = 0;
+    for part in util::split_world_state(world, MAX_WORLD_STATE_BYTES, layout.max_entities_per_packet) {
+        let (frame, part_index, part_count) = (part.frame, part.part, part.part_count);
+        let mut world_state_buf = [0; networking::ETHERNET_MTU];
+        let encode = |part: NetWorldStateData, buf: &mut [u8]| {
+            bincode::serde::encode_into_slice(ServerToClientPacket::WorldState(part), &mut buf[HEADER_LEN..], NET_CONFIG)
+                .map(|n| HEADER_LEN + n)
+        };
+        let recipients = client_query.iter();
+
+        // Only part 0 carries custom, the rest are the same for everyone and stay shared
+        if let Some(hook) = hook.as_ref().filter(|_| part_index == 0) {
+            for (conn, input) in recipients {
+                let mut part = part.clone();
+                (hook.0)(&mut part, conn);
+                let num_bytes = match encode(part, &mut world_state_buf) {
+                    Ok(n) => n,
+                    Err(e) => {
+                        error!("{}: WorldStateHook left world state part {}/{} for frame {} too big for a packet: {:?}", conn.addr, part_index + 1, part_count, frame, e);
+                        continue;
+                    }
+                };
+                total_bytes += num_bytes;
+                util::write_header(&mut world_state_buf[..num_bytes], conn, input, &padding, &layout, now);
+                transport.send(conn.addr, &world_state_buf[..num_bytes]);
+            }
+            continue;
+        }
+
+        let num_bytes = match encode(part, &mut world_state_buf) {
+            Ok(n) => n,
+            Err(e) => {
+                error!("World state part {}/{} for frame {} doesn't fit in a packet: {:?}", part_index + 1, part_count, frame, e);
+                continue;
+            }
+        };
+        total_bytes += num_bytes;
 
-    for (conn, mut input) in client_query.iter_mut() {
         // Hand-serializing only the data that changes. This means we do the least serialization per client
-        byteorder::NetworkEndian::write_u32(&mut world_state_buf[size_of::<u32>()..], conn.last_applied_input);
-        world_state_buf[size_of::<u32>() * 2] = conn.player_index;
-        transport.send(conn.addr, &world_state_buf[..num_bytes]);
+        transport.broadcast_with(recipients, &mut world_state_buf[..num_bytes], |(conn, input), buf| {
+            util::write_header(buf, conn, input, &padding, &layout, now);
+            conn.addr
+        });
+    }
+    broadcast_span.record("bytes", total_bytes);
 
+    for (conn, mut input) in client_query.iter_mut() {
         let mut ping_buf = [0; networking::ETHERNET_MTU];
-        util::write_header(&mut ping_buf, conn);
+        util::write_header(&mut ping_buf, conn, &input, &padding, &layout, now);
 
         for ping in &input.pings {
             let packet = ServerToClientPacket::Pong(ping.clone());
-            let num_bytes = HEADER_LEN + bincode::serde::encode_into_slice(packet, &mut ping_buf[HEADER_LEN..], config::standard()).unwrap();
+            let num_bytes = HEADER_LEN + bincode::serde::encode_into_slice(packet, &mut ping_buf[HEADER_LEN..], NET_CONFIG).unwrap();
 
             debug!("Sent ping {} to {} at {:?}", ping.ping_id, conn.addr, time::Instant::now());
 
@@ -315,22 +668,345 @@ fn broadcast_world_state(
     }
 }
 
-fn apply_velocity_system(mut query: Query<(&mut Transform, &Velocity)>, time: Res<Time<Fixed>>) {
+// New connections get it right away, everyone gets it again every SERVER_INFO_RESEND_S
+fn send_server_info(
+    client_query: Query<(Ref<NetConnection>, &NetInput)>,
+    mut transport: ResMut<Transport>,
+    layout: Res<WorldStateLayout>,
+    padding: Res<JitterPadding>,
+    paddle_settings: Res<PaddleSettings>,
+    ball_settings: Res<BallSettings>,
+    color_assignment: Res<ColorAssignment>,
+    real_time: Res<Time<Real>>,
+    mut last_resend_s: Local<f32>,
+) {
+    let now = real_time.elapsed_seconds();
+    let resend = now - *last_resend_s >= SERVER_INFO_RESEND_S;
+    if resend {
+        *last_resend_s = now;
+    } else if !client_query.iter().any(|(conn, _)| conn.is_added()) {
+        return;
+    }
+
+    let packet = ServerToClientPacket::ServerInfo(NetServerInfo {
+        tick_hz: TICK_RATE_HZ as u16,
+        send_interval_ticks: layout.send_interval_ticks,
+        paddles_per_player: paddle_settings.per_player as u8,
+        teams: color_assignment.team_colors.len() as u8,
+        arena_size: arena_size(),
+        paddle: paddle_settings.tuning,
+        lives: paddle_settings.lives.unwrap_or(0),
+        gravity: ball_settings.gravity
+    });
+    let mut buf = [0; networking::ETHERNET_MTU];
+    let num_bytes = HEADER_LEN + bincode::serde::encode_into_slice(packet, &mut buf[HEADER_LEN..], NET_CONFIG).unwrap();
+    for (conn, input) in client_query.iter().filter(|(conn, _)| resend || conn.is_added()) {
+        util::write_header(&mut buf[..num_bytes], &conn, input, &padding, &layout, now);
+        // Clients don't spawn paddles until they have it, so it skips sim loss, the budget and pacing
+        transport.send_immediate(conn.addr, &buf[..num_bytes]);
+    }
+}
+
+fn match_running(pause: Res<MatchPause>) -> bool {
+    !pause.is_paused()
+}
+
+fn balls_moving(pause: Res<MatchPause>, countdown: Res<MatchCountdown>) -> bool {
+    !pause.is_paused() && countdown.phase == MatchPhase::Playing
+}
+
+fn print_ascii_view(
+    time: Res<Time>,
+    mut view: ResMut<AsciiView>,
+    world_resource: Res<FixedTickWorldResource>,
+    score: Res<Score>,
+    bricks: Query<&Transform, With<Brick>>,
+    paddles: Query<&Transform, With<Paddle>>,
+    balls: Query<&Transform, With<Ball>>,
+) {
+    if !view.timer.tick(time.delta()).just_finished() {
+        return;
+    }
+
+    let xy = |t: &Transform| t.translation.xy();
+    println!(
+        "frame {} score {}\n{}",
+        world_resource.frame_counter,
+        score.0,
+        util::render_ascii(bricks.iter().map(xy), paddles.iter().map(xy), balls.iter().map(xy))
+    );
+}
+
+fn send_paced(transport: Res<Transport>) -> bool {
+    transport.is_paced()
+}
+
+fn survival_mode(paddle_settings: Res<PaddleSettings>) -> bool {
+    paddle_settings.lives.is_some()
+}
+
+fn simulating(idle: Res<IdleSettings>, connections: Res<NetConnections>) -> bool {
+    idle.mode != IdleMode::Pause || !connections.addr_to_entity.is_empty()
+}
+
+// Drops to IDLE_TICK_RATE_HZ in slow mode once the last client leaves and comes back up on connect
+fn update_idle(
+    idle: Res<IdleSettings>,
+    connections: Res<NetConnections>,
+    mut fixed_time: ResMut<Time<Fixed>>,
+    mut was_idle: Local<bool>,
+) {
+    let is_idle = connections.addr_to_entity.is_empty();
+    if is_idle == *was_idle {
+        return;
+    }
+    *was_idle = is_idle;
+
+    if is_idle {
+        info!("No clients connected, idling ({:?})", idle.mode);
+    } else {
+        info!("Client connected, done idling");
+    }
+    if idle.mode == IdleMode::Slow {
+        fixed_time.set_timestep_hz(if is_idle { IDLE_TICK_RATE_HZ } else { TICK_RATE_HZ });
+    }
+}
+
+fn advance_countdown(
+    mut countdown: ResMut<MatchCountdown>,
+    connections: Res<NetConnections>,
+) {
+    match countdown.phase {
+        MatchPhase::Playing => {}
+        MatchPhase::WaitingForPlayers => {
+            if connections.addr_to_entity.len() >= countdown.min_players {
+                info!("{} players connected, starting countdown", connections.addr_to_entity.len());
+                countdown.phase = MatchPhase::Countdown(countdown.countdown_ticks);
+            }
+        }
+        MatchPhase::Countdown(ticks) => {
+            countdown.phase = if ticks > 1 { MatchPhase::Countdown(ticks - 1) } else { MatchPhase::Playing };
+        }
+    }
+}
+
+// Admin command, the server window is the only UI we have
+fn toggle_pause_on_key(
+    keyboard_input: Res<ButtonInput<KeyCode>>,
+    mut pause: ResMut<MatchPause>,
+) {
+    if !keyboard_input.just_pressed(KeyCode::KeyP) {
+        return;
+    }
+
+    if pause.is_paused() {
+        info!("Match resumed by admin");
+        pause.reason = None;
+    } else {
+        info!("Match paused by admin");
+        pause.reason = Some(PauseReason::Admin);
+    }
+}
+
+fn request_reset_on_key(
+    keyboard_input: Res<ButtonInput<KeyCode>>,
+    mut reset: ResMut<MatchReset>,
+) {
+    if keyboard_input.just_pressed(KeyCode::KeyR) {
+        reset.requested = true;
+    }
+}
+
+fn reset_requested(reset: Res<MatchReset>) -> bool {
+    reset.requested
+}
+
+// Everything happens in this one system and its commands are applied before broadcast_world_state,
+// so clients go straight from the old round to the new one with no half reset state in between
+fn reset_match(
+    mut commands: Commands,
+    mut reset: ResMut<MatchReset>,
+    mut countdown: ResMut<MatchCountdown>,
+    mut score: ResMut<Score>,
+    mut net_id_gen: ResMut<NetIdGenerator>,
+    mut serve_settings: ResMut<ServeSettings>,
+    brick_settings: Res<BrickSettings>,
+    brick_query: Query<Entity, With<Brick>>,
+    mut ball_query: Query<(Entity, &mut Transform, &mut Velocity, &mut Spin, &NetId), With<Ball>>,
+    paddle_query: Query<(&Transform, &NetId, &PaddleTuning), (With<Paddle>, Without<Ball>)>,
+    client_query: Query<&NetConnection>,
+    mut pause: ResMut<MatchPause>,
+) {
+    info!("Resetting match");
+    reset.requested = false;
+    // The new round is for whoever is still here, an admin pause stays until P is pressed again
+    if pause.reason == Some(PauseReason::PlayerDropped) {
+        info!("Resuming match paused for a dropped player");
+        pause.reason = None;
+    }
+    countdown.restart();
+    score.0 = 0;
+    serve_settings.round += 1;
+
+    for brick in brick_query.iter() {
+        commands.entity(brick).despawn();
+    }
+    util::spawn_bricks(&mut commands, &mut net_id_gen, brick_settings.values);
+
+    let serve = serve_settings.serve();
+    for (ball, mut transform, mut velocity, mut spin, &net_id) in ball_query.iter_mut() {
+        let owner_paddle = client_query
+            .iter()
+            .find(|conn| conn.ball_entity == Some(ball))
+            .and_then(|conn| conn.paddle_entities.last())
+            .and_then(|&paddle| paddle_query.get(paddle).ok().map(|(t, &paddle_net_id, tuning)| (t, tuning, HeldBy { paddle, paddle_net_id })));
+        if let (BallSpawn::Paddle, Some((_, _, held))) = (serve_settings.spawn, owner_paddle) {
+            commands.entity(ball).insert(held);
+        }
+        let pos = util::ball_spawn_position(serve_settings.spawn, owner_paddle.map(|(t, tuning, _)| (t.translation.xy(), tuning)));
+        transform.translation = Vec3::from((pos, transform.translation.z));
+        velocity.0 = serve_velocity(&serve, net_id);
+        spin.0 = 0.0;
+    }
+}
+
+// With --ball-spawn paddle a served ball rides on its owner's top paddle until the countdown ends,
+// or during play until its owner presses Serve. It's placed after inputs are applied, so on the
+// launch tick it leaves from wherever the paddle is. Clients do the same in reconcile using
+// NetBallData::held_by.
+fn carry_held_balls(
+    mut commands: Commands,
+    mut ball_query: Query<(Entity, &mut Transform, &HeldBy), With<Ball>>,
+    paddle_query: Query<(&Transform, &PaddleTuning), (With<Paddle>, Without<Ball>)>,
+    mut client_query: Query<&mut NetConnection>,
+    countdown: Res<MatchCountdown>,
+    mut last_phase: Local<MatchPhase>,
+) {
+    let countdown_ended = countdown.phase == MatchPhase::Playing && *last_phase != MatchPhase::Playing;
+    *last_phase = countdown.phase;
+    let served: Vec<Entity> = client_query
+        .iter_mut()
+        .filter(|conn| conn.serve_requested)
+        .filter_map(|mut conn| {
+            conn.serve_requested = false;
+            conn.ball_entity
+        })
+        .collect();
+
+    for (ball, mut transform, held) in ball_query.iter_mut() {
+        if let Ok((paddle, tuning)) = paddle_query.get(held.paddle) {
+            let pos = held_ball_position(paddle.translation.xy(), tuning);
+            transform.translation = Vec3::from((pos, transform.translation.z));
+        }
+        if countdown_ended || (countdown.phase == MatchPhase::Playing && served.contains(&ball)) {
+            commands.entity(ball).remove::<HeldBy>();
+        }
+    }
+}
+
+// Survival mode: a ball that reaches the bottom wall costs its owner a life and is served again,
+// with --ball-spawn paddle onto their paddle to wait for them to press Serve. Losing the last one
+// despawns the player's paddles and ball, they stay connected and watch.
+fn detect_ball_loss(
+    mut commands: Commands,
+    mut client_query: Query<&mut NetConnection>,
+    mut ball_query: Query<(&mut Transform, &mut Velocity, &mut Spin, &NetId), With<Ball>>,
+    mut paddle_query: Query<(&Transform, &NetId, &PaddleTuning, &mut Lives), (With<Paddle>, Without<Ball>)>,
+    serve_settings: Res<ServeSettings>,
+) {
+    for mut conn in client_query.iter_mut() {
+        let Some(ball) = conn.ball_entity else {
+            continue;
+        };
+        let Ok((mut transform, mut velocity, mut spin, &net_id)) = ball_query.get_mut(ball) else {
+            continue;
+        };
+        if !util::ball_lost(transform.translation.xy()) {
+            continue;
+        }
+
+        let mut remaining = 0;
+        for &paddle in conn.paddle_entities.iter() {
+            if let Ok((_, _, _, mut lives)) = paddle_query.get_mut(paddle) {
+                lives.0 = lives.0.saturating_sub(1);
+                remaining = lives.0;
+            }
+        }
+
+        if remaining == 0 {
+            info!("{}: player {} lost their last ball, eliminated", conn.addr, conn.player_index);
+            for paddle in conn.paddle_entities.drain(..) {
+                commands.entity(paddle).despawn();
+            }
+            commands.entity(ball).despawn();
+            conn.ball_entity = None;
+        } else {
+            info!("{}: player {} lost a ball, {} lives left", conn.addr, conn.player_index, remaining);
+            let owner_paddle = conn.paddle_entities
+                .last()
+                .and_then(|&paddle| paddle_query.get(paddle).ok().map(|(t, &paddle_net_id, tuning, _)| (t, tuning, HeldBy { paddle, paddle_net_id })));
+            if let (BallSpawn::Paddle, Some((_, _, held))) = (serve_settings.spawn, owner_paddle) {
+                commands.entity(ball).insert(held);
+            }
+            let pos = util::ball_spawn_position(serve_settings.spawn, owner_paddle.map(|(t, tuning, _)| (t.translation.xy(), tuning)));
+            transform.translation = Vec3::from((pos, transform.translation.z));
+            velocity.0 = serve_velocity(&serve_settings.serve(), net_id);
+            spin.0 = 0.0;
+        }
+    }
+}
+
+// Inputs that arrive while paused are acked but never applied. If we let them pile up, resuming
+// would consume them all at once and every client would mispredict the catch-up.
+fn discard_input_while_paused(
+    mut client_query: Query<(&mut NetConnection, &mut NetInput)>,
+) {
+    for (mut net_connection, mut net_input) in client_query.iter_mut() {
+        if let Some(last) = net_input.inputs.back() {
+            net_connection.last_applied_input = last.data.sequence;
+        }
+        net_input.inputs.clear();
+        net_input.input_state = NetInputState::Buffering;
+    }
+}
+
+// Held balls ride on their paddle instead, see carry_held_balls
+fn apply_spin_system(mut query: Query<(&mut Velocity, &mut Spin), Without<HeldBy>>) {
+    for (mut velocity, mut spin) in &mut query {
+        apply_spin(&mut velocity, &mut spin);
+    }
+}
+
+fn apply_gravity_system(mut query: Query<&mut Velocity, Without<HeldBy>>, ball_settings: Res<BallSettings>) {
+    for mut velocity in &mut query {
+        apply_gravity(&mut velocity, ball_settings.gravity);
+    }
+}
+
+fn apply_velocity_system(mut query: Query<(&mut Transform, &Velocity), Without<HeldBy>>) {
     for (mut transform, velocity) in &mut query {
-        transform.translation.x += velocity.x * time.delta_seconds();
-        transform.translation.y += velocity.y * time.delta_seconds();
+        apply_velocity(&mut transform, velocity);
     }
 }
 
 pub fn check_for_collisions(
     mut commands: Commands,
     mut score: ResMut<Score>,
-    mut ball_query: Query<(&mut Velocity, &Transform), With<Ball>>,
-    collider_query: Query<(Entity, &Transform, Option<&Brick>), With<Collider>>,
+    mut ball_query: Query<(&mut Velocity, &mut Spin, &Transform, &NetId), (With<Ball>, Without<HeldBy>)>,
+    collider_query: Query<(Entity, &Transform, Option<&Brick>, Has<Paddle>), With<Collider>>,
+    mut collision_events: EventWriter<CollisionEvent>,
+    world_resource: Res<FixedTickWorldResource>,
 ) {
     let mut entities_to_delete = Vec::new();
-    for (mut ball_velocity, ball_transform) in ball_query.iter_mut() {
-        check_single_ball_collision(&mut score, collider_query.iter(), &ball_transform, &mut ball_velocity, &mut entities_to_delete);
+    let mut collisions = Vec::new();
+    // Lowest NetId wins ties, see check_single_ball_collision
+    let mut balls: Vec<_> = ball_query.iter_mut().collect();
+    balls.sort_unstable_by_key(|(_, _, _, net_id)| **net_id);
+    for (mut ball_velocity, mut ball_spin, ball_transform, &ball) in balls {
+        check_single_ball_collision(&mut score, collider_query.iter(), ball_transform, &mut ball_velocity, &mut ball_spin, &mut entities_to_delete, &mut collisions);
+        for (pos, kind) in collisions.drain(..) {
+            collision_events.send(CollisionEvent { frame: world_resource.frame_counter, ball, pos, kind });
+        }
     }
 
     for e in entities_to_delete {
@@ -339,32 +1015,51 @@ pub fn check_for_collisions(
 }
 
 // Not good strict ECS because i'm mutating both input and transforms in the same system, should maybe be broken up with events?
+// Connections are processed in the order util::input_processing_order gives, not query order, so
+// no player is always moved first
 fn process_input(
-    mut client_query: Query<(&mut NetConnection, &mut NetInput)>,
-    mut paddle_query: Query<&mut Transform, With<Paddle>>,
-    fixed_time: Res<Time<Fixed>>,
+    mut client_query: Query<(Entity, &mut NetConnection, &mut NetInput)>,
+    mut paddle_query: Query<(&mut Transform, &PaddleTuning), With<Paddle>>,
     real_time: Res<Time<Real>>,
+    padding: Res<JitterPadding>,
+    world_resource: Res<FixedTickWorldResource>,
+    mut countdown: ResMut<MatchCountdown>,
 ) {
-    for (mut net_connection, mut net_input) in client_query.iter_mut() {
-        let mut paddle_transform = paddle_query.get_mut(net_connection.paddle_entity).unwrap();
+    let now = real_time.elapsed_seconds();
+    let players = client_query.iter().map(|(entity, conn, _)| (conn.player_index, entity)).collect();
+    for entity in util::input_processing_order(players, world_resource.frame_counter) {
+        let (_, mut net_connection, mut net_input) = client_query.get_mut(entity).unwrap();
+        let paddles = net_connection.paddle_entities.clone();
+        let padding_s = padding.effective_s(&net_input.jitter);
 
         let input_state = net_input.input_state;
         match input_state {
             NetInputState::Buffering => {
-                let now = real_time.elapsed_seconds();
                 if net_input.inputs.is_empty() {
-                    info!("EMPTY INPUTS BUFFERING");
+                    let starved_s = now - *net_input.starved_since.get_or_insert(now);
+                    if starved_s >= STALLED_INPUT_S && !net_input.stalled {
+                        warn!("{}: no input for {:.1} s, holding paddles until it comes back", net_connection.addr, starved_s);
+                        net_input.stalled = true;
+                    } else {
+                        debug!("EMPTY INPUTS BUFFERING");
+                    }
                     continue;
-                } else if now - net_input.inputs.front().unwrap().time_received < BUFFER_DELAY_S as f32 {
-                    info!("(NOW {}) {:?}", now, net_input.inputs.iter().map(|input| input.time_received).collect::<Vec<_>>());
+                } else if now - net_input.inputs.front().unwrap().time_received < buffer_delay_s(padding_s) as f32 {
+                    debug!("(NOW {}) {:?}", now, net_input.inputs.iter().map(|input| input.time_received).collect::<Vec<_>>());
                     continue;
                 } else {
+                    if net_input.stalled {
+                        info!("{}: input is back after {:.1} s", net_connection.addr, now - net_input.starved_since.unwrap_or(now));
+                    }
+                    net_input.starved_since = None;
+                    net_input.stalled = false;
                     net_input.input_state = NetInputState::Playing;
                 }
             }
             NetInputState::Playing => {
                 if net_input.inputs.is_empty()  {
                     info!("EMPTY INPUTS TRANSITION TO BUFFERING");
+                    net_input.starved_since = Some(now);
                     net_input.input_state = NetInputState::Buffering;
                     continue;
                 }
@@ -372,27 +1067,180 @@ fn process_input(
         }
 
         let mut num_consumed = 0;
-        let mut last_consumed;
-        let inputs = &mut net_input.inputs;
+        let mut last_consumed = net_connection.last_applied_input;
+        let NetInput { inputs, applied_delay, .. } = &mut *net_input;
         assert!(!inputs.is_empty());
         loop {
             // Always consume at least one input
             let input = inputs.pop_front().unwrap();
-
-            move_paddle(fixed_time.delta_seconds(), &mut paddle_transform, &input.data);
-
             num_consumed += 1;
-            last_consumed = input.data.sequence;
 
-            if inputs.len() < BUFFER_LEN {
+            match util::validate_input(&input.data, last_consumed, paddles.len()) {
+                Ok(()) => {
+                    for (i, &paddle) in paddles.iter().enumerate() {
+                        if input.data.controls_paddle(i) {
+                            let (mut transform, tuning) = paddle_query.get_mut(paddle).unwrap();
+                            move_paddle(&mut transform, &input.data, tuning);
+                        }
+                    }
+                    if input.data.pressed(NetKey::Serve) {
+                        match countdown.phase {
+                            MatchPhase::WaitingForPlayers => {
+                                info!("{}: served, starting countdown", net_connection.addr);
+                                countdown.phase = MatchPhase::Countdown(countdown.countdown_ticks);
+                            }
+                            MatchPhase::Countdown(_) => {
+                                info!("{}: served, skipping the rest of the countdown", net_connection.addr);
+                                countdown.phase = MatchPhase::Playing;
+                            }
+                            // A no-op unless their ball is waiting on a paddle, see carry_held_balls
+                            MatchPhase::Playing => net_connection.serve_requested = true,
+                        }
+                    }
+                    last_consumed = input.data.sequence;
+                    applied_delay.record(now - input.time_received);
+                }
+                Err(reason) => {
+                    // Still ack garbage keys and paddles so the client stops predicting with them,
+                    // but never ack backwards for a replayed sequence
+                    if reason != SuspiciousInput::ReplayedSequence {
+                        last_consumed = input.data.sequence;
+                    }
+                    util::flag_suspicious_input(&mut net_connection, reason);
+                }
+            }
+
+            if inputs.len() < buffer_len(padding_s) {
                 //info!("BREAK {}  in buffer, {} consumed", inputs.len(), num_consumed);
                 if num_consumed > 1 {
                     info!("{} consumed to catch up, {} remaining in buffer", num_consumed, inputs.len());
                 }
                 break;
+            } else if num_consumed >= MAX_INPUTS_PER_TICK {
+                // Catching up any faster would move the paddle more than MAX_INPUTS_PER_TICK steps
+                // of its speed this tick, leave the rest for next tick
+                util::flag_suspicious_input(&mut net_connection, SuspiciousInput::TooManyInputs);
+                break;
             }
         }
 
         net_connection.last_applied_input = last_consumed;
+        debug!(
+            "{}: input applied {:.1} ms after receive ({:.1} ms smoothed)",
+            net_connection.addr,
+            net_input.applied_delay.last_s * 1000.0,
+            net_input.applied_delay.smoothed_s.unwrap_or(0.0) * 1000.0
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bevy::ecs::system::RunSystemOnce;
+    use std::net::SocketAddr;
+
+    fn broadcast_world(max_entities_per_packet: Option<usize>) -> World {
+        let mut world = World::new();
+        world.insert_resource(Score(0));
+        world.init_resource::<Transport>();
+        world.init_resource::<FixedTickWorldResource>();
+        world.init_resource::<MatchPause>();
+        world.init_resource::<Events<CollisionEvent>>();
+        world.init_resource::<Time<Real>>();
+        world.insert_resource(JitterPadding { configured_s: MIN_JITTER_S, adaptive: false });
+        world.insert_resource(WorldStateLayout { sort_by_net_id: true, max_entities_per_packet, send_interval_ticks: 1 });
+        world.insert_resource(MatchCountdown::new(0.0, 1));
+        world.insert_resource(ServeSettings { half_angle_rad: 0.5, seed: 1, round: 0, spawn: BallSpawn::Fixed });
+        for id in 1..=2 {
+            world.spawn((Transform::default(), NetId(id), Brick { value: 1 }));
+        }
+
+        let mut connections = NetConnections::default();
+        for player_index in 0..2 {
+            let addr: SocketAddr = format!("127.0.0.1:{}", 3000 + player_index as u16).parse().unwrap();
+            let conn = NetConnection {
+                addr,
+                paddle_entities: Vec::new(),
+                ball_entity: None,
+                last_applied_input: 0,
+                player_index,
+                suspicious_inputs: 0,
+                team: None,
+                color: NetColorIndex(0),
+                last_input_s: None,
+                serve_requested: false
+            };
+            connections.addr_to_entity.insert(addr, world.spawn((conn, NetInput::default())).id());
+        }
+        world.insert_resource(connections);
+        world.insert_resource(WorldStateHook::new(|ws, conn| ws.custom = vec![conn.player_index; 3]));
+        world
+    }
+
+    // Every world state part sent, by destination port
+    fn sent_world_states(world: &World) -> Vec<(u16, NetWorldStateData)> {
+        world.resource::<Transport>().get_messages().iter().filter_map(|m| {
+            match ServerToClientPacket::decode(&m.payload) {
+                Ok((_, ServerToClientPacket::WorldState(ws))) => Some((m.destination.port(), ws)),
+                _ => None
+            }
+        }).collect()
+    }
+
+    #[test]
+    fn test_world_state_hook_sets_custom_per_connection() {
+        let mut world = broadcast_world(None);
+        world.run_system_once(broadcast_world_state);
+
+        let mut sent = sent_world_states(&world);
+        sent.sort_by_key(|(port, _)| *port);
+        assert_eq!(sent.len(), 2);
+        assert_eq!(sent[0].1.custom, vec![0; 3]);
+        assert_eq!(sent[1].1.custom, vec![1; 3]);
+        assert_eq!(sent[0].1.entities.len(), sent[1].1.entities.len());
+    }
+
+    #[test]
+    fn test_world_state_hook_only_runs_on_part_zero() {
+        let mut world = broadcast_world(Some(1));
+        world.run_system_once(broadcast_world_state);
+
+        let sent = sent_world_states(&world);
+        // Two bricks and the score, one part each for both connections
+        assert_eq!(sent.len(), 6);
+        for (port, ws) in sent {
+            assert_eq!(ws.part_count, 3);
+            let expected = if ws.part == 0 { vec![(port - 3000) as u8; 3] } else { Vec::new() };
+            assert_eq!(ws.custom, expected);
+        }
+    }
+
+    #[test]
+    fn test_reset_resumes_match_paused_for_dropped_player() {
+        let mut world = broadcast_world(None);
+        world.init_resource::<MatchReset>();
+        world.init_resource::<NetIdGenerator>();
+        world.insert_resource(BrickSettings { values: BrickValues::Flat });
+
+        let dropped: SocketAddr = "127.0.0.1:3001".parse().unwrap();
+        world.run_system_once(move |
+            mut commands: Commands,
+            mut client_query: Query<(&mut NetConnection, &mut NetInput)>,
+            mut connections: ResMut<NetConnections>,
+            mut pause: ResMut<MatchPause>
+        | {
+            util::handle_client_disconnected(&dropped, DisconnectCause::Timeout, &mut commands, &mut client_query, &mut connections, &mut pause);
+        });
+        let pause = world.resource::<MatchPause>();
+        assert_eq!(pause.reason, Some(PauseReason::PlayerDropped));
+        assert_eq!(pause.resume_player_count, 2);
+
+        world.run_system_once(reset_match);
+        assert!(!world.resource::<MatchPause>().is_paused());
+
+        world.resource_mut::<MatchPause>().reason = Some(PauseReason::Admin);
+        world.run_system_once(reset_match);
+        assert_eq!(world.resource::<MatchPause>().reason, Some(PauseReason::Admin));
     }
 }
@@ -0,0 +1,250 @@
+use std::fmt::Write as _;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::{mpsc, Mutex};
+use std::{thread, time};
+use bevy::prelude::*;
+use crate::common::*;
+use crate::networking::{NetworkResource, SimLatencyInFlight, Transport};
+use crate::server_types::*;
+
+pub const DIAGNOSTICS_ADDRESS: &str = "127.0.0.1:7002";
+// Longest a slow or silent client gets to send its request line or read the response
+const CLIENT_TIMEOUT: time::Duration = time::Duration::from_millis(500);
+const MAX_REQUEST_LINE: u64 = 1024;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum DiagnosticsRoute {
+    Stats,
+    // POST /reset, asks for a match reset on the next tick
+    Reset,
+}
+
+impl DiagnosticsRoute {
+    // From an HTTP request line like "POST /reset HTTP/1.1". Anything that isn't a POST to /reset
+    // gets the stats, so a bare nc that never sends a request line still works.
+    pub fn parse(request_line: &str) -> Self {
+        let mut parts = request_line.split_whitespace();
+        let (Some(method), Some(target)) = (parts.next(), parts.next()) else {
+            return DiagnosticsRoute::Stats;
+        };
+        let path = target.split_once('?').map_or(target, |(path, _)| path);
+        if method == "POST" && path.trim_end_matches('/') == "/reset" {
+            DiagnosticsRoute::Reset
+        } else {
+            DiagnosticsRoute::Stats
+        }
+    }
+}
+
+// Parsed on the listener thread, answered by serve_diagnostics with the current stats
+pub struct DiagnosticsRequest {
+    pub route: DiagnosticsRoute,
+    pub addr: SocketAddr,
+    pub reply: mpsc::Sender<String>,
+}
+
+// Plain text over TCP so it can be scraped with curl or nc from a headless box.
+// Every connection gets the current stats and is closed. Sockets live on their own thread so a
+// slow client never stalls the tick, which only formats the stats for requests that came in.
+#[derive(Resource)]
+pub struct DiagnosticsListener(Mutex<mpsc::Receiver<DiagnosticsRequest>>);
+
+impl DiagnosticsListener {
+    pub fn new(bind_addr: &str) -> Self {
+        let listener = TcpListener::bind(bind_addr).expect("could not bind diagnostics listener");
+        let (sender, receiver) = mpsc::channel();
+        thread::Builder::new()
+            .name("diagnostics".to_string())
+            .spawn(move || accept_requests(listener, sender))
+            .expect("could not start diagnostics thread");
+        DiagnosticsListener(Mutex::new(receiver))
+    }
+}
+
+fn accept_requests(listener: TcpListener, requests: mpsc::Sender<DiagnosticsRequest>) {
+    for stream in listener.incoming() {
+        match stream {
+            Ok(stream) => {
+                if !answer_request(stream, &requests) {
+                    // The app is gone
+                    return;
+                }
+            }
+            Err(e) => warn!("Diagnostics accept failed: {:?}", e),
+        }
+    }
+}
+
+// False once the tick side has stopped taking requests
+fn answer_request(mut stream: TcpStream, requests: &mpsc::Sender<DiagnosticsRequest>) -> bool {
+    let Ok(addr) = stream.peer_addr() else {
+        return true;
+    };
+    let _ = stream.set_read_timeout(Some(CLIENT_TIMEOUT));
+    let _ = stream.set_write_timeout(Some(CLIENT_TIMEOUT));
+
+    // Also drains whatever else the client sent along with it, so closing doesn't reset the connection
+    let mut request_line = String::new();
+    let _ = BufReader::new((&stream).take(MAX_REQUEST_LINE)).read_line(&mut request_line);
+
+    let (reply, body) = mpsc::channel();
+    let request = DiagnosticsRequest { route: DiagnosticsRoute::parse(&request_line), addr, reply };
+    if requests.send(request).is_err() {
+        return false;
+    }
+    // Answered on the next fixed tick
+    let Ok(body) = body.recv_timeout(time::Duration::from_secs(1)) else {
+        warn!("Diagnostics request from {} wasn't answered", addr);
+        return true;
+    };
+
+    let response = format!(
+        "HTTP/1.0 200 OK\r\nContent-Type: text/plain\r\nContent-Length: {}\r\n\r\n{}",
+        body.len(),
+        body
+    );
+    if let Err(e) = stream.write_all(response.as_bytes()) {
+        warn!("Failed to write diagnostics to {}: {:?}", addr, e);
+    }
+    true
+}
+
+pub fn serve_diagnostics(
+    listener: Res<DiagnosticsListener>,
+    net: Res<NetworkResource>,
+    world_resource: Res<FixedTickWorldResource>,
+    histogram: Res<TickTimeHistogram>,
+    client_query: Query<(&NetConnection, &NetInput)>,
+    time: Res<Time>,
+    real_time: Res<Time<Real>>,
+    transport: Res<Transport>,
+    in_flight: Res<SimLatencyInFlight>,
+    padding: Res<JitterPadding>,
+    connections: Res<NetConnections>,
+    mut reset: ResMut<MatchReset>,
+) {
+    let requests = listener.0.lock().unwrap();
+    for request in requests.try_iter() {
+        let mut body = String::new();
+        if request.route == DiagnosticsRoute::Reset {
+            info!("Match reset requested by {}", request.addr);
+            reset.requested = true;
+            body.push_str("reset requested\n");
+        }
+        body.push_str(&format_diagnostics(&net, &world_resource, &histogram, &client_query, &time, &real_time, &transport, &padding));
+        let _ = writeln!(
+            body,
+            "sim_latency_in_flight: send {} ({} dropped on overflow) receive {} ({} dropped on overflow)",
+            in_flight.send,
+            in_flight.send_dropped_on_overflow,
+            in_flight.receive,
+            in_flight.receive_dropped_on_overflow
+        );
+        let mut disconnects: Vec<_> = connections.disconnects.iter().collect();
+        disconnects.sort_by_key(|(cause, _)| format!("{:?}", cause));
+        let _ = writeln!(
+            body,
+            "disconnects: {}",
+            disconnects.iter().map(|(cause, count)| format!("{:?} {}", cause, count)).collect::<Vec<_>>().join(", ")
+        );
+        // The listener thread gave up waiting, nothing to do about it
+        let _ = request.reply.send(body);
+    }
+}
+
+fn format_diagnostics(
+    net: &NetworkResource,
+    world_resource: &FixedTickWorldResource,
+    histogram: &TickTimeHistogram,
+    client_query: &Query<(&NetConnection, &NetInput)>,
+    time: &Time,
+    real_time: &Time<Real>,
+    transport: &Transport,
+    padding: &JitterPadding,
+) -> String {
+    let mut out = String::new();
+    let _ = writeln!(out, "frame: {}", world_resource.frame_counter);
+    let _ = writeln!(out, "connections: {}", net.connections.len());
+    let decode_errors = &net.decode_errors;
+    let _ = writeln!(
+        out,
+        "decode_errors: {} ({} bytes){}",
+        decode_errors.total,
+        decode_errors.total_bytes,
+        decode_errors.last.map_or(String::new(), |(addr, len, at)| format!(
+            ", last from {} ({} bytes) {} ms ago", addr, len, at.elapsed().as_millis()
+        ))
+    );
+    let _ = writeln!(
+        out,
+        "send_queue: {} queued, {} dropped on overflow, {} retried after transient errors",
+        transport.queued_len(),
+        transport.dropped_on_overflow(),
+        transport.retried()
+    );
+
+    // The server only echoes pings, RTT is measured on the client, so report activity instead
+    for (conn, input) in client_query.iter() {
+        let last_activity_ms = net.connections
+            .get(&conn.addr)
+            .map(|last| (time.elapsed() - *last).as_millis().to_string())
+            .unwrap_or_else(|| "-".to_string());
+        let last_input_ms = conn
+            .seconds_since_last_input(real_time.elapsed_seconds())
+            .map_or_else(|| "-".to_string(), |s| format!("{:.0}", s * 1000.0));
+        let usage = transport.usage(&conn.addr);
+        let _ = writeln!(
+            out,
+            "  {} player {} last_activity_ms {} last_input_ms {} last_applied_input {} suspicious_inputs {} jitter_padding_ms {:.1} input_delay_ms {:.1} (smoothed {:.1}) sent_bytes_per_s {} throttled {}",
+            conn.addr, conn.player_index, last_activity_ms, last_input_ms, conn.last_applied_input, conn.suspicious_inputs,
+            padding.effective_s(&input.jitter) * 1000.0,
+            input.applied_delay.last_s * 1000.0,
+            input.applied_delay.smoothed_s.unwrap_or(0.0) * 1000.0,
+            usage.map_or(0, |u| u.bytes_last_window),
+            usage.map_or(0, |u| u.throttled)
+        );
+        if let Some(count) = decode_errors.by_addr.get(&conn.addr) {
+            let _ = writeln!(out, "    decode_errors {}", count);
+        }
+        if input.resync.last_granted_s.is_some() || input.resync.refused > 0 {
+            let _ = writeln!(out, "    resync last granted {:.1}s, {} refused", input.resync.last_granted_s.unwrap_or(0.0), input.resync.refused);
+        }
+        if input.stalled {
+            let _ = writeln!(out, "    input stalled, paddles holding");
+        }
+        if let Some(heartbeats) = net.heartbeats.get(&conn.addr) {
+            let _ = writeln!(
+                out,
+                "    heartbeats {} stale {} one_way_excess_ms {}",
+                heartbeats.received,
+                heartbeats.stale,
+                heartbeats.one_way_excess.map_or("-".to_string(), |e| e.as_millis().to_string())
+            );
+        }
+    }
+
+    let tick_times = &world_resource.recent_tick_times;
+    if !tick_times.is_empty() {
+        let total: time::Duration = tick_times.iter().sum();
+        let max = tick_times.iter().max().unwrap();
+        let _ = writeln!(
+            out,
+            "tick_us: avg {} max {} (last {} ticks)",
+            (total / tick_times.len() as u32).as_micros(),
+            max.as_micros(),
+            tick_times.len()
+        );
+    }
+
+    let _ = writeln!(
+        out,
+        "tick_us: p50 {} p99 {}, {} of {} ticks over budget",
+        histogram.percentile(0.5).as_micros(),
+        histogram.percentile(0.99).as_micros(),
+        histogram.over_budget,
+        histogram.count
+    );
+
+    out
+}
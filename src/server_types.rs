@@ -1,17 +1,20 @@
 use std::collections::VecDeque;
 use std::net::SocketAddr;
+use std::time;
 use bevy::color::Color;
 use bevy::math::Vec2;
 use bevy::prelude::{Component, Entity, Resource};
 use bevy::utils::HashMap;
 use rand_chacha::ChaCha8Rng;
 use crate::common::*;
+use crate::networking;
 
 pub const GAP_BETWEEN_PADDLE_AND_FLOOR: f32 = 60.0;
 // How close can the paddle get to the wall
 
 // We set the z-value of the ball to 1 (WHEN SPAWNING, NOT HERE) so it renders on top in the case of overlapping sprites.
 pub const BALL_STARTING_POSITION: Vec2 = Vec2::new(0.0, -50.0);
+pub const DEFAULT_SERVE_HALF_ANGLE_DEG: f32 = 45.0;
 pub const PADDLE_Y: f32 = BOTTOM_WALL + GAP_BETWEEN_PADDLE_AND_FLOOR;
 pub const GAP_BETWEEN_PADDLE_AND_BRICKS: f32 = 270.0;
 pub const GAP_BETWEEN_BRICKS: f32 = 5.0;
@@ -22,16 +25,52 @@ pub const BACKGROUND_COLOR: Color = Color::srgb(0.9, 0.9, 0.9);
 
 
 pub const LISTEN_ADDRESS: &str = "127.0.0.1:7001";
-pub const BUFFER_DELAY_S: f64 = 5.0 * TICK_S + MIN_JITTER_S;
-pub const BUFFER_LEN: usize = 1 + ((BUFFER_DELAY_S / TICK_S) as usize);
+pub fn buffer_delay_s(jitter_padding_s: f64) -> f64 {
+    5.0 * TICK_S + jitter_padding_s
+}
+
+pub fn buffer_len(jitter_padding_s: f64) -> usize {
+    1 + ((buffer_delay_s(jitter_padding_s) / TICK_S) as usize)
+}
+// Most inputs one connection can have applied in a single tick while catching up. Every input moves
+// the paddle at most its tuning's speed * TICK_S, so this bounds how far a paddle can move per tick.
+pub const MAX_INPUTS_PER_TICK: usize = 4;
+pub const DEFAULT_COUNTDOWN_S: f32 = 3.0;
+// Most inputs one connection can have buffered, 2s worth. Honest clients send one per tick and sit
+// around buffer_len, past this they're flooding and the oldest input is dropped for each new one.
+pub const MAX_BUFFERED_INPUTS: usize = 2 * TICK_RATE_HZ as usize;
 
 #[derive(Component)]
 pub struct NetConnection {
     pub addr: SocketAddr,
-    pub paddle_entity: Entity,
-    pub ball_entity: Entity,
+    pub paddle_entities: Vec<Entity>, // In spawn order, PlayerInputData::paddle indexes into this. Empty once eliminated.
+    pub ball_entity: Option<Entity>, // None once eliminated
     pub last_applied_input: u32,
-    pub player_index: u8
+    pub player_index: u8,
+    pub suspicious_inputs: u32,
+    pub team: Option<u8>,
+    pub color: NetColorIndex,
+    // Time<Real> seconds when an input packet last arrived, None until the first one
+    pub last_input_s: Option<f32>,
+    // Set by a Serve input this tick, carry_held_balls launches the ball if it's waiting on a paddle
+    pub serve_requested: bool
+}
+
+impl NetConnection {
+    // None if no input has arrived yet. Keeps growing while the player is AFK or their uplink is
+    // out, well before the idle timeout kicks in, since heartbeats and pings still come in.
+    pub fn seconds_since_last_input(&self, now_s: f32) -> Option<f32> {
+        self.last_input_s.map(|last| now_s - last)
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum SuspiciousInput {
+    ReplayedSequence,
+    UnknownKeys,
+    UnknownPaddle,
+    TooManyInputs,
+    InputFlood
 }
 
 #[derive(Default)]
@@ -40,24 +79,316 @@ pub struct ReceivedPlayerInput {
     pub time_received: f32
 }
 
-#[derive(Clone, Copy, Default)]
-pub enum NetInputState {
-    #[default]
-    Buffering,
-    Playing
-}
-
 #[derive(Component, Default)]
 pub struct NetInput {
     pub input_state: NetInputState,
     pub inputs: VecDeque<ReceivedPlayerInput>,
-    pub pings: VecDeque<PingData> // Not a good place for this, but being fast
+    pub jitter: JitterEstimator,
+    pub applied_delay: AppliedInputDelay,
+    pub pings: VecDeque<PingData>, // Not a good place for this, but being fast
+    // When the input buffer last ran dry, None while inputs are arriving
+    pub starved_since: Option<f32>,
+    // Set once starved for STALLED_INPUT_S, so the stall is logged once instead of every tick
+    pub stalled: bool,
+    pub resync: ResyncRequests
+}
+
+// Every world state is already a full one, so granting a resync doesn't change what we send. Until
+// there are delta states this is only protocol plumbing, the rate limit and counters are all it does.
+#[derive(Default, Clone, Copy, Debug)]
+pub struct ResyncRequests {
+    pub last_granted_s: Option<f32>,
+    pub refused: u32
+}
+
+impl ResyncRequests {
+    // Returns whether the request was granted, refused ones are inside RESYNC_MIN_INTERVAL_S of the last
+    pub fn request(&mut self, now_s: f32) -> bool {
+        if self.last_granted_s.is_some_and(|last| now_s - last < RESYNC_MIN_INTERVAL_S) {
+            self.refused += 1;
+            return false;
+        }
+        self.last_granted_s = Some(now_s);
+        true
+    }
+}
+
+// No input for this long means the client's uplink is probably gone, paddles just hold where they are
+pub const STALLED_INPUT_S: f32 = 1.0;
+
+// Time from an input arriving to process_input applying it, i.e. the latency our buffering adds
+#[derive(Default, Clone, Copy, Debug)]
+pub struct AppliedInputDelay {
+    pub last_s: f32,
+    pub smoothed_s: Option<f32>,
+}
+
+impl AppliedInputDelay {
+    pub fn record(&mut self, delay_s: f32) {
+        self.last_s = delay_s;
+        self.smoothed_s = Some(match self.smoothed_s {
+            Some(smoothed) => (smoothed * 7.0 + delay_s) / 8.0,
+            None => delay_s
+        });
+    }
+
+    pub fn smoothed_ms(&self) -> u16 {
+        (self.smoothed_s.unwrap_or(0.0) * 1000.0) as u16
+    }
 }
 
 #[derive(Resource, Default)]
 pub struct NetConnections {
     pub addr_to_entity: HashMap<SocketAddr, Entity>,    // Players are removed when they disconnect
-    pub next_player_index: u8
+    pub next_player_index: u8,
+    pub disconnects: HashMap<DisconnectCause, u32>
+}
+
+// Why a player was dropped, logged with every disconnect and counted in the diagnostics
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub enum DisconnectCause {
+    // The client sent Disconnect
+    Explicit,
+    // Nothing heard from the client for the idle timeout
+    Timeout,
+    // Sending to the client failed with an error that retrying won't fix
+    SendError,
+    // Kicked for sending an oversized message
+    Kicked,
+    // The transport reported the connection gone
+    Transport
+}
+
+// Empty team_colors is free-for-all, each player gets a color nobody else connected is using.
+// Otherwise players join the smallest team and take that team's color.
+#[derive(Resource, Default)]
+pub struct ColorAssignment {
+    pub team_colors: Vec<NetColorIndex>,
+    pub balls: BallColors
+}
+
+impl ColorAssignment {
+    pub fn ball_color(&self, player_color: NetColorIndex) -> NetColorIndex {
+        match self.balls {
+            BallColors::Player => player_color,
+            BallColors::White => NetColorIndex::NEUTRAL
+        }
+    }
+}
+
+// How balls are colored. Paddles always take their player's color.
+#[derive(clap::ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum BallColors {
+    // Same as the player's paddles
+    #[default]
+    Player,
+    // Every ball is white, so they stand apart from the paddles
+    White
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum PauseReason {
+    Admin,
+    PlayerDropped
+}
+
+#[derive(Resource, Default)]
+pub struct MatchPause {
+    pub reason: Option<PauseReason>,
+    // A PlayerDropped pause resumes on its own once this many players are connected again
+    pub resume_player_count: usize
+}
+
+impl MatchPause {
+    pub fn is_paused(&self) -> bool {
+        self.reason.is_some()
+    }
+}
+
+#[derive(Resource)]
+pub struct MatchCountdown {
+    pub phase: MatchPhase,
+    pub countdown_ticks: u16, // 0 turns the countdown off
+    pub min_players: usize
+}
+
+impl MatchCountdown {
+    pub fn new(countdown_s: f32, min_players: usize) -> Self {
+        let countdown_ticks = (countdown_s as f64 * TICK_RATE_HZ).round() as u16;
+        let mut countdown = MatchCountdown { phase: MatchPhase::Playing, countdown_ticks, min_players };
+        countdown.restart();
+        countdown
+    }
+
+    // Back to waiting, the countdown runs again once enough players are connected
+    pub fn restart(&mut self) {
+        if self.countdown_ticks > 0 {
+            self.phase = MatchPhase::WaitingForPlayers;
+        }
+    }
+}
+
+// Set by the admin key or the diagnostics endpoint, reset_match picks it up at a fixed point in the tick
+#[derive(Resource, Default)]
+pub struct MatchReset {
+    pub requested: bool
+}
+
+// Room for one world state part once the header and codec flag are in front of it
+pub const MAX_WORLD_STATE_BYTES: usize = networking::ETHERNET_MTU - networking::codec::MAX_OVERHEAD - HEADER_LEN;
+
+// Query iteration order isn't stable, sorting makes identical worlds serialize to identical bytes
+#[derive(Resource)]
+pub struct WorldStateLayout {
+    pub sort_by_net_id: bool,
+    // World states that don't fit in an MTU, or have more entities than this if set, are split into
+    // several packets for the same frame
+    pub max_entities_per_packet: Option<usize>,
+    // World states go out every this many ticks, from --send-hz
+    pub send_interval_ticks: u8
+}
+
+// --send-hz rounded to a whole number of ticks between sends
+pub fn send_interval_ticks(send_hz: f64) -> u8 {
+    (TICK_RATE_HZ / send_hz).round().clamp(1.0, u8::MAX as f64) as u8
+}
+
+// --send-pacing gap between bursts to one client. Half a send interval splits up ticks that ran in
+// the same frame, while leaving room for the held burst to catch up before the next one is queued.
+pub fn send_pacing_gap(send_hz: f64) -> time::Duration {
+    time::Duration::from_secs_f64(TICK_S * send_interval_ticks(send_hz) as f64 / 2.0)
+}
+
+// Insert one to change world state packets per connection, e.g. strip fields or fill in
+// NetWorldStateData::custom, without touching broadcast_world_state. It runs on every packet right
+// before it's encoded for that connection, only on part 0 when a state is split since the client
+// only keeps part 0's custom. Hooked packets get their own copy and encode per connection, so they
+// cost more than the shared one without.
+#[derive(Resource)]
+#[allow(dead_code)]
+pub struct WorldStateHook(pub Box<dyn Fn(&mut NetWorldStateData, &NetConnection) + Send + Sync>);
+
+#[allow(dead_code)]
+impl WorldStateHook {
+    pub fn new(hook: impl Fn(&mut NetWorldStateData, &NetConnection) + Send + Sync + 'static) -> Self {
+        Self(Box::new(hook))
+    }
+}
+
+#[derive(Resource)]
+pub struct PacketPolicy {
+    pub kick_on_oversized: bool
+}
+
+pub const ASCII_VIEW_COLUMNS: usize = 72;
+pub const ASCII_VIEW_ROWS: usize = 24;
+// Anything faster floods the terminal
+pub const MIN_ASCII_VIEW_INTERVAL_S: f32 = 0.1;
+
+// Present when --ascii-view is on
+#[derive(Resource)]
+pub struct AsciiView {
+    pub timer: bevy::time::Timer
+}
+
+// What the server does while nobody is connected
+#[derive(clap::ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum IdleMode {
+    // Keep receiving, accepting connections and serving diagnostics, but skip the sim and world
+    // state sends entirely
+    #[default]
+    Pause,
+    // Run everything, just at IDLE_TICK_RATE_HZ
+    Slow,
+    // Same as with players connected
+    Run
+}
+
+pub const IDLE_TICK_RATE_HZ: f64 = 4.0;
+
+#[derive(Resource)]
+pub struct IdleSettings {
+    pub mode: IdleMode
+}
+
+// Extra paddles stack up from PADDLE_Y, staying under the ball's starting position and the bricks
+pub const MAX_PADDLES_PER_PLAYER: usize = 3;
+pub const PADDLE_ROW_SPACING: f32 = 80.0;
+
+#[derive(Resource)]
+pub struct PaddleSettings {
+    pub per_player: usize,
+    pub tuning: PaddleTuning,
+    // Survival mode lives each player's paddles start with, None turns survival mode off
+    pub lives: Option<u8>
+}
+
+// A served ball rides on this paddle, only with --ball-spawn paddle. Ones served before or during
+// the countdown launch when it ends, ones served during play wait for their owner to press Serve.
+#[derive(Component, Clone, Copy)]
+pub struct HeldBy {
+    pub paddle: Entity,
+    pub paddle_net_id: NetId
+}
+
+// Lives left in survival mode. Every paddle of a player carries the same count.
+#[derive(Component, Clone, Copy)]
+pub struct Lives(pub u8);
+
+pub const DEFAULT_SEED: u64 = 1337;
+
+// Where balls are put for a serve
+#[derive(clap::ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum BallSpawn {
+    // BALL_STARTING_POSITION, the middle of the arena
+    #[default]
+    Fixed,
+    // Just above the owner's highest paddle, so they don't have to chase it
+    Paddle
+}
+
+// What each brick is worth
+#[derive(clap::ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum BrickValues {
+    // Every brick is worth 1
+    #[default]
+    Flat,
+    // The bottom row is worth 1 and every row above it one more
+    Rows
+}
+
+impl BrickValues {
+    pub fn value(self, row: usize) -> u8 {
+        match self {
+            BrickValues::Flat => 1,
+            BrickValues::Rows => (row + 1).min(u8::MAX as usize) as u8
+        }
+    }
+}
+
+#[derive(Resource)]
+pub struct BallSettings {
+    pub gravity: f32 // px/s^2, 0 is off
+}
+
+#[derive(Resource)]
+pub struct BrickSettings {
+    pub values: BrickValues
+}
+
+// Serves go up toward the bricks inside a cone around +Y
+#[derive(Resource)]
+pub struct ServeSettings {
+    pub half_angle_rad: f32,
+    pub seed: u64,
+    pub round: u32,
+    pub spawn: BallSpawn
+}
+
+impl ServeSettings {
+    pub fn serve(&self) -> NetServeData {
+        NetServeData { seed: self.seed, round: self.round, half_angle_rad: self.half_angle_rad }
+    }
 }
 
 #[derive(Resource)]
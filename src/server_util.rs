@@ -2,29 +2,430 @@ use bevy::prelude::*;
 use crate::common::*;
 use std::net::SocketAddr;
 
-use byteorder::ByteOrder;
 
 use crate::server_types::*;
 
+pub fn spawn_bricks(commands: &mut Commands, net_id_gen: &mut NetIdGenerator, values: BrickValues) {
+    let total_width_of_bricks = (RIGHT_WALL - LEFT_WALL) - 2. * GAP_BETWEEN_BRICKS_AND_SIDES;
+    let bottom_edge_of_bricks = PADDLE_Y + GAP_BETWEEN_PADDLE_AND_BRICKS;
+    let total_height_of_bricks = TOP_WALL - bottom_edge_of_bricks - GAP_BETWEEN_BRICKS_AND_CEILING;
+
+    assert!(total_width_of_bricks > 0.0);
+    assert!(total_height_of_bricks > 0.0);
+
+    // Given the space available, compute how many rows and columns of bricks we can fit
+    let n_columns = (total_width_of_bricks / (BRICK_SIZE.x + GAP_BETWEEN_BRICKS)).floor() as usize;
+    let n_rows = (total_height_of_bricks / (BRICK_SIZE.y + GAP_BETWEEN_BRICKS)).floor() as usize;
+    let n_vertical_gaps = n_columns - 1;
+
+    // Because we need to round the number of columns,
+    // the space on the top and sides of the bricks only captures a lower bound, not an exact value
+    let center_of_bricks = (LEFT_WALL + RIGHT_WALL) / 2.0;
+    let left_edge_of_bricks = center_of_bricks
+        // Space taken up by the bricks
+        - (n_columns as f32 / 2.0 * BRICK_SIZE.x)
+        // Space taken up by the gaps
+        - n_vertical_gaps as f32 / 2.0 * GAP_BETWEEN_BRICKS;
+
+    // In Bevy, the `translation` of an entity describes the center point,
+    // not its bottom-left corner
+    let offset_x = left_edge_of_bricks + BRICK_SIZE.x / 2.;
+    let offset_y = bottom_edge_of_bricks + BRICK_SIZE.y / 2.;
+
+    for row in 0..n_rows {
+        for column in 0..n_columns {
+            let brick_position = Vec2::new(
+                offset_x + column as f32 * (BRICK_SIZE.x + GAP_BETWEEN_BRICKS),
+                offset_y + row as f32 * (BRICK_SIZE.y + GAP_BETWEEN_BRICKS),
+            );
+
+            commands.spawn(BrickBundle::new(brick_position, net_id_gen.next(), values.value(row)));
+        }
+    }
+}
+
 pub fn handle_client_disconnected(
     handle: &SocketAddr,
+    cause: DisconnectCause,
     commands: &mut Commands,
     client_query:
     &mut Query<(&mut NetConnection, &mut NetInput)>,
     connections: &mut ResMut<NetConnections>,
+    pause: &mut ResMut<MatchPause>,
 ) {
     if connections.addr_to_entity.contains_key(handle) {
+        info!("disconnect: addr {} cause={:?}", handle, cause);
+        *connections.disconnects.entry(cause).or_default() += 1;
         let id = connections.addr_to_entity.get(handle).unwrap();
         let conn = client_query.get(*id).unwrap().0;
-        commands.entity(conn.paddle_entity).despawn();
-        commands.entity(conn.ball_entity).despawn();
+        for &paddle in conn.paddle_entities.iter() {
+            commands.entity(paddle).despawn();
+        }
+        if let Some(ball) = conn.ball_entity {
+            commands.entity(ball).despawn();
+        }
         commands.entity(*id).despawn();
         connections.addr_to_entity.remove(handle);
+
+        // Someone dropped mid-match, hold the game until they (or a replacement) come back or the match is reset
+        let remaining = connections.addr_to_entity.len();
+        if remaining > 0 && !pause.is_paused() {
+            info!("Player dropped, pausing match until {} players are connected", remaining + 1);
+            pause.reason = Some(PauseReason::PlayerDropped);
+            pause.resume_player_count = remaining + 1;
+        }
+    }
+}
+
+// taken is every connected player's (team, color), including ones connected earlier this tick
+pub fn assign_color(
+    assignment: &ColorAssignment,
+    taken: impl Iterator<Item = (Option<u8>, NetColorIndex)>
+) -> (Option<u8>, NetColorIndex) {
+    if assignment.team_colors.is_empty() {
+        let mut used = [false; NUM_COLORS];
+        for (_, color) in taken {
+            used[color.0 as usize % NUM_COLORS] = true;
+        }
+        // More players than colors, someone has to double up
+        let free = used.iter().position(|u| !u).unwrap_or(0);
+        return (None, NetColorIndex(free as u8));
+    }
+
+    let mut team_sizes = vec![0; assignment.team_colors.len()];
+    for (team, _) in taken {
+        if let Some(size) = team.and_then(|t| team_sizes.get_mut(t as usize)) {
+            *size += 1;
+        }
+    }
+    let team = (0..team_sizes.len()).min_by_key(|&t| team_sizes[t]).unwrap();
+    (Some(team as u8), assignment.team_colors[team])
+}
+
+// Sorted by player index (entity breaks ties once indices wrap), then rotated one place per tick
+// so every player gets their turn at having their input applied first
+pub fn input_processing_order(mut players: Vec<(u8, Entity)>, frame: u32) -> Vec<Entity> {
+    players.sort_unstable();
+    if !players.is_empty() {
+        let len = players.len();
+        players.rotate_left(frame as usize % len);
+    }
+    players.into_iter().map(|(_, entity)| entity).collect()
+}
+
+// Bytes value takes once encoded with NET_CONFIG, without encoding it anywhere
+pub fn encoded_len(value: impl serde::Serialize) -> usize {
+    let mut size = bincode::enc::write::SizeWriter::default();
+    match bincode::serde::encode_into_writer(value, &mut size, NET_CONFIG) {
+        Ok(()) => size.bytes_written,
+        Err(_) => usize::MAX,
+    }
+}
+
+// Splits a world state into as few parts as fit in max_bytes each once encoded as a
+// ServerToClientPacket, also starting a new part after max_entities if given. Stops splitting at
+// u8::MAX parts, the last one then takes the rest and fails to encode. Every part carries the frame
+// and match state, only the first carries collisions, serve and custom.
+pub fn split_world_state(mut world: NetWorldStateData, max_bytes: usize, max_entities: Option<usize>) -> Vec<NetWorldStateData> {
+    // Variant index in front of the world state, plus room for the entity count to grow
+    let overhead = encoded_len(0u32) + encoded_len(u64::MAX);
+    let max_entities = max_entities.unwrap_or(usize::MAX).max(1);
+    let entities = std::mem::take(&mut world.entities);
+    let (frame, paused, phase) = (world.frame, world.paused, world.phase);
+
+    let mut part_len = overhead + encoded_len(&world);
+    let mut parts = vec![world];
+    for entity in entities {
+        let entity_len = encoded_len(&entity);
+        let last = parts.last().unwrap();
+        let full = part_len + entity_len > max_bytes || last.entities.len() >= max_entities;
+        if full && !last.entities.is_empty() && parts.len() < u8::MAX as usize {
+            let next = NetWorldStateData { frame, paused, phase, ..default() };
+            part_len = overhead + encoded_len(&next);
+            parts.push(next);
+        }
+        parts.last_mut().unwrap().entities.push(entity);
+        part_len += entity_len;
+    }
+
+    let part_count = parts.len() as u8;
+    for (i, part) in parts.iter_mut().enumerate() {
+        part.part = i as u8;
+        part.part_count = part_count;
+    }
+    parts
+}
+
+pub fn write_header(buf: &mut [u8], conn: &NetConnection, input: &NetInput, padding: &JitterPadding, layout: &WorldStateLayout, now: f32) {
+    ServerPacketHeader {
+        last_applied_input: conn.last_applied_input,
+        player_index: conn.player_index,
+        input_state: Some(input.input_state),
+        buffer_remaining_ms: buffering_remaining_ms(input, padding, now),
+        input_delay_ms: input.applied_delay.smoothed_ms(),
+        send_interval_ticks: layout.send_interval_ticks,
+    }.write(buf);
+}
+
+// How much longer process_input will hold this client's inputs before it starts playing them
+pub fn buffering_remaining_ms(input: &NetInput, padding: &JitterPadding, now: f32) -> u16 {
+    match input.input_state {
+        NetInputState::Playing => 0,
+        NetInputState::Buffering => {
+            let waited = input.inputs.front().map_or(0.0, |i| now - i.time_received);
+            let delay_s = buffer_delay_s(padding.effective_s(&input.jitter)) as f32;
+            ((delay_s - waited).max(0.0) * 1000.0) as u16
+        }
+    }
+}
+
+// Server only timing invariants, on top of common::check_shared_timing
+pub fn check_server_timing(padding: &JitterPadding, countdown_s: f32, send_hz: f64) -> Vec<TimingIssue> {
+    let mut issues = check_shared_timing(padding);
+    if !(send_hz > 0.0) {
+        issues.push(TimingIssue::Error(format!("--send-hz ({}) has to be more than 0", send_hz)));
+    } else if send_hz > TICK_RATE_HZ {
+        issues.push(TimingIssue::Warning(format!(
+            "--send-hz ({}) is above the tick rate ({} Hz), world states still only go out once a tick",
+            send_hz, TICK_RATE_HZ
+        )));
+    } else if TICK_RATE_HZ / send_interval_ticks(send_hz) as f64 != send_hz {
+        issues.push(TimingIssue::Warning(format!(
+            "--send-hz ({}) doesn't divide the tick rate ({} Hz), sending at {:.1} Hz instead",
+            send_hz, TICK_RATE_HZ, TICK_RATE_HZ / send_interval_ticks(send_hz) as f64
+        )));
     }
+    let max_countdown_s = u16::MAX as f64 / TICK_RATE_HZ;
+    if countdown_s < 0.0 {
+        issues.push(TimingIssue::Error(format!("countdown ({} s) can't be negative, use 0 to start right away", countdown_s)));
+    } else if countdown_s as f64 > max_countdown_s {
+        issues.push(TimingIssue::Error(format!(
+            "countdown ({} s) is longer than fits in the world state's tick count ({:.0} s at {} Hz)",
+            countdown_s, max_countdown_s, TICK_RATE_HZ
+        )));
+    }
+    issues
 }
 
-pub fn write_header(buf: &mut [u8], conn: &NetConnection) {
-    byteorder::NetworkEndian::write_u32(buf, WORLD_PACKET_HEADER_TAG);
-    byteorder::NetworkEndian::write_u32(&mut buf[size_of::<u32>()..], conn.last_applied_input);
-    buf[size_of::<u32>() * 2] = conn.player_index;
+pub fn parse_ascii_view_interval(s: &str) -> Result<f32, String> {
+    let interval_s: f32 = s.parse().map_err(|e| format!("{}", e))?;
+    if !(interval_s >= MIN_ASCII_VIEW_INTERVAL_S && interval_s.is_finite()) {
+        return Err(format!("has to be at least {} s", MIN_ASCII_VIEW_INTERVAL_S));
+    }
+    Ok(interval_s)
+}
+
+// Coarse text picture of the arena: '#' bricks, '=' paddles, 'o' balls. Later marks win a cell.
+pub fn render_ascii(
+    bricks: impl Iterator<Item = Vec2>,
+    paddles: impl Iterator<Item = Vec2>,
+    balls: impl Iterator<Item = Vec2>,
+) -> String {
+    let mut grid = vec![[' '; ASCII_VIEW_COLUMNS]; ASCII_VIEW_ROWS];
+    let mut mark = |pos: Vec2, c: char| {
+        let x = (pos.x - LEFT_WALL) / (RIGHT_WALL - LEFT_WALL) * ASCII_VIEW_COLUMNS as f32;
+        let y = (TOP_WALL - pos.y) / (TOP_WALL - BOTTOM_WALL) * ASCII_VIEW_ROWS as f32;
+        let col = (x.max(0.0) as usize).min(ASCII_VIEW_COLUMNS - 1);
+        let row = (y.max(0.0) as usize).min(ASCII_VIEW_ROWS - 1);
+        grid[row][col] = c;
+    };
+    bricks.for_each(|p| mark(p, '#'));
+    paddles.for_each(|p| mark(p, '='));
+    balls.for_each(|p| mark(p, 'o'));
+
+    let border = format!("+{}+", "-".repeat(ASCII_VIEW_COLUMNS));
+    let mut out = String::with_capacity((ASCII_VIEW_COLUMNS + 3) * (ASCII_VIEW_ROWS + 2));
+    out.push_str(&border);
+    out.push('\n');
+    for row in grid {
+        out.push('|');
+        out.extend(row);
+        out.push_str("|\n");
+    }
+    out.push_str(&border);
+    out
+}
+
+pub fn validate_input(input: &PlayerInputData, last_applied_input: u32, num_paddles: usize) -> Result<(), SuspiciousInput> {
+    let valid_keys = (1 << NetKey::Left as u8) | (1 << NetKey::Right as u8) | (1 << NetKey::Serve as u8);
+    if input.sequence <= last_applied_input {
+        Err(SuspiciousInput::ReplayedSequence)
+    } else if input.key_mask & !valid_keys != 0 {
+        Err(SuspiciousInput::UnknownKeys)
+    } else if input.paddle.is_some_and(|p| p as usize >= num_paddles) {
+        Err(SuspiciousInput::UnknownPaddle)
+    } else {
+        Ok(())
+    }
+}
+
+pub fn flag_suspicious_input(conn: &mut NetConnection, reason: SuspiciousInput) {
+    conn.suspicious_inputs += 1;
+    warn!("{}: suspicious input {:?} ({} total from this connection)", conn.addr, reason, conn.suspicious_inputs);
+}
+
+pub fn paddle_row_y(paddle_index: usize) -> f32 {
+    PADDLE_Y + paddle_index as f32 * PADDLE_ROW_SPACING
+}
+
+// Taller paddles still have to clear the floor and not overlap the row stacked above them
+pub fn check_paddle_height(tuning: &PaddleTuning) -> Result<(), String> {
+    let max_height = PADDLE_ROW_SPACING.min(2.0 * (PADDLE_Y - BOTTOM_WALL - WALL_THICKNESS / 2.0));
+    if tuning.size.y >= max_height {
+        return Err(format!("paddle height {} has to be under {} px", tuning.size.y, max_height));
+    }
+    Ok(())
+}
+
+// Touching the bottom wall, which costs a life in survival mode
+pub fn ball_lost(pos: Vec2) -> bool {
+    pos.y - BALL_DIAMETER / 2.0 <= BOTTOM_WALL + WALL_THICKNESS / 2.0
+}
+
+// owner_paddle is the owner's highest paddle, None falls back to the fixed position
+pub fn ball_spawn_position(spawn: BallSpawn, owner_paddle: Option<(Vec2, &PaddleTuning)>) -> Vec2 {
+    let pos = match (spawn, owner_paddle) {
+        (BallSpawn::Paddle, Some((paddle, tuning))) => held_ball_position(paddle, tuning),
+        _ => BALL_STARTING_POSITION,
+    };
+    snap_to_sim_grid(pos)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render_ascii_marks_and_borders() {
+        let out = render_ascii(
+            [Vec2::new(LEFT_WALL, TOP_WALL)].into_iter(),
+            [Vec2::new(0.0, BOTTOM_WALL)].into_iter(),
+            // Past the walls, clamped onto the edge cells
+            [Vec2::new(RIGHT_WALL + 100.0, TOP_WALL + 100.0)].into_iter(),
+        );
+        let lines: Vec<&str> = out.lines().collect();
+        assert_eq!(lines.len(), ASCII_VIEW_ROWS + 2);
+        let border = format!("+{}+", "-".repeat(ASCII_VIEW_COLUMNS));
+        assert_eq!((lines[0], lines[ASCII_VIEW_ROWS + 1]), (border.as_str(), border.as_str()));
+        assert!(lines.iter().all(|l| l.chars().count() == ASCII_VIEW_COLUMNS + 2));
+
+        let cell = |row: usize, col: usize| lines[row + 1].chars().nth(col + 1).unwrap();
+        assert_eq!(cell(0, 0), '#');
+        assert_eq!(cell(ASCII_VIEW_ROWS - 1, ASCII_VIEW_COLUMNS / 2), '=');
+        assert_eq!(cell(0, ASCII_VIEW_COLUMNS - 1), 'o');
+        assert_eq!(out.matches(['#', '=', 'o']).count(), 3);
+    }
+
+    #[test]
+    fn test_parse_ascii_view_interval() {
+        assert_eq!(parse_ascii_view_interval("0.5"), Ok(0.5));
+        assert!(parse_ascii_view_interval("0").is_err());
+        assert!(parse_ascii_view_interval("-1").is_err());
+        assert!(parse_ascii_view_interval("NaN").is_err());
+        assert!(parse_ascii_view_interval("inf").is_err());
+    }
+
+    #[test]
+    fn test_paddle_serves_clear_tall_paddles() {
+        let tall = PaddleTuning { size: Vec2::new(PADDLE_SIZE.x, 60.0), ..default() };
+        assert!(check_paddle_height(&tall).is_ok());
+        assert!(check_paddle_height(&PaddleTuning { size: Vec2::new(PADDLE_SIZE.x, PADDLE_ROW_SPACING), ..default() }).is_err());
+
+        let paddle = Vec2::new(0.0, PADDLE_Y);
+        let pos = ball_spawn_position(BallSpawn::Paddle, Some((paddle, &tall)));
+        assert!(pos.y - BALL_DIAMETER / 2.0 > paddle.y + tall.size.y / 2.0);
+        assert_eq!(ball_spawn_position(BallSpawn::Fixed, Some((paddle, &tall))), snap_to_sim_grid(BALL_STARTING_POSITION));
+    }
+
+    fn brick(id: u16) -> NetEntity {
+        NetEntity {
+            entity_type: NetEntityType::Brick(NetBrickData { pos: Vec2::new(id as f32, -(id as f32)), value: 1 }),
+            net_id: NetId(id),
+        }
+    }
+
+    fn stock_world_state() -> NetWorldStateData {
+        let mut world = World::new();
+        let mut queue = bevy::ecs::world::CommandQueue::default();
+        spawn_bricks(&mut Commands::new(&mut queue, &world), &mut NetIdGenerator::default(), BrickValues::Rows);
+        queue.apply(&mut world);
+
+        let mut state = NetWorldStateData::default();
+        for (transform, &net_id, brick) in world.query::<(&Transform, &NetId, &Brick)>().iter(&world) {
+            state.entities.push(NetEntity {
+                entity_type: NetEntityType::Brick(NetBrickData { pos: transform.translation.xy(), value: brick.value }),
+                net_id,
+            });
+        }
+        for player in 0..4u8 {
+            state.entities.push(NetEntity {
+                entity_type: NetEntityType::Paddle(NetPaddleData {
+                    pos: Vec2::new(player as f32, PADDLE_Y),
+                    player_index: NetPlayerIndex(player),
+                    color: NetColorIndex(player),
+                    lives: Some(3),
+                }),
+                net_id: NetId(1000 + player as u16),
+            });
+            state.entities.push(NetEntity {
+                entity_type: NetEntityType::Ball(NetBallData {
+                    pos: Vec2::new(player as f32, 0.0),
+                    velocity: Vec2::new(1.0, 1.0),
+                    spin: 0.5,
+                    launch_frame: Some(100),
+                    held_by: Some(NetId(1000 + player as u16)),
+                    player_index: NetPlayerIndex(player),
+                    color: NetColorIndex(player),
+                }),
+                net_id: NetId(2000 + player as u16),
+            });
+        }
+        state.entities.push(NetEntity { entity_type: NetEntityType::Score(NetScoreData { score: 12345 }), net_id: NetId(0) });
+        state.collisions.extend((0..MAX_COLLISIONS_PER_PACKET).map(|i| CollisionEvent {
+            frame: i as u32,
+            ball: NetId(2000),
+            pos: Vec2::ZERO,
+            kind: CollisionKind::Brick,
+        }));
+        state
+    }
+
+    fn encode_part(part: NetWorldStateData) -> usize {
+        let mut buf = [0; crate::networking::ETHERNET_MTU * 4];
+        bincode::serde::encode_into_slice(ServerToClientPacket::WorldState(part), &mut buf, NET_CONFIG).unwrap()
+    }
+
+    #[test]
+    fn test_stock_world_state_fits_one_packet() {
+        let state = stock_world_state();
+        let parts = split_world_state(state.clone(), MAX_WORLD_STATE_BYTES, None);
+        assert_eq!(parts.len(), 1);
+        assert_eq!((parts[0].part, parts[0].part_count), (0, 1));
+        assert_eq!(parts[0].entities.len(), state.entities.len());
+        assert!(encode_part(parts[0].clone()) <= MAX_WORLD_STATE_BYTES);
+    }
+
+    #[test]
+    fn test_split_world_state_by_encoded_size() {
+        let mut state = NetWorldStateData { frame: 7, ..default() };
+        state.entities.extend((1..=400).map(brick));
+        state.collisions.push(CollisionEvent { frame: 7, ball: NetId(1), pos: Vec2::ZERO, kind: CollisionKind::Bounce });
+        state.custom = vec![1; 100];
+
+        let parts = split_world_state(state, 500, None);
+        assert!(parts.len() > 1);
+        let mut ids = Vec::new();
+        for (i, part) in parts.iter().enumerate() {
+            assert_eq!((part.frame, part.part as usize, part.part_count as usize), (7, i, parts.len()));
+            assert_eq!(!part.collisions.is_empty(), i == 0);
+            assert_eq!(!part.custom.is_empty(), i == 0);
+            ids.extend(part.entities.iter().map(|e| e.net_id.0));
+            assert!(encode_part(part.clone()) <= 500);
+        }
+        assert_eq!(ids, (1..=400).collect::<Vec<_>>());
+
+        let capped = split_world_state(NetWorldStateData { entities: (1..=10).map(brick).collect(), ..default() }, 500, Some(4));
+        assert_eq!(capped.iter().map(|p| p.entities.len()).collect::<Vec<_>>(), [4, 4, 2]);
+    }
 }